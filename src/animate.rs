@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+/// The minimum delay between edits of an animated message.
+pub(crate) const EDIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most times a single animated message is edited after it is sent.
+pub(crate) const MAX_EDITS: usize = 5;
+
+/// Limits how often animated replies are edited so they stay clear of
+/// Telegram's flood limits, regardless of how many frames an animation has.
+pub(crate) struct EditGuard {
+    pub(crate) interval: Duration,
+    max_edits: usize,
+}
+
+impl EditGuard {
+    pub(crate) fn new(interval: Duration, max_edits: usize) -> Self {
+        Self { interval, max_edits }
+    }
+
+    /// Picks which frames to show as edits after the first frame is sent. Frames
+    /// are spread evenly across the animation and the final frame is always
+    /// included.
+    pub(crate) fn plan(&self, frames: usize) -> Vec<usize> {
+        let edits = frames.saturating_sub(1);
+        if edits <= self.max_edits {
+            return (1..frames).collect();
+        }
+
+        (1..=self.max_edits)
+            .map(|i| i * edits / self.max_edits)
+            .collect()
+    }
+}
+
+impl Default for EditGuard {
+    fn default() -> Self {
+        Self::new(EDIT_INTERVAL, MAX_EDITS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let guard = EditGuard::new(EDIT_INTERVAL, 5);
+
+        assert!(guard.plan(0).is_empty());
+        assert!(guard.plan(1).is_empty());
+        assert_eq!(vec![1, 2, 3], guard.plan(4));
+        assert_eq!(vec![1, 2, 3, 4, 5], guard.plan(6));
+    }
+
+    #[test]
+    fn test_plan_large() {
+        let guard = EditGuard::new(EDIT_INTERVAL, 5);
+        let plan = guard.plan(1000);
+
+        assert_eq!(5, plan.len());
+        assert_eq!(Some(&999), plan.last());
+        assert!(plan.windows(2).all(|w| w[0] < w[1]), "{:?}", plan);
+    }
+}
@@ -0,0 +1,53 @@
+use rand::Rng;
+
+/// The most options `/choose` accepts, keeping the reply a reasonable size.
+pub(crate) const MAX_OPTIONS: usize = 20;
+
+/// Splits a `/choose` argument into its options, separated by commas or
+/// newlines. Empty options are ignored.
+pub(crate) fn parse_options(input: &str) -> Vec<String> {
+    input.split([',', '\n'])
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Picks one of the options uniformly at random, returning its index.
+pub(crate) fn choose<R: Rng + ?Sized>(options: &[String], rng: &mut R) -> Option<usize> {
+    match options.len() {
+        0 => None,
+        n => Some(rng.gen_range(0..n)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(vec!["pizza", "tacos", "sushi"], parse_options("pizza, tacos,, sushi "));
+        assert_eq!(vec!["fight", "flee"], parse_options("fight\nflee"));
+        assert!(parse_options(" , ").is_empty());
+    }
+
+    #[test]
+    fn test_choose() {
+        assert_eq!(None, choose(&[], &mut StdRng::seed_from_u64(1)));
+
+        let options = parse_options("a, b, c");
+        let mut seen = [false; 3];
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            seen[choose(&options, &mut rng).unwrap()] = true;
+        }
+        assert_eq!([true; 3], seen);
+
+        let a = choose(&options, &mut StdRng::seed_from_u64(42));
+        let b = choose(&options, &mut StdRng::seed_from_u64(42));
+        assert_eq!(a, b);
+    }
+}
@@ -0,0 +1,237 @@
+use std::iter;
+
+use nom::bytes::complete::tag_no_case;
+use nom::error::{Error, ErrorKind};
+use nom::{Err, IResult};
+
+/// Describes a bot command. The catalog below is the single source for parsing
+/// command names and for exporting the command list to other frontends.
+#[derive(Debug)]
+pub(crate) struct CommandSpec {
+    pub(crate) name: &'static str,
+    pub(crate) aliases: &'static [&'static str],
+    pub(crate) syntax: &'static str,
+    pub(crate) description: &'static str,
+}
+
+pub(crate) const START: CommandSpec = CommandSpec {
+    name: "start",
+    aliases: &[],
+    syntax: "",
+    description: "See introductory information about this bot",
+};
+
+pub(crate) const HELP: CommandSpec = CommandSpec {
+    name: "help",
+    aliases: &[],
+    syntax: "[topic]",
+    description: "See the available commands and roll syntax",
+};
+
+pub(crate) const ROLL: CommandSpec = CommandSpec {
+    name: "roll",
+    aliases: &["r"],
+    syntax: "[expression]",
+    description: "Roll dice and calculate a total",
+};
+
+pub(crate) const GROUP_ROLL: CommandSpec = CommandSpec {
+    name: "grouproll",
+    aliases: &[],
+    syntax: "start [expression] | end",
+    description: "Open or close a roll that everyone in the chat can contribute to",
+};
+
+pub(crate) const JOIN: CommandSpec = CommandSpec {
+    name: "join",
+    aliases: &[],
+    syntax: "",
+    description: "Roll into the open group roll",
+};
+
+pub(crate) const SLOW_ROLL: CommandSpec = CommandSpec {
+    name: "slowroll",
+    aliases: &[],
+    syntax: "[expression]",
+    description: "Roll dice, revealing them one at a time",
+};
+
+pub(crate) const AVERAGE: CommandSpec = CommandSpec {
+    name: "average",
+    aliases: &["avg"],
+    syntax: "[expression]",
+    description: "Calculate the expected value of an expression without rolling it",
+};
+
+pub(crate) const RANGE: CommandSpec = CommandSpec {
+    name: "range",
+    aliases: &[],
+    syntax: "[expression]",
+    description: "Show the lowest and highest totals an expression can roll",
+};
+
+pub(crate) const STATS: CommandSpec = CommandSpec {
+    name: "stats",
+    aliases: &[],
+    syntax: "[expression]",
+    description: "Show the chance of each total an expression can roll",
+};
+
+pub(crate) const CHOOSE: CommandSpec = CommandSpec {
+    name: "choose",
+    aliases: &[],
+    syntax: "(poll) [option], [option], ...",
+    description: "Let the dice pick one of the options",
+};
+
+pub(crate) const CRIT: CommandSpec = CommandSpec {
+    name: "crit",
+    aliases: &[],
+    syntax: "[expression]",
+    description: "Roll critical damage, doubling the dice but not the modifiers",
+};
+
+pub(crate) const CONFIRM_CRIT: CommandSpec = CommandSpec {
+    name: "confirmcrit",
+    aliases: &[],
+    syntax: "[expression] vs [target] (crit [threat])",
+    description: "Roll an attack and confirm any critical threat with a second roll",
+};
+
+pub(crate) const VALIDATE: CommandSpec = CommandSpec {
+    name: "validate",
+    aliases: &[],
+    syntax: "[one expression per line]",
+    description: "Check expressions without rolling them",
+};
+
+pub(crate) const TIMESTAMPS: CommandSpec = CommandSpec {
+    name: "timestamps",
+    aliases: &[],
+    syntax: "on | off",
+    description: "Show the time of each roll in this chat's replies",
+};
+
+pub(crate) const BREAKDOWN: CommandSpec = CommandSpec {
+    name: "breakdown",
+    aliases: &[],
+    syntax: "on | off",
+    description: "Show subtotals and the total on its own line in this chat's rolls",
+};
+
+pub(crate) const TIMEZONE: CommandSpec = CommandSpec {
+    name: "timezone",
+    aliases: &[],
+    syntax: "[UTC offset]",
+    description: "Set the chat's timezone used for roll timestamps",
+};
+
+pub(crate) const SET_REROLL_ONES: CommandSpec = CommandSpec {
+    name: "setreroll1",
+    aliases: &[],
+    syntax: "(on | off)",
+    description: "Toggle rerolling 1s once on all of your rolls",
+};
+
+pub(crate) const LAST: CommandSpec = CommandSpec {
+    name: "last",
+    aliases: &[],
+    syntax: "(n)",
+    description: "Show one of your recent rolls in this chat again",
+};
+
+pub(crate) const COMMANDS: &[CommandSpec] = &[START, HELP, ROLL, GROUP_ROLL, JOIN, SLOW_ROLL, AVERAGE, RANGE, STATS, CHOOSE, CRIT, CONFIRM_CRIT, VALIDATE, TIMESTAMPS, BREAKDOWN, TIMEZONE, SET_REROLL_ONES, LAST];
+
+impl CommandSpec {
+    /// The command's name followed by each of its aliases.
+    pub(crate) fn names(&self) -> impl Iterator<Item=&'static str> {
+        iter::once(self.name).chain(self.aliases.iter().copied())
+    }
+}
+
+/// Matches the name or any alias of `spec`, case-insensitively. Names are tried
+/// in catalog order, so longer names should precede their prefixes.
+pub(crate) fn keyword<'a>(spec: &'static CommandSpec) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    move |input| {
+        for name in spec.names() {
+            let res: IResult<&str, &str> = tag_no_case(name)(input);
+            if res.is_ok() {
+                return res;
+            }
+        }
+        Err(Err::Error(Error::new(input, ErrorKind::Tag)))
+    }
+}
+
+/// Lists every command name and alias with its description, for Telegram's
+/// command menu.
+pub(crate) fn menu() -> Vec<(&'static str, &'static str)> {
+    COMMANDS.iter()
+        .flat_map(|spec| spec.names().map(|name| (name, spec.description)))
+        .collect()
+}
+
+/// Renders the command catalog as a JSON array for use by companion frontends.
+pub(crate) fn to_json() -> String {
+    let entries: Vec<String> = COMMANDS.iter()
+        .map(|spec| {
+            let aliases: Vec<String> = spec.aliases.iter().map(|a| json_string(a)).collect();
+            format!(
+                r#"{{"name":{},"aliases":[{}],"syntax":{},"description":{}}}"#,
+                json_string(spec.name),
+                aliases.join(","),
+                json_string(spec.syntax),
+                json_string(spec.description),
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyword() {
+        assert_eq!(Ok((" 2d6", "roll")), keyword(&ROLL)("roll 2d6"));
+        assert_eq!(Ok(("2d6", "R")), keyword(&ROLL)("R2d6"));
+        assert!(keyword(&START)("help").is_err());
+    }
+
+    #[test]
+    fn test_menu() {
+        let menu = menu();
+        assert_eq!(Some(&("start", START.description)), menu.first());
+        assert!(menu.contains(&("r", ROLL.description)));
+        assert!(menu.iter().all(|(name, _)| name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')));
+    }
+
+    #[test]
+    fn test_to_json() {
+        let json = to_json();
+        assert!(json.starts_with('['));
+        assert!(json.contains(r#"{"name":"roll","aliases":["r"],"syntax":"[expression]","#));
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(r#""a \"b\" \\ c""#, json_string(r#"a "b" \ c"#));
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::env::{self, VarError};
+use std::fmt::{self, Display, Formatter};
+use std::{fs, io};
+
+use nom::branch::alt;
+use nom::bytes::complete::{escaped_transform, is_not, tag, take_while, take_while1};
+use nom::character::complete::{char, digit1, space0};
+use nom::combinator::{all_consuming, map, opt, recognize, rest, value};
+use nom::multi::separated_list1;
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated};
+use nom::IResult;
+
+/// The prefix shared by every setting's environment variable. The rest of the
+/// name, lowercased, is the setting's key in a config file.
+const ENV_PREFIX: &str = "DICE_GOBLIN_";
+
+/// Settings read from a TOML config file, like `api_id = 12345` for
+/// `DICE_GOBLIN_API_ID`. Keys under a table are prefixed with its name, so
+/// `burst` under `[rate]` is `DICE_GOBLIN_RATE_BURST`. Only string, integer,
+/// and boolean values are supported. Environment variables take precedence
+/// over the file.
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    values: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    Read(io::Error),
+    /// A line that isn't a `key = value` pair, counting from one.
+    Invalid { line: usize },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(e) => write!(f, "unable to read config: {}", e),
+            ConfigError::Invalid { line } => write!(f, "invalid config on line {}", line),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub(crate) fn load(path: &str) -> Result<Self, ConfigError> {
+        fs::read_to_string(path).map_err(ConfigError::Read)?.parse()
+    }
+
+    /// Reads the setting for the environment variable `name`, falling back to
+    /// the config file when it isn't set.
+    pub(crate) fn var(&self, name: &str) -> Result<String, VarError> {
+        match env::var(name) {
+            Err(VarError::NotPresent) => name.strip_prefix(ENV_PREFIX)
+                .and_then(|key| self.values.get(&key.to_lowercase()))
+                .cloned()
+                .ok_or(VarError::NotPresent),
+            res => res,
+        }
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut values = HashMap::new();
+        let mut table = None;
+        for (i, line) in s.lines().enumerate() {
+            if let Ok((_, name)) = all_consuming(header)(line) {
+                table = Some(name);
+                continue;
+            }
+            match all_consuming(entry)(line) {
+                Ok((_, Some((key, value)))) => {
+                    let key = match &table {
+                        Some(table) => format!("{}_{}", table, key),
+                        None => key.to_string(),
+                    };
+                    values.insert(key, value);
+                }
+                Ok((_, None)) => {}
+                Err(_) => return Err(ConfigError::Invalid { line: i + 1 }),
+            }
+        }
+        Ok(Self { values })
+    }
+}
+
+/// Parses a table header like `[rate]`, returning the prefix its keys get.
+/// Dotted names like `[rate.limit]` are joined as `rate_limit`.
+fn header(input: &str) -> IResult<&str, String> {
+    terminated(
+        delimited(
+            pair(space0, char('[')),
+            map(separated_list1(char('.'), delimited(space0, key, space0)), |keys| keys.join("_")),
+            pair(char(']'), space0),
+        ),
+        opt(preceded(char('#'), rest)),
+    )(input)
+}
+
+/// Parses a line holding a `key = value` pair, a comment, or nothing.
+fn entry(input: &str) -> IResult<&str, Option<(&str, String)>> {
+    terminated(
+        delimited(
+            space0,
+            opt(separated_pair(key, delimited(space0, char('='), space0), config_value)),
+            space0,
+        ),
+        opt(preceded(char('#'), rest)),
+    )(input)
+}
+
+fn key(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-')(input)
+}
+
+fn config_value(input: &str) -> IResult<&str, String> {
+    alt((
+        string,
+        literal,
+        value("true".to_string(), tag("true")),
+        value("false".to_string(), tag("false")),
+        map(recognize(pair(opt(alt((char('+'), char('-')))), digit1)), String::from),
+    ))(input)
+}
+
+/// A single-quoted literal string, which has no escapes.
+fn literal(input: &str) -> IResult<&str, String> {
+    map(delimited(char('\''), take_while(|c| c != '\''), char('\'')), String::from)(input)
+}
+
+fn string(input: &str) -> IResult<&str, String> {
+    alt((
+        value(String::new(), tag("\"\"")),
+        delimited(
+            char('"'),
+            escaped_transform(is_not("\\\""), '\\', alt((
+                value("\\", char('\\')),
+                value("\"", char('"')),
+                value("\n", char('n')),
+                value("\t", char('t')),
+            ))),
+            char('"'),
+        ),
+    ))(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config: Config = r#"
+            # credentials
+            api_id = 12345
+            api_hash = "abc\"def"   # quoted
+            rate_burst=-3
+            empty = ""
+        "#.parse().unwrap();
+        assert_eq!(Some("12345"), config.values.get("api_id").map(String::as_str));
+        assert_eq!(Some("abc\"def"), config.values.get("api_hash").map(String::as_str));
+        assert_eq!(Some("-3"), config.values.get("rate_burst").map(String::as_str));
+        assert_eq!(Some(""), config.values.get("empty").map(String::as_str));
+
+        assert!(matches!("ok = 1\nbroken".parse::<Config>(), Err(ConfigError::Invalid { line: 2 })));
+        assert!(matches!("key = \"unclosed".parse::<Config>(), Err(ConfigError::Invalid { line: 1 })));
+        assert!(matches!("key = 1.5".parse::<Config>(), Err(ConfigError::Invalid { line: 1 })));
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let config: Config = r#"
+            strict_division = true
+            natural_crits=false
+            log_format = 'json'   # literal
+            path = 'C:\bots\goblin'
+            [rate]
+            burst = 3
+            [ session . save ]  # dotted
+            interval = "5m"
+        "#.parse().unwrap();
+        assert_eq!(Some("true"), config.values.get("strict_division").map(String::as_str));
+        assert_eq!(Some("false"), config.values.get("natural_crits").map(String::as_str));
+        assert_eq!(Some("json"), config.values.get("log_format").map(String::as_str));
+        assert_eq!(Some("C:\\bots\\goblin"), config.values.get("path").map(String::as_str));
+        assert_eq!(Some("3"), config.values.get("rate_burst").map(String::as_str));
+        assert_eq!(Some("5m"), config.values.get("session_save_interval").map(String::as_str));
+        assert_eq!(Ok(true), config.values["strict_division"].parse::<bool>());
+
+        assert!(matches!("key = True".parse::<Config>(), Err(ConfigError::Invalid { line: 1 })));
+        assert!(matches!("key = 'it's'".parse::<Config>(), Err(ConfigError::Invalid { line: 1 })));
+        assert!(matches!("[unclosed".parse::<Config>(), Err(ConfigError::Invalid { line: 1 })));
+    }
+
+    #[test]
+    fn test_var() {
+        let config: Config = "test_config_only = \"file\"\ntest_config_both = \"file\"".parse().unwrap();
+        env::set_var("DICE_GOBLIN_TEST_CONFIG_BOTH", "env");
+
+        assert_eq!(Ok("file".to_string()), config.var("DICE_GOBLIN_TEST_CONFIG_ONLY"));
+        assert_eq!(Ok("env".to_string()), config.var("DICE_GOBLIN_TEST_CONFIG_BOTH"));
+        assert_eq!(Err(VarError::NotPresent), config.var("DICE_GOBLIN_TEST_CONFIG_NEITHER"));
+    }
+}
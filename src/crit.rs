@@ -0,0 +1,98 @@
+use rand::Rng;
+
+use crate::rolls;
+use crate::rolls::Roll;
+
+/// The natural roll needed to threaten a critical hit when no range is given.
+pub(crate) const DEFAULT_THREAT: i64 = 20;
+
+/// The outcome of an attack that may need a second roll to confirm a critical.
+#[derive(Debug)]
+pub(crate) struct CritCheck {
+    pub(crate) attack: Roll,
+    pub(crate) natural: Option<i64>,
+    pub(crate) target: i64,
+    /// The confirmation roll, present only if the attack threatened a critical.
+    pub(crate) confirm: Option<Roll>,
+}
+
+impl CritCheck {
+    pub(crate) fn hit(&self) -> bool {
+        self.attack.value().is_ok_and(|v| v >= self.target)
+    }
+
+    pub(crate) fn threatened(&self) -> bool {
+        self.confirm.is_some()
+    }
+
+    pub(crate) fn confirmed(&self) -> bool {
+        self.confirm.as_ref().is_some_and(|c| c.value().is_ok_and(|v| v >= self.target))
+    }
+}
+
+/// Rolls `expr` as an attack against `target`. The first die rolled is treated
+/// as the natural roll; if it is at least `threat`, the attack is rolled again
+/// and the critical is confirmed if that roll also meets the target.
+pub(crate) fn confirm_crit<R: Rng + ?Sized>(
+    expr: &str,
+    target: i64,
+    threat: i64,
+    rng: &mut R,
+) -> Result<CritCheck, rolls::ParseError> {
+    let attack = rolls::parse_with_rng(expr, rng)?;
+    let natural = attack.evaluate_all().first().copied();
+
+    let confirm = match natural {
+        Some(n) if n >= threat => Some(rolls::parse_with_rng(expr, rng)?),
+        _ => None,
+    };
+
+    Ok(CritCheck { attack, natural, target, confirm })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_confirmed() {
+        let check = confirm_crit("d20 + 5", 0, 1, &mut StdRng::seed_from_u64(1)).unwrap();
+        assert!(check.hit());
+        assert!(check.threatened());
+        assert!(check.confirmed());
+    }
+
+    #[test]
+    fn test_unconfirmed() {
+        let check = confirm_crit("d20 + 5", 100, 1, &mut StdRng::seed_from_u64(1)).unwrap();
+        assert!(!check.hit());
+        assert!(check.threatened());
+        assert!(!check.confirmed());
+    }
+
+    #[test]
+    fn test_no_threat() {
+        for seed in 0..50 {
+            let check = confirm_crit("d20", 10, DEFAULT_THREAT, &mut StdRng::seed_from_u64(seed)).unwrap();
+            assert_eq!(check.natural == Some(20), check.threatened(), "seed {}", seed);
+            assert!(!check.threatened() || check.confirm.is_some());
+        }
+    }
+
+    #[test]
+    fn test_seeded() {
+        let a = confirm_crit("d20 + 5", 15, 19, &mut StdRng::seed_from_u64(9)).unwrap();
+        let b = confirm_crit("d20 + 5", 15, 19, &mut StdRng::seed_from_u64(9)).unwrap();
+        assert_eq!(a.attack, b.attack);
+        assert_eq!(a.confirmed(), b.confirmed());
+    }
+
+    #[test]
+    fn test_constant_attack() {
+        let check = confirm_crit("20", 15, DEFAULT_THREAT, &mut StdRng::seed_from_u64(1)).unwrap();
+        assert_eq!(None, check.natural);
+        assert!(!check.threatened());
+    }
+}
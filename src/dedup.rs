@@ -0,0 +1,159 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long an identical command from the same user is treated as an
+/// accidental resend.
+pub(crate) const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_millis(1500);
+
+/// Suppresses identical commands sent by the same user in the same chat within
+/// a short window, so a double-tapped `/roll` only rolls once.
+pub(crate) struct Dedup {
+    window: Duration,
+    recent: Mutex<HashMap<(i64, i64, String), Instant>>,
+}
+
+impl Dedup {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records the command, returning true if an identical one was already seen
+    /// within the window. A zero window disables deduplication.
+    pub(crate) fn is_duplicate(&self, chat: i64, user: i64, text: &str, now: Instant) -> bool {
+        if self.window.is_zero() {
+            return false;
+        }
+
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        recent.retain(|_, at| now.duration_since(*at) < self.window);
+
+        let key = (chat, user, text.trim().to_string());
+        if recent.contains_key(&key) {
+            return true;
+        }
+        recent.insert(key, now);
+        false
+    }
+}
+
+impl Default for Dedup {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEDUP_WINDOW)
+    }
+}
+
+/// How many of the most recently handled messages are remembered for telling
+/// whether an edit changed their text.
+pub(crate) const DEFAULT_MAX_EDITS: usize = 10_000;
+
+/// Remembers the text each recent message was handled with, so an edit that
+/// leaves the text as it was, like one that only loads a link preview, isn't
+/// rolled again.
+pub(crate) struct Edits {
+    max: usize,
+    handled: Mutex<Handled>,
+}
+
+/// The text of each remembered message by chat and message id, along with the
+/// order they were first handled in.
+#[derive(Default)]
+struct Handled {
+    texts: HashMap<(i64, i32), String>,
+    order: VecDeque<(i64, i32)>,
+}
+
+impl Edits {
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            max,
+            handled: Mutex::new(Handled::default()),
+        }
+    }
+
+    /// Records `text` as handled for the message, returning false if it was
+    /// already handled with the same text. Once more than the most messages
+    /// are remembered the oldest is forgotten.
+    pub(crate) fn changed(&self, chat: i64, msg: i32, text: &str) -> bool {
+        let mut handled = self.handled.lock().unwrap_or_else(|e| e.into_inner());
+        let Handled { texts, order } = &mut *handled;
+
+        let text = text.trim();
+        match texts.get_mut(&(chat, msg)) {
+            Some(last) if last == text => return false,
+            Some(last) => *last = text.to_string(),
+            None => {
+                texts.insert((chat, msg), text.to_string());
+                order.push_back((chat, msg));
+                while order.len() > self.max {
+                    if let Some(oldest) = order.pop_front() {
+                        texts.remove(&oldest);
+                    }
+                }
+            }
+        }
+        true
+    }
+}
+
+impl Default for Edits {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_EDITS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_duplicate() {
+        let dedup = Dedup::new(Duration::from_secs(1));
+        let now = Instant::now();
+
+        assert!(!dedup.is_duplicate(1, 2, "/roll 2d6", now));
+        assert!(dedup.is_duplicate(1, 2, "/roll 2d6", now + Duration::from_millis(200)));
+
+        // other users, chats, and commands are unaffected
+        assert!(!dedup.is_duplicate(1, 3, "/roll 2d6", now));
+        assert!(!dedup.is_duplicate(4, 2, "/roll 2d6", now));
+        assert!(!dedup.is_duplicate(1, 2, "/roll 2d8", now));
+
+        // a genuine repeat after the window rolls again
+        assert!(!dedup.is_duplicate(1, 2, "/roll 2d6", now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_edits() {
+        let edits = Edits::new(2);
+        assert!(edits.changed(1, 10, "/roll 2d6"));
+
+        // an edit that leaves the text alone isn't handled again
+        assert!(!edits.changed(1, 10, "/roll 2d6"));
+        assert!(!edits.changed(1, 10, "/roll 2d6 "));
+
+        // changing the text is, and the change is what later edits compare to
+        assert!(edits.changed(1, 10, "/roll 2d8"));
+        assert!(!edits.changed(1, 10, "/roll 2d8"));
+        assert!(edits.changed(1, 10, "/roll 2d6"));
+
+        // the same message id in another chat is another message
+        assert!(edits.changed(2, 10, "/roll 2d6"));
+
+        // the oldest message is forgotten past the limit
+        assert!(edits.changed(1, 11, "/roll d20"));
+        assert!(edits.changed(1, 10, "/roll 2d6"));
+        assert!(!edits.changed(1, 11, "/roll d20"));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let dedup = Dedup::new(Duration::ZERO);
+        let now = Instant::now();
+        assert!(!dedup.is_duplicate(1, 2, "/roll 2d6", now));
+        assert!(!dedup.is_duplicate(1, 2, "/roll 2d6", now));
+    }
+}
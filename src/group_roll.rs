@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::rolls;
+use crate::rolls::{EvalError, Roll, MAX_MESSAGE_DICE};
+
+/// How long a group roll stays open for contributions before it is discarded.
+pub(crate) const GROUP_ROLL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Tracks the pending group roll (if any) for each chat, keyed by chat id.
+pub(crate) struct GroupRolls {
+    timeout: Duration,
+    pending: Mutex<HashMap<i64, Pending>>,
+}
+
+struct Pending {
+    expr: String,
+    opened: Instant,
+    contributions: Vec<Contribution>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Contribution {
+    pub(crate) user_id: i64,
+    pub(crate) name: String,
+    pub(crate) roll: Roll,
+    pub(crate) value: i64,
+}
+
+#[derive(Debug)]
+pub(crate) struct Tally {
+    pub(crate) expr: String,
+    pub(crate) total: i64,
+    pub(crate) contributions: Vec<Contribution>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum GroupRollError {
+    InvalidExpression,
+    AlreadyOpen,
+    NotOpen,
+    AlreadyJoined,
+    /// The roll, or the group's running total, is too large to calculate.
+    Overflow,
+    /// The roll divides by zero, with strict division set.
+    DivisionByZero,
+    /// The expression rolls more dice than a message may.
+    TooManyDice,
+}
+
+impl GroupRolls {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Opens a new group roll in `chat`, validating `expr` up front so that
+    /// joining users never hit a parse error, nor roll more dice than any other
+    /// message may.
+    pub(crate) fn start(&self, chat: i64, expr: &str, now: Instant) -> Result<(), GroupRollError> {
+        if rolls::dice_count(expr).map_err(|_| GroupRollError::InvalidExpression)? > MAX_MESSAGE_DICE {
+            return Err(GroupRollError::TooManyDice);
+        }
+
+        let mut pending = self.lock(now);
+        if pending.contains_key(&chat) {
+            return Err(GroupRollError::AlreadyOpen);
+        }
+
+        pending.insert(chat, Pending {
+            expr: expr.trim().to_string(),
+            opened: now,
+            contributions: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Rolls the group expression on behalf of a user and adds it to the pool.
+    /// Each user may only contribute once per group roll, and rolls that would
+    /// overflow the group's total are turned away. The roll is made without
+    /// holding the lock, so a large one doesn't hold up other chats.
+    pub(crate) fn join(&self, chat: i64, user_id: i64, name: &str, now: Instant) -> Result<i64, GroupRollError> {
+        let (expr, opened) = {
+            let pending = self.lock(now);
+            let group = pending.get(&chat).ok_or(GroupRollError::NotOpen)?;
+            if group.joined(user_id) {
+                return Err(GroupRollError::AlreadyJoined);
+            }
+            (group.expr.clone(), group.opened)
+        };
+
+        let roll = rolls::parse(&expr).map_err(|_| GroupRollError::InvalidExpression)?;
+        let value = roll.value().map_err(|e| match e {
+            EvalError::Overflow => GroupRollError::Overflow,
+            EvalError::DivisionByZero => GroupRollError::DivisionByZero,
+        })?;
+
+        // the group may have ended, or the user joined it twice, while rolling
+        let mut pending = self.lock(now);
+        let group = pending.get_mut(&chat)
+            .filter(|g| g.opened == opened)
+            .ok_or(GroupRollError::NotOpen)?;
+        if group.joined(user_id) {
+            return Err(GroupRollError::AlreadyJoined);
+        }
+        let total = group.contributions.iter()
+            .map(|c| c.value)
+            .try_fold(value, i64::checked_add);
+        if total.is_none() {
+            return Err(GroupRollError::Overflow);
+        }
+        group.contributions.push(Contribution {
+            user_id,
+            name: name.to_string(),
+            roll,
+            value,
+        });
+        Ok(value)
+    }
+
+    /// Closes the group roll in `chat` and sums every contribution.
+    pub(crate) fn end(&self, chat: i64, now: Instant) -> Result<Tally, GroupRollError> {
+        let group = self.lock(now)
+            .remove(&chat)
+            .ok_or(GroupRollError::NotOpen)?;
+
+        Ok(Tally {
+            expr: group.expr,
+            total: group.contributions.iter().map(|c| c.value).sum(),
+            contributions: group.contributions,
+        })
+    }
+
+    fn lock(&self, now: Instant) -> std::sync::MutexGuard<'_, HashMap<i64, Pending>> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.retain(|_, p| now.duration_since(p.opened) < self.timeout);
+        pending
+    }
+}
+
+impl Pending {
+    fn joined(&self, user_id: i64) -> bool {
+        self.contributions.iter().any(|c| c.user_id == user_id)
+    }
+}
+
+impl Default for GroupRolls {
+    fn default() -> Self {
+        Self::new(GROUP_ROLL_TIMEOUT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAT: i64 = 42;
+
+    #[test]
+    fn test_group_roll() {
+        let groups = GroupRolls::default();
+        let now = Instant::now();
+
+        assert_eq!(Ok(()), groups.start(CHAT, "d6", now));
+        assert_eq!(Err(GroupRollError::AlreadyOpen), groups.start(CHAT, "d6", now));
+
+        let mut sum = 0;
+        for user in 1..=4 {
+            let value = groups.join(CHAT, user, "player", now).unwrap();
+            assert!((1..=6).contains(&value));
+            sum += value;
+        }
+        assert_eq!(Err(GroupRollError::AlreadyJoined), groups.join(CHAT, 1, "player", now).map(|_| ()));
+
+        let tally = groups.end(CHAT, now).unwrap();
+        assert_eq!("d6", tally.expr);
+        assert_eq!(4, tally.contributions.len());
+        assert_eq!(sum, tally.total);
+
+        assert_eq!(Err(GroupRollError::NotOpen), groups.end(CHAT, now).map(|_| ()));
+    }
+
+    #[test]
+    fn test_group_roll_timeout() {
+        let groups = GroupRolls::new(Duration::from_secs(10));
+        let now = Instant::now();
+
+        assert_eq!(Ok(()), groups.start(CHAT, "d6", now));
+        assert_eq!(Err(GroupRollError::NotOpen), groups.join(CHAT, 1, "player", now + Duration::from_secs(10)));
+        assert_eq!(Ok(()), groups.start(CHAT, "d6", now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_group_roll_invalid() {
+        let groups = GroupRolls::default();
+        assert_eq!(Err(GroupRollError::InvalidExpression), groups.start(CHAT, "3dd6", Instant::now()));
+        assert_eq!(Err(GroupRollError::NotOpen), groups.join(CHAT, 1, "player", Instant::now()));
+    }
+
+    #[test]
+    fn test_group_roll_dice() {
+        let groups = GroupRolls::default();
+        let many = ["1000000d6"; 3].join(" + ");
+        assert_eq!(Err(GroupRollError::TooManyDice), groups.start(CHAT, &many, Instant::now()));
+        assert_eq!(Err(GroupRollError::NotOpen), groups.join(CHAT, 1, "player", Instant::now()));
+        assert_eq!(Ok(()), groups.start(CHAT, "1000000d6 + 1000000d6", Instant::now()));
+    }
+
+    #[test]
+    fn test_group_roll_overflow() {
+        let groups = GroupRolls::default();
+        let now = Instant::now();
+
+        assert_eq!(Ok(()), groups.start(CHAT, "9223372036854775807", now));
+        assert_eq!(Ok(i64::MAX), groups.join(CHAT, 1, "player", now));
+        assert_eq!(Err(GroupRollError::Overflow), groups.join(CHAT, 2, "player", now));
+        assert_eq!(i64::MAX, groups.end(CHAT, now).unwrap().total);
+    }
+}
@@ -1,85 +1,715 @@
-use grammers_client::{InputMessage, Update};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+use std::sync::{Arc, OnceLock};
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use grammers_client::{button, Client, InputMessage, InvocationError, reply_markup, Update};
+use grammers_client::types::{CallbackQuery, Chat, InlineQuery, Message};
+use grammers_client::types::inline::query::Article;
+use grammers_tl_types as tl;
 use log::{info, trace, warn};
+use rand::{Rng, SeedableRng, thread_rng};
+use rand::rngs::StdRng;
+use tokio::task;
+use tokio::time::sleep;
 use nom::branch::alt;
-use nom::bytes::complete::tag_no_case;
-use nom::character::complete::multispace1;
+use nom::bytes::complete::{tag, tag_no_case, take_until};
+use nom::character::complete::{digit1, multispace0, multispace1};
 use nom::character::streaming::char;
-use nom::combinator::{eof, opt, rest};
+use nom::combinator::{eof, map, map_opt, map_res, opt, recognize, rest, value};
 use nom::IResult;
-use nom::sequence::{preceded, tuple};
+use nom::sequence::{delimited, pair, preceded, tuple};
 
 use crate::{Result, rolls};
-use crate::rolls::Roll;
-
-const START_MSG: &str = "Let *Dice Goblin* roll for you!
-
-Dice Goblin will roll any-sided rolls and perform simple arithmetic to reach a total value, appropriate for many tabletop and RPG games. See /help for details on the commands and syntax available.";
-
-const HELP_MSG: &str = "*COMMANDS*
-
-/start\\
-_See introductory information about this bot_
-
-/help\\
-_See this help output_
-
-/roll `[expression]`\\
-_Rolls and calculate a total (see expression syntax below)_
+use crate::animate::EditGuard;
+use crate::choose::{choose, MAX_OPTIONS, parse_options};
+use crate::commands::{AVERAGE, BREAKDOWN, CHOOSE, CONFIRM_CRIT, CRIT, GROUP_ROLL, HELP, JOIN, keyword, LAST, RANGE, ROLL, SET_REROLL_ONES, SLOW_ROLL, START, STATS, TIMESTAMPS, TIMEZONE, VALIDATE};
+use crate::crit::{confirm_crit, CritCheck, DEFAULT_THREAT};
+use crate::dedup::{Dedup, Edits};
+use crate::group_roll::{GroupRollError, GroupRolls};
+use crate::history::{History, HISTORY_DEPTH, Lookup};
+use crate::locale::{Locale, Text};
+use crate::metrics::METRICS;
+use crate::rate_limit::RateLimit;
+use crate::rolls::{EvalError, Limit, MAX_MESSAGE_DICE, MAX_REPEAT, MAX_ROLLS, ParseError, Roll};
+use crate::settings::{format_utc_offset, parse_utc_offset, Settings, UserSettings};
 
-/r `[expression]`\\
-_Alias for /roll_
+const HELP_DICE: &str = "*DICE*
 
-/`[expression]`\\
-_Alias for /roll_
-
-*ROLL EXPRESSION SYNTAX*
-
-Dice rolls are described in the standard `NdS` format, where `N` is the number of rolls and `S` is the number of sides. Each roll is summed together to calculate the overall value.
+Dice rolls are described in the standard `NdS` format, where `N` is the number of rolls and `S` is the number of sides. Each roll is summed together to calculate the overall value. In the results, dice showing their highest face are *bold* and dice showing their lowest face are _italic_.
 
 *Examples:*\\
 `3d10` - Roll a ten-sided die three times\\
+`6x 4d6kh3` - Roll the same expression six separate times (up to 100)\\
+`seed:1234 3d6` - Roll with a fixed seed, like the one shown after every roll, so everyone gets the same result\\
+`preview: 3d6` - Show every die on its average face instead of rolling, to check the math\\
+`2d6 # fire damage` - Label a roll with a comment after `#` or in quotes at the end\\
+`2d6; d20` - Roll several expressions at once (up to 20), separated by `;` or each on its own line\\
 `d6` - Roll a single six-sided die (N defaults to 1 if omitted)\\
 `D2` - flip a coin (The `d` is case-insensitive)\\
+`d%` - Roll a percentile die, the same as `d100`\\
+`(d4)d6` - Roll a four-sided die, then roll that many six-sided dice\\
+`3d(2d6)` - Roll 2d6 for the number of sides, then roll three dice with that many sides\\
+`2z10` - Roll two ten-sided dice numbered from 0 to 9 (for percentile tens)\\
+`4dF` - Roll four Fate dice, each counting as -1, 0, or +1 (shown as -, 0, +)";
+
+const HELP_POOLS: &str = "*MODIFIERS*
+
+Dice can be followed by modifiers that change how they are rolled.
 
-Rolls support basic arithmetic using the operators (+, -, \\*, /) as well as parenthesis. Division always rounds towards zero, and division by zero always equals zero.
+*Modifiers:*\\
+`2d6rl` - Roll two six-sided dice, then reroll the lowest die once\\
+`2d6ro2` - Reroll any die showing 2 or lower once, keeping the new result\\
+`4d6r1` - Keep rerolling any die showing 1 until it doesn't\\
+`3d6!` - Roll three six-sided dice, adding another die whenever one shows a 6\\
+`3d6!!` - Like `!`, but the extra rolls are added into the die that rolled the 6\\
+`3d10!>=9` - Explode on any die meeting the comparison instead of only the highest face\\
+`2d20kh1` - Roll two twenty-sided dice and keep the highest (`kh` defaults to one)\\
+`d20 adv` - Roll a twenty-sided die with advantage, keeping the higher of two (`dis` keeps the lower)\\
+`4d6kl3` - Roll four six-sided dice and keep the three lowest\\
+`4d6dl1` - Roll four six-sided dice and drop the lowest (`dh` drops the highest)\\
+`(2d6 + 1d4 + 2)kh2` - Keep the two highest dice across the group; numbers are always added\\
+`8d10>=7` - Count how many of eight ten-sided dice show 7 or more (also `>`, `<=`, `<`, `=`)\\
+`10d10>=8f<=1` - Count the dice showing 8 or more, minus those showing 1 (failures are shown in italics)\\
+`d6w[1:3,6:3]` - Roll a loaded die where 1 and 6 are three times as likely (marked as loaded)\\
+`4d6mi2` - Count any die showing less than 2 as a 2 (shown as 1↑2)\\
+`6d6s` - List the dice lowest first without changing the total (`sd` lists them highest first)";
+
+const HELP_MATH: &str = "*ARITHMETIC*
+
+Rolls support basic arithmetic using the operators (+, -, \\*, /, %, ^) as well as parenthesis. Division rounds towards zero unless followed by `f` (down), `c` (up), or `r` (nearest), and `//` is the same as `/f`. Division or remainder by zero equals zero by default, though in strict mode it's reported as an error instead.
 
 *Examples:*\\
 `3d10 + 2` - Roll three ten-sided rolls and add two to the result\\
 `(d6 - 1) * 2` - Roll a six-sided die, subtract one from the roll, and then double the result\\
+`-(2d6) + 10` - Negate a roll, subtracting it from the rest\\
 `3 / 2` - Equals 1 (1.5 rounded towards zero)\\
-`1 / 0` - Division by zero always equals zero";
+`7 /r 2` - Equals 4 (3.5 rounded to the nearest number)\\
+`-7 // 2` - Equals -4 (-3.5 rounded down)\\
+`1 / 0` - Equals zero by default, or is reported as an error in strict mode\\
+`d20 % 6` - The remainder of a twenty-sided die divided by six\\
+`max(2d6, 1d12)` - The higher of the two rolls (`min` picks the lower)\\
+`abs(d20 - d20)` - How far apart two twenty-sided dice land\\
+`clamp(d20 + 5, 1, 20)` - Keep the total between 1 and 20\\
+`2 ^ d6` - Raise two to the power of a six-sided die (exponents are applied before other operators)";
+
+/// The sections of the roll syntax shown by `/help [topic]`, along with the
+/// summary the overview lists them by.
+const HELP_TOPICS: &[(&str, &str, &str)] = &[
+    ("dice", "Dice notation, repeats, seeds, and labels", HELP_DICE),
+    ("pools", "Rerolling, exploding, keeping, and counting dice", HELP_POOLS),
+    ("math", "Arithmetic, rounding, and picking the higher or lower roll", HELP_MATH),
+];
+
+/// The most rows a /stats histogram shows before grouping totals into ranges.
+const MAX_STATS_ROWS: usize = 25;
+const STATS_BAR_WIDTH: usize = 20;
+
+/// Telegram rejects messages longer than 4096 characters, so roll reports are
+/// kept a little under that.
+const MAX_REPLY_LEN: usize = 4000;
+
+/// The most dice a slow roll reveals one at a time, keeping every frame well
+/// under `MAX_REPLY_LEN`.
+const MAX_SLOW_ROLL_DICE: usize = 100;
+
+/// The most answers, and the longest answer, Telegram allows in a poll.
+const MAX_POLL_OPTIONS: usize = 10;
+const MAX_POLL_OPTION_LEN: usize = 100;
+
+/// The longest flood wait a reply waits out before it's given up on.
+const MAX_FLOOD_WAIT: Duration = Duration::from_secs(60);
+
+/// The most bytes of data Telegram lets a button send back when pressed.
+const MAX_CALLBACK_DATA: usize = 64;
+
+/// How much of the unparsed text a roll error echoes back.
+const MAX_ERROR_CONTEXT: usize = 20;
+
+const OVERFLOW_MSG: &str = "That total is too large for me to calculate";
+const DIVISION_BY_ZERO_MSG: &str = "That roll divides by zero, so it has no total";
+
+const ONBOARDING_MSG: &str = "Welcome to *Dice Goblin*! Try `/roll 2d6 + 3` or just `/d20`. See /help for everything else.";
+
+const GROUP_INVALID_MSG: &str = "That isn't a valid roll expression. Use /help to see the syntax";
+const GROUP_OPEN_MSG: &str = "A group roll is already in progress. Use /grouproll end to tally it first";
+const GROUP_NOT_OPEN_MSG: &str = "No group roll in progress. Start one with /grouproll start `[expression]`";
+const GROUP_JOINED_MSG: &str = "You already rolled into this group roll";
+const GROUP_OVERFLOW_MSG: &str = "That roll would make the group total too large to calculate";
+
+static NATURAL_CRITS: OnceLock<bool> = OnceLock::new();
+
+/// The start message in each locale, parsed from markdown the first time it's
+/// sent. Messages are cheap to clone, so each reply is a copy rather than a
+/// fresh parse.
+static START_REPLIES: [OnceLock<InputMessage>; Locale::ALL.len()] = [const { OnceLock::new() }; Locale::ALL.len()];
+
+/// The help overview in each locale, then each of the `HELP_TOPICS` in order,
+/// parsed like the start message.
+static HELP_REPLIES: [OnceLock<(InputMessage, Vec<InputMessage>)>; Locale::ALL.len()] = [const { OnceLock::new() }; Locale::ALL.len()];
+
+/// Sets whether a lone d20 landing on 20 or 1 is announced as a critical. Only
+/// the first call has any effect.
+pub(crate) fn set_natural_crits(on: bool) {
+    let _ = NATURAL_CRITS.set(on);
+}
+
+fn natural_crits() -> bool {
+    NATURAL_CRITS.get().copied().unwrap_or(true)
+}
+
+/// What a roll that's only a d20 calls out when it lands on a 20 or a 1.
+fn natural_crit(roll: &Roll) -> Option<&'static str> {
+    match roll.natural_d20() {
+        Some(20) if natural_crits() => Some("*Critical!*"),
+        Some(1) if natural_crits() => Some("*Critical fail!*"),
+        _ => None,
+    }
+}
+
+/// State shared across every update handled by the bot.
+#[derive(Default)]
+pub(crate) struct State {
+    group_rolls: GroupRolls,
+    settings: Settings,
+    history: History,
+    dedup: Dedup,
+    edits: Edits,
+    rate_limit: RateLimit,
+}
+
+impl State {
+    /// State for a bot that has already seen the `seen` users, as saved by
+    /// an earlier run.
+    pub(crate) fn new(dedup: Dedup, rate_limit: RateLimit, seen: HashSet<i64>) -> Self {
+        Self {
+            settings: Settings::with_seen_users(seen),
+            dedup,
+            rate_limit,
+            ..Default::default()
+        }
+    }
+
+    /// Every user the bot has seen, for saving between runs.
+    pub(crate) fn seen_users(&self) -> HashSet<i64> {
+        self.settings.seen_users()
+    }
+}
 
-const UNKNOWN_MSG: &str = "Unknown command. Use /help to see available commands";
+pub(crate) async fn handle(update: Update, client: Client, state: Arc<State>) -> Result {
+    let res = handle_update(update, client, state).await;
+    if res.is_err() {
+        METRICS.handler_errors.inc();
+    }
+    res
+}
 
-pub(crate) async fn handle(update: Update) -> Result {
-    let msg = match update {
-        Update::NewMessage(m) if !m.outgoing() && !m.text().is_empty() => m,
+async fn handle_update(update: Update, client: Client, state: Arc<State>) -> Result {
+    let (msg, edited) = match update {
+        Update::NewMessage(m) if !m.outgoing() && !m.text().is_empty() => (m, false),
+        // edited commands are handled like new ones, replying with a fresh result
+        Update::MessageEdited(m) if !m.outgoing() && !m.text().is_empty() => (m, true),
+        Update::InlineQuery(query) => return inline_roll(&query).await,
+        Update::CallbackQuery(query) => return reroll(&query, &state).await,
         _ => {
             trace!("ignoring: {:?}", update);
             return Ok(());
         }
     };
 
+    let chat = msg.chat().id();
+    if !state.edits.changed(chat, msg.id(), msg.text()) && edited {
+        trace!("ignoring edit that didn't change the text: {}", msg.text());
+        return Ok(());
+    }
+
+    // channel posts have no sender, so the channel stands in for one
+    let user = msg.sender().map(|u| u.id()).unwrap_or(chat);
+    if state.dedup.is_duplicate(chat, user, msg.text(), Instant::now()) {
+        trace!("ignoring duplicate command: {}", msg.text());
+        return Ok(());
+    }
+
+    if !state.rate_limit.allow(user, Instant::now()) {
+        info!("rate limited {}: {}", user, msg.text());
+        return Ok(());
+    }
+
     let cmd = Command::from(msg.text());
-    match msg.sender() {
-        Some(user) if user.id() != msg.chat().id() => msg.reply(cmd).await?,
-        _ => msg.respond(cmd).await?,
+    match &cmd {
+        Command::Roll { lines, .. } => {
+            let failed = lines.iter().filter(|l| l.error.is_some()).count();
+            METRICS.rolls.add((lines.len() - failed) as u64);
+            METRICS.parse_failures.add(failed as u64);
+        }
+        Command::Error(_) => METRICS.parse_failures.inc(),
+        _ => {}
+    }
+    let onboard = onboard(&state.settings, &cmd, msg.sender().map(|u| u.id()));
+
+    let prefs = state.settings.user(user);
+    let locale = sender_locale(msg.sender().as_ref());
+
+    let reply: Reply = match cmd {
+        Command::Group(action) => {
+            let sender = match msg.sender() {
+                Some(sender) => (sender.id(), sender.name().to_string()),
+                None => (chat, msg.chat().name().to_string()),
+            };
+            let state = state.clone();
+            // joining rolls the group's dice, so it's kept off the async workers
+            let result = task::spawn_blocking(move || group_roll(&state.group_rolls, action, chat, sender, Instant::now())).await?;
+            InputMessage::markdown(result).into()
+        }
+        Command::Setting(change) => {
+            let settings = state.settings.update(chat, |s| match change {
+                SettingChange::Timestamps(on) => s.timestamps = on,
+                SettingChange::Breakdown(on) => s.breakdown = on,
+                SettingChange::Timezone(offset) => s.utc_offset = offset,
+            });
+            InputMessage::markdown(match change {
+                SettingChange::Timestamps(true) => "Roll timestamps enabled for this chat".to_string(),
+                SettingChange::Timestamps(false) => "Roll timestamps disabled for this chat".to_string(),
+                SettingChange::Breakdown(true) => "Roll breakdowns enabled for this chat".to_string(),
+                SettingChange::Breakdown(false) => "Roll breakdowns disabled for this chat".to_string(),
+                SettingChange::Timezone(_) => format!("Timezone set to {}", format_utc_offset(settings.utc_offset)),
+            }).into()
+        }
+        Command::RerollOnes(on) => {
+            let prefs = state.settings.update_user(user, |s| s.reroll_ones = on.unwrap_or(!s.reroll_ones));
+            InputMessage::markdown(match prefs.reroll_ones {
+                true => "Rerolling 1s once on all of your rolls",
+                false => "No longer rerolling 1s on your rolls",
+            }).into()
+        }
+        Command::Roll { lines, .. } => {
+            let settings = state.settings.get(chat);
+            let (breakdown, timestamp) = (settings.breakdown, settings.timestamp(msg.date()));
+            let (reply, report) = task::spawn_blocking(move || {
+                roll_reply(lines, prefs, breakdown, timestamp, Some((chat, user)), locale)
+            }).await?;
+            if let Some(report) = report {
+                state.history.record(chat, user, report, Instant::now());
+            }
+            reply.into()
+        }
+        Command::Last(back) => InputMessage::markdown(match state.history.get(chat, user, back) {
+            Lookup::Found(report) => format!("_Rolled earlier:_\\\n{}", report),
+            Lookup::TooFarBack(0) => "You haven't rolled in this chat yet".to_string(),
+            Lookup::TooFarBack(1) => "You've only rolled once in this chat".to_string(),
+            Lookup::TooFarBack(n) => format!("You've only rolled {} times in this chat", n),
+        }).into(),
+        Command::SlowRoll(expr) => match task::spawn_blocking(move || slow_roll(&expr, prefs, locale)).await? {
+            Ok(reply) => reply,
+            Err(msg) => InputMessage::markdown(msg).into(),
+        },
+        Command::Choose { options, poll: true } if fits_poll(&options) => {
+            let picked = choose(&options, &mut thread_rng()).unwrap_or_default();
+            let result = match send_poll(&client, &msg, &options, picked).await {
+                Ok(()) => choice_reveal(&options[picked]),
+                Err(e) => {
+                    warn!("failed to send poll, listing the options instead: {}", e);
+                    choice_list(&options, picked)
+                }
+            };
+            info!("choose: {}", result);
+            InputMessage::markdown(result).into()
+        }
+        // rolling and charting can take a while, so it's kept off the async workers
+        cmd => Reply::Message(task::spawn_blocking(move || cmd.reply(locale)).await?),
+    };
+    send(&msg, reply).await?;
+    if onboard {
+        retry_flood(|| msg.respond(InputMessage::markdown(ONBOARDING_MSG))).await?;
+    }
+
+    Ok(())
+}
+
+/// Answers an inline query like `@DiceGoblinBot 3d6` with a single result
+/// holding the rolled expression. Empty or invalid queries get no results.
+async fn inline_roll(query: &InlineQuery) -> Result {
+    let results: Vec<Article> = inline_report(query.text())
+        .map(|report| Article::new(format!("Roll {}", query.text().trim()), InputMessage::markdown(report))
+            .description("Send the total and every die rolled"))
+        .into_iter()
+        .collect();
+    // results aren't cached, so every query rolls fresh dice
+    query.answer(results).cache_time(0).private().send().await?;
+    Ok(())
+}
+
+/// Whether `sender` should get the onboarding tip along with the reply to
+/// `cmd`, which is only the first time they're seen. Users who start with
+/// `/start` or `/help` are shown the help instead, so they're counted as seen
+/// without the tip. Channels posting as themselves are never sent it.
+fn onboard(settings: &Settings, cmd: &Command, sender: Option<i64>) -> bool {
+    match (cmd, sender) {
+        (Command::Start | Command::Help(_), Some(user)) => {
+            settings.first_visit(user);
+            false
+        }
+        (_, Some(user)) => settings.first_visit(user),
+        (_, None) => false,
+    }
+}
+
+/// The locale to reply to `sender` in, from the language their Telegram app
+/// is set to. Groups and channels posting as themselves get the default.
+fn sender_locale(sender: Option<&Chat>) -> Locale {
+    match sender {
+        Some(Chat::User(user)) => Locale::from_lang_code(user.lang_code()),
+        _ => Locale::default(),
+    }
+}
+
+/// Rolls the command stored in a reroll button again for whoever pressed it,
+/// replying to the message the button is under so earlier results stay put.
+async fn reroll(query: &CallbackQuery, state: &State) -> Result {
+    let lines = match std::str::from_utf8(query.data()).map(Command::from) {
+        Ok(Command::Roll { lines, .. }) => lines,
+        _ => {
+            query.answer().send().await?;
+            return Ok(());
+        }
     };
 
+    let user = query.sender().id();
+    if !state.rate_limit.allow(user, Instant::now()) {
+        info!("rate limited {}: reroll", user);
+        query.answer().text("Slow down! Try again in a moment").send().await?;
+        return Ok(());
+    }
+
+    let chat = query.chat().id();
+    let prefs = state.settings.user(user);
+    let settings = state.settings.get(chat);
+    let locale = sender_locale(Some(query.sender()));
+    let (breakdown, timestamp) = (settings.breakdown, settings.timestamp(Utc::now()));
+    let (reply, report) = task::spawn_blocking(move || {
+        roll_reply(lines, prefs, breakdown, timestamp, Some((chat, user)), locale)
+    }).await?;
+    if let Some(report) = report {
+        state.history.record(chat, user, report, Instant::now());
+    }
+    query.answer().send().await?;
+    let original = query.load_message().await?;
+    deliver(&original, reply).await?;
+    Ok(())
+}
+
+/// Rolls an inline query. The report echoes the expression, which matters all
+/// the more here since the reply appears without the query that produced it.
+fn inline_report(query: &str) -> Option<String> {
+    let expr = query.trim();
+    if expr.is_empty() {
+        return None;
+    }
+    let roll = rolls::parse(expr).ok().filter(|r| r.value().is_ok())?;
+    let line = RollLine { expr: expr.to_string(), ..Default::default() };
+    Some(roll_report(&[(line, vec![roll])], UserSettings::default(), false, None))
+}
+
+/// A reply to a command, either sent once or animated through a series of edits.
+enum Reply {
+    Message(InputMessage),
+    Animated {
+        frames: usize,
+        frame: Box<dyn Fn(usize) -> String + Send + Sync>,
+    },
+}
+
+impl From<InputMessage> for Reply {
+    fn from(msg: InputMessage) -> Self {
+        Reply::Message(msg)
+    }
+}
+
+async fn send(msg: &Message, reply: Reply) -> Result {
+    match reply {
+        Reply::Message(m) => {
+            deliver(msg, m).await?;
+        }
+        Reply::Animated { frames, frame } => animate(msg, frames, frame.as_ref(), &EditGuard::default()).await?,
+    }
+    Ok(())
+}
+
+/// Replies to the message, or responds without threading if it shouldn't be
+/// threaded.
+async fn deliver(msg: &Message, reply: InputMessage) -> std::result::Result<Message, InvocationError> {
+    let threaded = threaded(matches!(msg.chat(), Chat::User(_)), msg.sender().is_some());
+    retry_flood(|| async {
+        match threaded {
+            true => msg.reply(reply.clone()).await,
+            false => msg.respond(reply.clone()).await,
+        }
+    }).await
+}
+
+/// Whether a reply should be threaded onto the message it answers. Replies in
+/// groups are, so it's clear which message they answer, while private chats
+/// have only one person to answer. Channel posts have no sender, and the reply
+/// is a post of its own in the channel's feed rather than a reply to one.
+fn threaded(private: bool, has_sender: bool) -> bool {
+    !private && has_sender
+}
+
+/// Sends with `send`, and if Telegram asks us to wait, waits it out and tries
+/// once more so the reply isn't lost.
+async fn retry_flood<T, F, Fut>(mut send: F) -> std::result::Result<T, InvocationError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output=std::result::Result<T, InvocationError>>,
+{
+    match send().await {
+        Err(e) => match flood_wait(&e) {
+            Some(wait) => {
+                warn!("flood limited, retrying in {:?}: {}", wait, e);
+                sleep(wait).await;
+                send().await
+            }
+            None => Err(e),
+        },
+        res => res,
+    }
+}
+
+/// How long a flood wait error asks us to hold off, if it's short enough to
+/// be worth waiting for.
+fn flood_wait(err: &InvocationError) -> Option<Duration> {
+    match err {
+        InvocationError::Rpc(e) if e.name == "FLOOD_WAIT" => {
+            Some(Duration::from_secs(e.value.unwrap_or(1).into())).filter(|wait| *wait <= MAX_FLOOD_WAIT)
+        }
+        _ => None,
+    }
+}
+
+/// Sends the first frame and then edits it through the frames chosen by the
+/// guard. If Telegram starts rate limiting the edits, the final frame is posted
+/// as a new message instead so the result is never lost.
+async fn animate(msg: &Message, frames: usize, frame: &(dyn Fn(usize) -> String + Send + Sync), guard: &EditGuard) -> Result {
+    if frames == 0 {
+        return Ok(());
+    }
+
+    let sent = deliver(msg, InputMessage::markdown(frame(0))).await?;
+    for i in guard.plan(frames) {
+        sleep(guard.interval).await;
+        match sent.edit(InputMessage::markdown(frame(i))).await {
+            Ok(_) => {}
+            Err(InvocationError::Rpc(e)) if e.name == "FLOOD_WAIT" => {
+                warn!("edits flood limited, sending final frame: {}", e);
+                deliver(msg, InputMessage::markdown(frame(frames - 1))).await?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
     Ok(())
 }
 
+/// Rolls `expr` with `rng`, applying the roller's preferences to every dice term.
+fn roll_with<R: Rng + ?Sized>(expr: &str, prefs: UserSettings, rng: &mut R) -> std::result::Result<Roll, ParseError> {
+    let start = Instant::now();
+    let roll = match prefs.reroll_ones {
+        true => rolls::parse_rerolling_ones_with_rng(expr, rng),
+        false => rolls::parse_with_rng(expr, rng),
+    };
+    METRICS.eval_time.observe(start.elapsed());
+    roll
+}
+
+/// Evaluates `expr` with every die on its average face rather than rolling it,
+/// applying the user's preferences just as `roll_with` does.
+fn preview_with(expr: &str, prefs: UserSettings) -> std::result::Result<Roll, ParseError> {
+    rolls::Expression::try_from(expr).map(|e| match prefs.reroll_ones {
+        true => e.reroll_ones().preview(),
+        false => e.preview(),
+    })
+}
+
+/// Rolls `expr` for /slowroll, revealing its dice one at a time, or says why
+/// it can't be rolled. Rolls of more than `MAX_SLOW_ROLL_DICE` dice are too
+/// long to reveal, so they're reported all at once like any other roll.
+fn slow_roll(expr: &str, prefs: UserSettings, locale: Locale) -> std::result::Result<Reply, String> {
+    if rolls::dice_count(expr).unwrap_or(0) > MAX_MESSAGE_DICE {
+        return Err(too_many_dice_msg());
+    }
+    let (roll, expr) = match rolls::split_seed(expr) {
+        (Some(seed), expr) => (roll_with(expr, prefs, &mut StdRng::seed_from_u64(seed)), expr),
+        (None, expr) => (roll_with(expr, prefs, &mut thread_rng()), expr),
+    };
+    let roll = match roll {
+        Ok(roll) if roll.value().is_err() => return Err(eval_error_msg(&roll).to_string()),
+        Ok(roll) => roll,
+        Err(_) => return Err(locale.text(Text::Unknown).to_string()),
+    };
+
+    let faces = roll.evaluate_all();
+    if faces.len() > MAX_SLOW_ROLL_DICE {
+        let line = RollLine { expr: expr.to_string(), ..Default::default() };
+        return Ok(InputMessage::markdown(roll_report(&[(line, vec![roll])], prefs, false, None)).into());
+    }
+    info!("slow roll: {}", outcome(&roll));
+    Ok(Reply::Animated {
+        frames: faces.len() + 1,
+        frame: Box::new(move |i| slow_roll_frame(&roll, &faces, i)),
+    })
+}
+
+/// Renders frame `i` of a slow roll of `faces`: the first `i` dice are
+/// revealed, and the frame after the last die shows the full result.
+fn slow_roll_frame(roll: &Roll, faces: &[i64], i: usize) -> String {
+    if i >= faces.len() {
+        let result = outcome(roll);
+        return if result.chars().count() <= MAX_REPLY_LEN { result } else { summary(roll) };
+    }
+
+    let shown: Vec<String> = faces.iter()
+        .enumerate()
+        .map(|(n, face)| if n < i { face.to_string() } else { "?".to_string() })
+        .collect();
+    format!("Rolling… {}", shown.join(", "))
+}
+
+/// Carries out a group roll action in `chat` for `sender`, given by id and
+/// name, returning the reply.
+fn group_roll(groups: &GroupRolls, action: GroupAction, chat: i64, sender: (i64, String), now: Instant) -> String {
+    let result = match action {
+        GroupAction::Start(expr) => groups.start(chat, &expr, now).map(|_| format!(
+            "Group roll started: `{}`\\\nUse /join to roll in, then /grouproll end to tally",
+            expr.trim(),
+        )),
+        GroupAction::Join => {
+            let (user_id, name) = sender;
+            groups.join(chat, user_id, &name, now)
+                .map(|value| format!("{} rolled {}", escape_markdown(&name), value))
+        }
+        GroupAction::End => groups.end(chat, now).map(|tally| {
+            let mut out = format!("Group roll `{}` total: *{}*", tally.expr, tally.total);
+            for c in &tally.contributions {
+                out.push_str(&format!("\\\n{}: {} = {}", escape_markdown(&c.name), c.value, c.roll));
+            }
+            info!("group roll: {}", out);
+            out
+        }),
+    };
+
+    match result {
+        Ok(out) => out,
+        Err(GroupRollError::InvalidExpression) => GROUP_INVALID_MSG.to_string(),
+        Err(GroupRollError::AlreadyOpen) => GROUP_OPEN_MSG.to_string(),
+        Err(GroupRollError::NotOpen) => GROUP_NOT_OPEN_MSG.to_string(),
+        Err(GroupRollError::AlreadyJoined) => GROUP_JOINED_MSG.to_string(),
+        Err(GroupRollError::Overflow) => GROUP_OVERFLOW_MSG.to_string(),
+        Err(GroupRollError::DivisionByZero) => DIVISION_BY_ZERO_MSG.to_string(),
+        Err(GroupRollError::TooManyDice) => too_many_dice_msg(),
+    }
+}
+
+/// Escapes characters that would otherwise be interpreted as markdown so that
+/// user-supplied text is rendered verbatim.
+fn escape_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '`' | '[' | ']' | '~') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
 #[derive(Debug)]
 enum Command {
     Start,
-    Help,
-    Roll(Roll),
+    /// Help on the given topic, or the overview without one.
+    Help(Option<String>),
+    Roll {
+        lines: Vec<RollLine>,
+    },
+    SlowRoll(String),
+    Average(String),
+    Range(String),
+    Stats(String),
+    Choose {
+        options: Vec<String>,
+        poll: bool,
+    },
+    Crit(String),
+    ConfirmCrit {
+        expr: String,
+        target: i64,
+        threat: i64,
+    },
+    Validate(Vec<String>),
+    Group(GroupAction),
+    Setting(SettingChange),
+    RerollOnes(Option<bool>),
+    /// Shows the sender's roll this many rolls back again, counting the latest as 1.
+    Last(usize),
+    /// A roll that stopped parsing partway.
+    Error(ParseError),
     Unknown,
 }
 
+/// A single expression in a roll command, with its optional label, how many
+/// times to roll it, and the seed to roll it with, if any.
+#[derive(Debug, Default, PartialEq)]
+struct RollLine {
+    expr: String,
+    label: Option<String>,
+    repeat: i64,
+    seed: Option<u64>,
+    /// Land every die on its average face rather than rolling it.
+    preview: bool,
+    /// Where a malformed expression stopped parsing. It's reported in place of
+    /// its roll, leaving the rest of the command to roll as usual.
+    error: Option<ParseError>,
+}
+
+/// Formats the line so that it parses back to the same roll.
+impl Display for RollLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.preview {
+            write!(f, "preview: ")?;
+        }
+        if let Some(seed) = self.seed {
+            write!(f, "seed:{} ", seed)?;
+        }
+        if self.repeat != 1 {
+            write!(f, "{}x ", self.repeat)?;
+        }
+        write!(f, "{}", self.expr.trim())?;
+        if let Some(label) = &self.label {
+            write!(f, " # {}", label)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum SettingChange {
+    Timestamps(bool),
+    Breakdown(bool),
+    Timezone(i32),
+}
+
+#[derive(Debug, PartialEq)]
+enum GroupAction {
+    Start(String),
+    Join,
+    End,
+}
+
 impl From<&str> for Command {
     fn from(value: &str) -> Self {
-        match parse_command(value) {
+        match parse_command(&strip_mention(value)) {
             Ok((_, cmd)) => cmd,
             Err(e) => {
                 warn!("malformed command received: {}", e);
@@ -89,20 +719,510 @@ impl From<&str> for Command {
     }
 }
 
-impl Into<InputMessage> for Command {
-    fn into(self) -> InputMessage {
+impl Command {
+    /// The reply to the command, with its fixed messages in `locale`.
+    fn reply(self, locale: Locale) -> InputMessage {
         use Command::*;
+        let unknown = || InputMessage::markdown(locale.text(Text::Unknown));
         match self {
-            Start => InputMessage::markdown(START_MSG),
-            Help => InputMessage::markdown(HELP_MSG),
-            Roll(r) => {
-                let result = format!("{} = {}", r.value(), r);
-                info!("roll: {}", result);
+            Start => START_REPLIES[locale as usize].get_or_init(|| InputMessage::markdown(locale.text(Text::Start))).clone(),
+            Help(topic) => help_reply(topic.as_deref(), locale),
+            Roll { .. } | Group(_) | Setting(_) | RerollOnes(_) | Last(_) | SlowRoll(_) => unreachable!("handled with chat state"),
+            Average(expr) => match rolls::average(&expr) {
+                Ok(avg) => InputMessage::markdown(format!("Average of `{}`: *{}*", expr.trim(), fmt_average(avg))),
+                Err(_) => unknown(),
+            },
+            Range(expr) => match rolls::range(&expr) {
+                Ok((min, max)) => InputMessage::markdown(format!("Range of `{}`: *{}..{}*", expr.trim(), min, max)),
+                Err(_) => unknown(),
+            },
+            Stats(expr) => match rolls::stats(&expr, &mut thread_rng()) {
+                Ok(stats) => InputMessage::markdown(stats_report(&expr, &stats)),
+                Err(_) => unknown(),
+            },
+            Choose { options, poll } => {
+                let result = choice_report(&options, poll, &mut thread_rng());
+                info!("choose: {}", result);
+                InputMessage::markdown(result)
+            }
+            Crit(expr) => {
+                let result = crit_reply(&expr);
+                info!("crit: {}", result);
+                InputMessage::markdown(result)
+            }
+            ConfirmCrit { expr, target, threat } => {
+                let result = match confirm_crit(&expr, target, threat, &mut thread_rng()) {
+                    Ok(check) if check.attack.value().is_err() => eval_error_msg(&check.attack).to_string(),
+                    Ok(CritCheck { confirm: Some(c), .. }) if c.value().is_err() => eval_error_msg(&c).to_string(),
+                    Ok(check) => crit_report(&check),
+                    Err(_) => locale.text(Text::Unknown).to_string(),
+                };
+                info!("confirm crit: {}", result);
                 InputMessage::markdown(result)
             }
-            Unknown => InputMessage::markdown(UNKNOWN_MSG),
+            Validate(lines) => InputMessage::markdown(validation_report(&lines)),
+            Error(err) => InputMessage::markdown(parse_error_msg(&err)),
+            Unknown => unknown(),
+        }
+    }
+}
+
+/// The roll command a reroll button sends back when pressed, or nothing if
+/// there's nothing to reroll or it doesn't fit in a button's data.
+fn reroll_data<'a>(lines: impl IntoIterator<Item=&'a RollLine>) -> Option<String> {
+    let lines: Vec<String> = lines.into_iter().map(|l| l.to_string()).collect();
+    if lines.is_empty() {
+        return None;
+    }
+    let data = format!("/roll {}", lines.join("\n"));
+    (data.len() <= MAX_CALLBACK_DATA).then_some(data)
+}
+
+/// Why a roll has no total, for replying in place of the roll.
+fn eval_error_msg(roll: &Roll) -> &'static str {
+    match roll.value() {
+        Err(EvalError::DivisionByZero) => DIVISION_BY_ZERO_MSG,
+        _ => OVERFLOW_MSG,
+    }
+}
+
+/// Formats a roll as its total followed by how it was rolled. Callers check for
+/// rolls without a total up front so they can reply with `eval_error_msg` instead.
+fn outcome(roll: &Roll) -> String {
+    match roll.value() {
+        Ok(v) => format!("{} = {}", v, roll),
+        Err(e) => format!("{} ({})", roll, e),
+    }
+}
+
+/// The reply to /help on `topic` in `locale`, reusing the parsed overview and
+/// topics.
+fn help_reply(topic: Option<&str>, locale: Locale) -> InputMessage {
+    let (overview, topics) = HELP_REPLIES[locale as usize].get_or_init(|| (
+        InputMessage::markdown(help_msg(None, locale)),
+        HELP_TOPICS.iter().map(|(name, _, _)| InputMessage::markdown(help_msg(Some(name), locale))).collect(),
+    ));
+    let Some(topic) = topic else {
+        return overview.clone();
+    };
+    match HELP_TOPICS.iter().position(|(name, _, _)| name.eq_ignore_ascii_case(topic)) {
+        Some(i) => topics[i].clone(),
+        // unknown topics are echoed back, so their replies differ every time
+        None => InputMessage::markdown(help_msg(Some(topic), locale)),
+    }
+}
+
+/// The help on `topic`, or the overview in `locale` and its list of topics
+/// when there's no topic or it isn't one of them.
+fn help_msg(topic: Option<&str>, locale: Locale) -> String {
+    let found = topic.and_then(|t| HELP_TOPICS.iter().find(|(name, _, _)| name.eq_ignore_ascii_case(t)));
+    if let Some((_, _, text)) = found {
+        return text.to_string();
+    }
+
+    let topics: Vec<_> = HELP_TOPICS.iter()
+        .map(|(name, summary, _)| format!("`/help {}` - {}", name, summary))
+        .collect();
+    let overview = format!("{}\n\n*Topics:*\\\n{}", locale.text(Text::Help), topics.join("\\\n"));
+    match topic {
+        Some(t) => format!("_There's no help on_ \"{}\"\n\n{}", escape_markdown(t), overview),
+        None => overview,
+    }
+}
+
+/// Points out where a roll stopped parsing, showing only the start of any long
+/// remainder. Dice terms too large to roll say which limit they went over.
+fn parse_error_msg(err: &ParseError) -> String {
+    let (max_dice, max_sides) = rolls::dice_limits();
+    match err.limit() {
+        Some(Limit::Dice) => return format!("Too many dice, the most I can roll in one term is {}", max_dice),
+        Some(Limit::Sides) => return format!("Too many sides, the most a die can have is {}", max_sides),
+        Some(Limit::Length) => return format!("That roll is too long, the most I can read is {} characters", rolls::max_length()),
+        None => {}
+    }
+    let near = err.remaining().trim();
+    if near.is_empty() {
+        return "That roll ends too early. Use /help to see the syntax".to_string();
+    }
+    let mut shown: String = near.chars().take(MAX_ERROR_CONTEXT).collect();
+    if shown.len() < near.len() {
+        shown.push('…');
+    }
+    format!("Couldn't parse that roll near \"{}\". Use /help to see the syntax", escape_markdown(&shown))
+}
+
+/// Lists the terms of a sum, each followed by its subtotal unless it's a
+/// number or a single die, then gives the total in bold on the next line.
+fn term_breakdown(roll: &Roll) -> String {
+    let terms = roll.terms();
+    let mut out = String::new();
+    for (i, (subtracted, term)) in terms.iter().enumerate() {
+        if i > 0 {
+            out.push_str(if *subtracted { " - " } else { " + " });
+        }
+        out.push_str(&term.to_string());
+        let plain = matches!(term, Roll::Num(_)) || matches!(term, Roll::Dice(dice, _) if dice.len() == 1);
+        if let (false, true, Ok(v)) = (plain, terms.len() > 1, term.value()) {
+            out.push_str(&format!(" _({})_", v));
+        }
+    }
+    match roll.value() {
+        Ok(v) => format!("{}\\\n= *{}*", out, v),
+        Err(e) => format!("{}\\\n_{}_", out, e),
+    }
+}
+
+/// Summarizes a roll by its dice instead of listing each of them, for results
+/// too long to fit in a reply.
+fn summary(roll: &Roll) -> String {
+    let faces = roll.evaluate_all();
+    match (roll.value(), faces.iter().min(), faces.iter().max()) {
+        (Ok(v), Some(lo), Some(hi)) => format!("{} = {} dice, lowest {}, highest {}", v, faces.len(), lo, hi),
+        (Ok(v), _, _) => v.to_string(),
+        (Err(_), _, _) => outcome(roll),
+    }
+}
+
+/// The reply to a message that would roll more than `MAX_MESSAGE_DICE` dice.
+fn too_many_dice_msg() -> String {
+    format!("Too many dice, the most I can roll in one message is {}", MAX_MESSAGE_DICE)
+}
+
+/// Rolls every line of a roll command for the chat and user in `origin`, if
+/// known, broken down by term if `breakdown` is set and replying in `locale`. Lines without a seed are
+/// given a random one, which the report echoes so any roll can be repeated.
+/// Alongside the reply is its report when anything was actually rolled,
+/// for keeping in the roller's history.
+fn roll_reply(lines: Vec<RollLine>, prefs: UserSettings, breakdown: bool, timestamp: Option<String>, origin: Option<(i64, i64)>, locale: Locale) -> (InputMessage, Option<String>) {
+    if lines.len() > MAX_ROLLS {
+        return (InputMessage::markdown(format!("Too many rolls, the most I can roll in one message is {}", MAX_ROLLS)), None);
+    }
+    if lines.iter().any(|l| !(1..=MAX_REPEAT).contains(&l.repeat)) {
+        return (InputMessage::markdown(format!("Too many repeats, the most I can roll an expression at once is {} times", MAX_REPEAT)), None);
+    }
+    // each term is limited on its own, but the whole message is too so its
+    // repeats and expressions can't multiply the work; previews build their
+    // dice just the same
+    let dice = lines.iter()
+        .filter(|l| l.error.is_none())
+        .map(|l| rolls::dice_count(&l.expr).unwrap_or(0).saturating_mul(l.repeat))
+        .fold(0, i64::saturating_add);
+    if dice > MAX_MESSAGE_DICE {
+        return (InputMessage::markdown(too_many_dice_msg()), None);
+    }
+
+    let expr = lines.iter().map(RollLine::to_string).collect::<Vec<_>>().join("\n");
+    // rerolling picks new seeds rather than repeating the ones picked below
+    let reroll = reroll_data(lines.iter().filter(|l| l.error.is_none() && !l.preview));
+    let rolled: Option<Vec<_>> = lines.into_iter()
+        .map(|mut line| {
+            if line.error.is_none() && !line.preview && line.seed.is_none() {
+                line.seed = Some(thread_rng().gen());
+            }
+            let rng = &mut StdRng::seed_from_u64(line.seed.unwrap_or_default());
+            let repeat = if line.error.is_some() { 0 } else { line.repeat };
+            let rolls: Option<Vec<_>> = (0..repeat)
+                .map(|_| match line.preview {
+                    true => preview_with(&line.expr, prefs).ok(),
+                    false => roll_with(&line.expr, prefs, rng).ok(),
+                })
+                .collect();
+            rolls.map(|r| (line, r))
+        })
+        .collect();
+    let (result, rolls) = match rolled {
+        Some(rolls) => match rolls.iter().flat_map(|(_, r)| r).find(|r| r.value().is_err()) {
+            Some(failed) => (eval_error_msg(failed).to_string(), None),
+            None => (roll_report(&rolls, prefs, breakdown, timestamp.as_deref()), Some(rolls)),
+        },
+        None => (locale.text(Text::Unknown).to_string(), None),
+    };
+    let (chat, user) = origin.unzip();
+    let seeds = rolls.iter().flatten()
+        .filter_map(|(line, _)| line.seed)
+        .map(|seed| seed.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    info!(chat_id = chat, user_id = user, expr = expr, seeds = seeds; "roll: {}", result);
+    // previews aren't rolls worth showing again
+    let report = rolls.as_ref()
+        .filter(|r| r.iter().any(|(line, _)| line.error.is_none() && !line.preview))
+        .map(|_| result.clone());
+    let reply = match reroll.filter(|_| rolls.is_some()) {
+        Some(data) => InputMessage::markdown(result)
+            .reply_markup(&reply_markup::inline(vec![vec![button::inline("🎲 Reroll", data)]])),
+        None => InputMessage::markdown(result),
+    };
+    (reply, report)
+}
+
+/// Reports each roll on its own line, prefixed by its label if it has one.
+/// Repeated rolls are numbered, and seeded rolls echo their seed. With
+/// `breakdown`, each roll shows its terms' subtotals and then its total. Reports
+/// longer than `MAX_REPLY_LEN` summarize each roll instead, and any rolls that
+/// still don't fit are left off.
+fn roll_report(rolls: &[(RollLine, Vec<Roll>)], prefs: UserSettings, breakdown: bool, timestamp: Option<&str>) -> String {
+    let mut footer = String::new();
+    if prefs.reroll_ones {
+        footer.push_str("\\\n_rerolling 1s once_");
+    }
+    if let Some(ts) = timestamp {
+        footer.push_str(&format!("\\\n_{}_", ts));
+    }
+
+    let fmt = if breakdown { term_breakdown } else { outcome };
+    let out = report_lines(rolls, fmt).join("\\\n") + &footer;
+    if out.chars().count() <= MAX_REPLY_LEN {
+        return out;
+    }
+
+    let lines = report_lines(rolls, summary);
+    let mut kept: Vec<&str> = Vec::new();
+    let mut len = footer.chars().count();
+    for (i, line) in lines.iter().enumerate() {
+        let more = format!("_…and {} more_", lines.len() - i);
+        if len + line.chars().count() + more.chars().count() + 4 > MAX_REPLY_LEN {
+            return format!("{}{}", [kept.as_slice(), &[more.as_str()]].concat().join("\\\n"), footer);
+        }
+        len += line.chars().count() + 2;
+        kept.push(line);
+    }
+    kept.join("\\\n") + &footer
+}
+
+fn report_lines(rolls: &[(RollLine, Vec<Roll>)], fmt: fn(&Roll) -> String) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (roll_line, repeats) in rolls {
+        let label = match &roll_line.label {
+            Some(label) => format!("_{}_: ", escape_markdown(label)),
+            None => String::new(),
+        };
+        if let Some(err) = &roll_line.error {
+            // unlike rolls that parsed, this may hold a backtick, so it's escaped rather than quoted
+            lines.push(format!("{}{} → {}", label, escape_markdown(roll_line.expr.trim()), parse_error_msg(err)));
+            continue;
+        }
+        for (i, roll) in repeats.iter().enumerate() {
+            let mut line = label.clone();
+            if repeats.len() > 1 {
+                line.push_str(&format!("{}. ", i + 1));
+            }
+            // repeats are numbered, so only the first needs the expression; it has
+            // already parsed, so it can't hold a backtick that would end the quote
+            if i == 0 && !roll_line.expr.is_empty() {
+                line.push_str(&format!("`{}` → ", roll_line.expr.trim()));
+            }
+            line.push_str(&fmt(roll));
+            if let Some(crit) = natural_crit(roll) {
+                line.push_str(&format!(" {}", crit));
+            }
+            if let Some(seed) = roll_line.seed {
+                line.push_str(&format!(" _(seed {})_", seed));
+            }
+            if roll_line.preview {
+                line.push_str(" _(preview)_");
+            }
+            lines.push(line);
+        }
+    }
+    lines
+}
+
+/// Formats an average to at most two decimal places, without trailing zeros.
+fn fmt_average(avg: f64) -> String {
+    let out = format!("{:.2}", avg);
+    out.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Charts a distribution as a histogram in a code block, grouping neighboring
+/// totals into ranges when there are too many to list.
+fn stats_report(expr: &str, stats: &rolls::Stats) -> String {
+    let mut out = format!("*Distribution of* {}", escape_markdown(expr.trim()));
+    match stats.samples {
+        Some(n) => out.push_str(&format!(" _(estimated from {} rolls)_", n)),
+        None => out.push_str(" _(exact)_"),
+    }
+
+    let (first, last) = match (stats.distribution.keys().next(), stats.distribution.keys().next_back()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => return out,
+    };
+    let width = ((last as i128 - first as i128) / MAX_STATS_ROWS as i128 + 1) as u64;
+    let mut rows: Vec<(String, f64)> = Vec::new();
+    let mut current = None;
+    for (total, chance) in &stats.distribution {
+        let bucket = (*total as i128 - first as i128) as u64 / width;
+        if current != Some(bucket) {
+            let lo = first as i128 + (bucket * width) as i128;
+            let hi = (lo + width as i128 - 1).min(last as i128);
+            rows.push((if lo == hi { lo.to_string() } else { format!("{}–{}", lo, hi) }, 0.0));
+            current = Some(bucket);
+        }
+        if let Some(row) = rows.last_mut() {
+            row.1 += chance;
         }
     }
+
+    let label_width = rows.iter().map(|(label, _)| label.chars().count()).max().unwrap_or(0);
+    let most = rows.iter().map(|(_, chance)| *chance).fold(0.0, f64::max);
+    out.push_str("\n```\n");
+    for (label, chance) in rows {
+        let bar = "█".repeat((chance / most * STATS_BAR_WIDTH as f64).round() as usize);
+        out.push_str(&format!("{:>w$} {:>5.1}% {}\n", label, chance * 100.0, bar, w = label_width));
+    }
+    out.push_str("```");
+    out
+}
+
+fn choice_report<R: Rng + ?Sized>(options: &[String], poll: bool, rng: &mut R) -> String {
+    if options.len() > MAX_OPTIONS {
+        return format!("Too many options, the most I can choose between is {}", MAX_OPTIONS);
+    }
+    let picked = match choose(options, rng) {
+        Some(i) => i,
+        None => return "Give me some options to choose from, separated by commas".to_string(),
+    };
+
+    match poll {
+        true => choice_list(options, picked),
+        false => choice_reveal(&options[picked]),
+    }
+}
+
+/// Lists the numbered options before revealing the one `picked`, for when
+/// they can't be posted as a poll.
+fn choice_list(options: &[String], picked: usize) -> String {
+    let mut out = "*Let the dice decide:*".to_string();
+    for (i, option) in options.iter().enumerate() {
+        out.push_str(&format!("\\\n{}. {}", i + 1, escape_markdown(option)));
+    }
+    format!("{}\\\n\\\n{}", out, choice_reveal(&options[picked]))
+}
+
+fn choice_reveal(option: &str) -> String {
+    format!("The dice choose: *{}*", escape_markdown(option))
+}
+
+/// Whether Telegram accepts `options` as the answers of a poll.
+fn fits_poll(options: &[String]) -> bool {
+    (2..=MAX_POLL_OPTIONS).contains(&options.len())
+        && options.iter().all(|o| o.chars().count() <= MAX_POLL_OPTION_LEN)
+}
+
+/// A quiz of `options` with the one `picked` by the dice as its correct
+/// answer, so voting reveals what the dice decided.
+fn choice_poll(options: &[String], picked: usize, id: i64) -> tl::types::InputMediaPoll {
+    let text = |text: &str| tl::types::TextWithEntities { text: text.to_string(), entities: Vec::new() }.into();
+    tl::types::InputMediaPoll {
+        poll: tl::types::Poll {
+            id,
+            closed: false,
+            public_voters: false,
+            multiple_choice: false,
+            quiz: true,
+            question: text("Let the dice decide"),
+            answers: options.iter()
+                .enumerate()
+                .map(|(i, option)| tl::types::PollAnswer { text: text(option), option: vec![i as u8] }.into())
+                .collect(),
+            close_period: None,
+            close_date: None,
+        }.into(),
+        correct_answers: Some(vec![vec![picked as u8]]),
+        solution: None,
+        solution_entities: None,
+    }
+}
+
+/// Posts `options` to the message's chat as a quiz with the dice's pick as the
+/// correct answer.
+async fn send_poll(client: &Client, msg: &Message, options: &[String], picked: usize) -> std::result::Result<(), InvocationError> {
+    let request = tl::functions::messages::SendMedia {
+        silent: false,
+        background: false,
+        clear_draft: false,
+        noforwards: false,
+        update_stickersets_order: false,
+        invert_media: false,
+        allow_paid_floodskip: false,
+        peer: msg.chat().pack().to_input_peer(),
+        reply_to: None,
+        media: choice_poll(options, picked, thread_rng().gen()).into(),
+        message: String::new(),
+        random_id: thread_rng().gen(),
+        reply_markup: None,
+        entities: None,
+        schedule_date: None,
+        send_as: None,
+        quick_reply_shortcut: None,
+        effect: None,
+        allow_paid_stars: None,
+    };
+    retry_flood(|| client.invoke(&request)).await?;
+    Ok(())
+}
+
+/// Rolls `expr` as a critical hit. Doubling its dice counts against the same
+/// budget as any other message, so that's checked before anything's rolled.
+fn crit_reply(expr: &str) -> String {
+    if rolls::dice_count(expr).unwrap_or(0).saturating_mul(2) > MAX_MESSAGE_DICE {
+        return too_many_dice_msg();
+    }
+    match rolls::parse_critical(expr) {
+        Ok(roll) if roll.value().is_err() => eval_error_msg(&roll).to_string(),
+        Ok(roll) => format!("*Critical!* {}", outcome(&roll)),
+        Err(err) => parse_error_msg(&err),
+    }
+}
+
+fn crit_report(check: &CritCheck) -> String {
+    let attack = &check.attack;
+    let mut out = format!("Attack: {} vs {}", outcome(attack), check.target);
+    if !check.threatened() {
+        out.push_str(if check.hit() { " — hit" } else { " — miss" });
+        return out;
+    }
+
+    if let Some(natural) = check.natural {
+        out.push_str(&format!(" — natural {}, critical threat!", natural));
+    }
+    if let Some(confirm) = &check.confirm {
+        out.push_str(&format!("\\\nConfirm: {}", outcome(confirm)));
+    }
+    out.push_str(match (check.confirmed(), check.hit()) {
+        (true, _) => " — *critical confirmed!*",
+        (false, true) => " — not confirmed, normal hit",
+        (false, false) => " — not confirmed",
+    });
+    out
+}
+
+fn validation_report(lines: &[String]) -> String {
+    if lines.is_empty() {
+        return "Nothing to validate. Put one expression per line after /validate".to_string();
+    }
+
+    let report: Vec<String> = lines.iter()
+        .map(|line| match rolls::validate(line) {
+            Ok(_) => format!("✅ `{}`", line),
+            Err(e) => format!("❌ {} (error at position {})", escape_markdown(line), e.offset() + 1),
+        })
+        .collect();
+    report.join("\\\n")
+}
+
+/// Removes the `@botname` Telegram appends to commands sent in group chats,
+/// like `/roll@DiceGoblinBot 2d6`. Any name is accepted, since the bot's own
+/// isn't known while parsing.
+fn strip_mention(input: &str) -> Cow<'_, str> {
+    if !input.starts_with('/') {
+        return Cow::Borrowed(input);
+    }
+    let end = input.find(char::is_whitespace).unwrap_or(input.len());
+    match input[..end].find('@') {
+        Some(at) => Cow::Owned(format!("{}{}", &input[..at], &input[end..])),
+        None => Cow::Borrowed(input),
+    }
 }
 
 fn parse_command(input: &str) -> IResult<&str, Command> {
@@ -111,6 +1231,21 @@ fn parse_command(input: &str) -> IResult<&str, Command> {
         alt((
             parse_start,
             parse_help,
+            parse_group_roll,
+            parse_join,
+            parse_slow_roll,
+            parse_average,
+            parse_range,
+            parse_stats,
+            parse_choose,
+            parse_confirm_crit,
+            parse_crit,
+            parse_validate,
+            parse_timestamps,
+            parse_breakdown,
+            parse_timezone,
+            parse_reroll_ones,
+            parse_last,
             parse_roll,
         )),
     )(input)
@@ -118,7 +1253,7 @@ fn parse_command(input: &str) -> IResult<&str, Command> {
 
 fn parse_start(input: &str) -> IResult<&str, Command> {
     let (input, _) = tuple((
-        tag_no_case("start"),
+        keyword(&START),
         alt((multispace1, eof)),
         rest,
     ))(input)?;
@@ -126,19 +1261,787 @@ fn parse_start(input: &str) -> IResult<&str, Command> {
 }
 
 fn parse_help(input: &str) -> IResult<&str, Command> {
+    let (input, (_, _, topic)) = tuple((
+        keyword(&HELP),
+        alt((multispace1, eof)),
+        rest,
+    ))(input)?;
+    let topic = Some(topic.trim()).filter(|t| !t.is_empty()).map(str::to_string);
+    Ok((input, Command::Help(topic)))
+}
+
+fn parse_group_roll(input: &str) -> IResult<&str, Command> {
+    let (input, _) = pair(keyword(&GROUP_ROLL), multispace1)(input)?;
+    alt((
+        map(
+            preceded(pair(tag_no_case("start"), multispace1), rest),
+            |expr: &str| Command::Group(GroupAction::Start(expr.to_string())),
+        ),
+        map(
+            tuple((tag_no_case("end"), multispace0, eof)),
+            |_| Command::Group(GroupAction::End),
+        ),
+    ))(input)
+}
+
+fn parse_join(input: &str) -> IResult<&str, Command> {
     let (input, _) = tuple((
-        tag_no_case("help"),
+        keyword(&JOIN),
         alt((multispace1, eof)),
         rest,
     ))(input)?;
-    Ok((input, Command::Help))
+    Ok((input, Command::Group(GroupAction::Join)))
+}
+
+/// Fails a command's parser where the roll in `input` stopped parsing.
+fn roll_failure<'a>(input: &'a str) -> impl FnOnce(ParseError) -> nom::Err<nom::error::Error<&'a str>> {
+    move |e| nom::Err::Error(nom::error::Error::new(&input[e.offset()..], nom::error::ErrorKind::Verify))
+}
+
+fn parse_slow_roll(input: &str) -> IResult<&str, Command> {
+    let (input, _) = pair(keyword(&SLOW_ROLL), multispace1)(input)?;
+    rolls::validate(input).map_err(roll_failure(input))?;
+    Ok(("", Command::SlowRoll(input.to_string())))
+}
+
+fn parse_average(input: &str) -> IResult<&str, Command> {
+    let (input, _) = pair(keyword(&AVERAGE), multispace1)(input)?;
+    rolls::validate(input).map_err(roll_failure(input))?;
+    Ok(("", Command::Average(input.to_string())))
+}
+
+fn parse_range(input: &str) -> IResult<&str, Command> {
+    let (input, _) = pair(keyword(&RANGE), multispace1)(input)?;
+    rolls::validate(input).map_err(roll_failure(input))?;
+    Ok(("", Command::Range(input.to_string())))
+}
+
+fn parse_stats(input: &str) -> IResult<&str, Command> {
+    let (input, _) = pair(keyword(&STATS), multispace1)(input)?;
+    rolls::validate(input).map_err(roll_failure(input))?;
+    Ok(("", Command::Stats(input.to_string())))
+}
+
+fn parse_choose(input: &str) -> IResult<&str, Command> {
+    let (input, _) = pair(keyword(&CHOOSE), alt((multispace1, eof)))(input)?;
+    let (input, poll) = opt(pair(tag_no_case("poll"), alt((multispace1, eof))))(input)?;
+    Ok(("", Command::Choose {
+        options: parse_options(input),
+        poll: poll.is_some(),
+    }))
+}
+
+fn parse_crit(input: &str) -> IResult<&str, Command> {
+    let (input, _) = pair(keyword(&CRIT), multispace1)(input)?;
+    rolls::validate(input).map_err(roll_failure(input))?;
+    Ok(("", Command::Crit(input.to_string())))
+}
+
+fn parse_confirm_crit(input: &str) -> IResult<&str, Command> {
+    map(
+        tuple((
+            preceded(pair(keyword(&CONFIRM_CRIT), multispace1), take_until(" vs ")),
+            preceded(pair(tag(" vs"), multispace1), int),
+            opt(preceded(tuple((multispace1, tag_no_case("crit"), multispace1)), int)),
+            multispace0,
+            eof,
+        )),
+        |(expr, target, threat, _, _)| Command::ConfirmCrit {
+            expr: expr.to_string(),
+            target,
+            threat: threat.unwrap_or(DEFAULT_THREAT),
+        },
+    )(input)
+}
+
+fn int(input: &str) -> IResult<&str, i64> {
+    map_res(recognize(pair(opt(char('-')), digit1)), str::parse)(input)
+}
+
+fn parse_validate(input: &str) -> IResult<&str, Command> {
+    let (input, _) = pair(keyword(&VALIDATE), alt((multispace1, eof)))(input)?;
+    let lines = input.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect();
+    Ok(("", Command::Validate(lines)))
+}
+
+fn parse_timestamps(input: &str) -> IResult<&str, Command> {
+    map(
+        delimited(
+            pair(keyword(&TIMESTAMPS), multispace1),
+            alt((
+                value(true, tag_no_case("on")),
+                value(false, tag_no_case("off")),
+            )),
+            pair(multispace0, eof),
+        ),
+        |on| Command::Setting(SettingChange::Timestamps(on)),
+    )(input)
+}
+
+fn parse_breakdown(input: &str) -> IResult<&str, Command> {
+    map(
+        delimited(
+            pair(keyword(&BREAKDOWN), multispace1),
+            alt((
+                value(true, tag_no_case("on")),
+                value(false, tag_no_case("off")),
+            )),
+            pair(multispace0, eof),
+        ),
+        |on| Command::Setting(SettingChange::Breakdown(on)),
+    )(input)
+}
+
+fn parse_timezone(input: &str) -> IResult<&str, Command> {
+    map(
+        preceded(
+            pair(keyword(&TIMEZONE), multispace1),
+            map_opt(rest, parse_utc_offset),
+        ),
+        |offset| Command::Setting(SettingChange::Timezone(offset)),
+    )(input)
+}
+
+fn parse_reroll_ones(input: &str) -> IResult<&str, Command> {
+    map(
+        delimited(
+            keyword(&SET_REROLL_ONES),
+            opt(preceded(multispace1, alt((
+                value(true, tag_no_case("on")),
+                value(false, tag_no_case("off")),
+            )))),
+            pair(multispace0, eof),
+        ),
+        Command::RerollOnes,
+    )(input)
+}
+
+fn parse_last(input: &str) -> IResult<&str, Command> {
+    map(
+        delimited(
+            keyword(&LAST),
+            opt(preceded(multispace1, map_opt(digit1, |n: &str| n.parse().ok().filter(|n| (1..=HISTORY_DEPTH).contains(n))))),
+            pair(multispace0, eof),
+        ),
+        |back| Command::Last(back.unwrap_or(1)),
+    )(input)
 }
 
 fn parse_roll(input: &str) -> IResult<&str, Command> {
-    let (input, _) = opt(alt((
-        tag_no_case("roll"),
-        tag_no_case("r"),
-    )))(input)?;
-    let roll = rolls::parse(input)?;
-    Ok(("", Command::Roll(roll)))
-}
\ No newline at end of file
+    // the name may be glued to the expression, as in `/r3d6+2`; a word like
+    // `/rest` then fails on its first letter and isn't taken for a roll
+    let (input, named) = opt(keyword(&ROLL))(input)?;
+    // text is only reported as a broken roll if it names the command or starts like one
+    let named = named.is_some() && input.starts_with(char::is_whitespace);
+    let mut lines = Vec::new();
+    for segment in rolls::split_rolls(input) {
+        let (expr, label) = rolls::split_label(segment);
+        let (preview, expr) = rolls::split_preview(expr);
+        let (seed, expr) = rolls::split_seed(expr);
+        let (repeat, expr) = rolls::split_repeat(expr);
+        let error = match rolls::validate(expr) {
+            Ok(()) => None,
+            Err(e) if named || e.offset() > 0 => Some(e),
+            Err(e) => return Err(roll_failure(expr)(e)),
+        };
+        lines.push(RollLine { expr: expr.to_string(), label: label.map(String::from), repeat, seed, preview, error });
+    }
+    if lines.is_empty() {
+        return Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Eof)));
+    }
+    // without a single roll that parsed, the first error is all there is to report
+    if lines.iter().all(|l| l.error.is_some()) {
+        if let Some(err) = lines.swap_remove(0).error {
+            return Ok(("", Command::Error(err)));
+        }
+    }
+    Ok(("", Command::Roll { lines }))
+}
+
+#[cfg(test)]
+mod tests {
+    use grammers_client::RpcError;
+
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn parse_err(expr: &str) -> ParseError {
+        rolls::validate(expr).unwrap_err()
+    }
+
+    #[test]
+    fn test_parse_group_roll() {
+        assert!(matches!(
+            Command::from("/grouproll start 2d6"),
+            Command::Group(GroupAction::Start(e)) if e == "2d6"));
+        assert!(matches!(Command::from("/grouproll end"), Command::Group(GroupAction::End)));
+        assert!(matches!(Command::from("/join"), Command::Group(GroupAction::Join)));
+    }
+
+    #[test]
+    fn test_parse_settings() {
+        assert!(matches!(
+            Command::from("/timestamps on"),
+            Command::Setting(SettingChange::Timestamps(true))));
+        assert!(matches!(
+            Command::from("/timestamps OFF"),
+            Command::Setting(SettingChange::Timestamps(false))));
+        assert!(matches!(
+            Command::from("/breakdown on"),
+            Command::Setting(SettingChange::Breakdown(true))));
+        assert!(matches!(Command::from("/breakdown maybe"), Command::Unknown));
+        assert!(matches!(
+            Command::from("/timezone -3"),
+            Command::Setting(SettingChange::Timezone(-10800))));
+        assert!(matches!(Command::from("/timezone mars"), Command::Unknown));
+    }
+
+    #[test]
+    fn test_validate() {
+        let lines = match Command::from("/validate 2d6 + 3\n3dd6\n\n  4d6kh3  ") {
+            Command::Validate(lines) => lines,
+            cmd => panic!("unexpected command: {:?}", cmd),
+        };
+        assert_eq!(vec!["2d6 + 3", "3dd6", "4d6kh3"], lines);
+
+        assert_eq!(
+            "✅ `2d6 + 3`\\\n❌ 3dd6 (error at position 2)\\\n✅ `4d6kh3`",
+            validation_report(&lines));
+    }
+
+    #[test]
+    fn test_parse_confirm_crit() {
+        assert!(matches!(
+            Command::from("/confirmcrit d20+5 vs 15"),
+            Command::ConfirmCrit { expr, target: 15, threat: DEFAULT_THREAT } if expr == "d20+5"));
+        assert!(matches!(
+            Command::from("/confirmcrit d20 + 2 vs 12 crit 19"),
+            Command::ConfirmCrit { expr, target: 12, threat: 19 } if expr == "d20 + 2"));
+        assert!(matches!(Command::from("/confirmcrit d20"), Command::Unknown));
+    }
+
+    #[test]
+    fn test_choose() {
+        let (options, poll) = match Command::from("/choose poll fight, flee, *parley*") {
+            Command::Choose { options, poll } => (options, poll),
+            cmd => panic!("unexpected command: {:?}", cmd),
+        };
+        assert!(poll);
+        assert_eq!(vec!["fight", "flee", "*parley*"], options);
+
+        let report = choice_report(&options, poll, &mut StdRng::seed_from_u64(1));
+        assert!(report.contains("3. \\*parley\\*"), "{}", report);
+        assert!(report.contains("The dice choose: *"), "{}", report);
+
+        assert!(matches!(Command::from("/choose a, b"), Command::Choose { poll: false, .. }));
+    }
+
+    #[test]
+    fn test_choice_poll() {
+        let options: Vec<String> = ["fight", "flee", "parley"].map(String::from).to_vec();
+        assert!(fits_poll(&options));
+        assert!(!fits_poll(&options[..1]));
+        assert!(!fits_poll(&vec!["a".to_string(); MAX_POLL_OPTIONS + 1]));
+        assert!(!fits_poll(&["a".to_string(), "b".repeat(MAX_POLL_OPTION_LEN + 1)]));
+
+        // the dice's pick is the quiz's correct answer
+        assert_eq!(Some(vec![vec![2]]), choice_poll(&options, 2, 7).correct_answers);
+        assert_eq!(Some(vec![vec![0]]), choice_poll(&options, 0, 7).correct_answers);
+
+        assert_eq!("*Let the dice decide:*\\\n1. fight\\\n2. flee\\\n3. parley\\\n\\\nThe dice choose: *flee*", choice_list(&options, 1));
+    }
+
+    #[test]
+    fn test_slow_roll_frames() {
+        assert!(matches!(Command::from("/slowroll 3d6 + 1"), Command::SlowRoll(e) if e == "3d6 + 1"));
+
+        let roll = rolls::parse("3d6 + 1").unwrap();
+        let faces = roll.evaluate_all();
+
+        assert_eq!("Rolling… ?, ?, ?", slow_roll_frame(&roll, &faces, 0));
+        assert_eq!(format!("Rolling… {}, {}, ?", faces[0], faces[1]), slow_roll_frame(&roll, &faces, 2));
+        assert_eq!(format!("{} = {}", roll.value().unwrap(), roll), slow_roll_frame(&roll, &faces, 3));
+    }
+
+    #[test]
+    fn test_slow_roll() {
+        let prefs = UserSettings::default();
+        assert!(matches!(slow_roll("3d6 + 1", prefs, Locale::default()), Ok(Reply::Animated { frames: 4, .. })));
+
+        // every frame of the most dice revealed fits in a reply
+        let most = ["20d1000000"; MAX_SLOW_ROLL_DICE / 20].join(" + ");
+        let Ok(Reply::Animated { frames, frame }) = slow_roll(&most, prefs, Locale::default()) else {
+            panic!("expected an animated slow roll");
+        };
+        assert_eq!(MAX_SLOW_ROLL_DICE + 1, frames);
+        assert!((0..frames).all(|i| frame(i).chars().count() <= MAX_REPLY_LEN));
+
+        // more dice than that are sent all at once
+        let many = format!("{} + d6", most);
+        assert!(matches!(slow_roll(&many, prefs, Locale::default()), Ok(Reply::Message(_))));
+        assert!(matches!(slow_roll("1000000d6", prefs, Locale::default()), Ok(Reply::Message(_))));
+
+        assert_eq!(Err(OVERFLOW_MSG.to_string()), slow_roll("9223372036854775807 + d1", prefs, Locale::default()).map(|_| ()));
+    }
+
+    #[test]
+    fn test_parse_roll() {
+        assert!(matches!(Command::from("/roll 2d6"), Command::Roll { .. }));
+        assert!(matches!(Command::from("/r 2d6"), Command::Roll { .. }));
+        assert!(matches!(Command::from("/2d6"), Command::Roll { .. }));
+        assert!(matches!(Command::from("/roll"), Command::Unknown));
+        assert!(matches!(Command::from("/roll 2d6\n3dd6"), Command::Roll { lines, .. } if lines[1].error.as_ref().map(ParseError::remaining) == Some("dd6")));
+        assert!(matches!(Command::from("/roll 3dd6\n2d6)"), Command::Error(e) if e.remaining() == "dd6"));
+    }
+
+    #[test]
+    fn test_parse_roll_glued() {
+        let expr = |input| match Command::from(input) {
+            Command::Roll { lines, .. } => Some(lines[0].expr.clone()),
+            _ => None,
+        };
+        assert_eq!(Some("3d6+2".to_string()), expr("/r3d6+2"));
+        assert_eq!(Some("3d6+2".to_string()), expr("/3d6+2"));
+        assert_eq!(Some("3d6".to_string()), expr("/roll3d6"));
+        assert_eq!(Some("d20-1".to_string()), expr("/Rd20-1@dice_goblin_bot"));
+        assert_eq!(Some("2".to_string()), expr("/roll2"));
+        assert!(matches!(Command::from("/r3dd6"), Command::Error(e) if e.remaining() == "dd6"));
+        // words that merely start with an alias aren't rolls
+        for input in ["/rest", "/rolling", "/reroll", "/rng"] {
+            assert!(matches!(Command::from(input), Command::Unknown), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_roll_preview() {
+        let lines = match Command::from("/roll preview: 2d6 + 1; d4") {
+            Command::Roll { lines, .. } => lines,
+            cmd => panic!("unexpected command: {:?}", cmd),
+        };
+        assert!(lines[0].preview && !lines[1].preview);
+        assert_eq!("preview: 2d6 + 1", lines[0].to_string());
+
+        let roll = preview_with(&lines[0].expr, UserSettings::default()).unwrap();
+        let preview = RollLine { repeat: 1, ..lines.into_iter().next().unwrap() };
+        assert_eq!("`2d6 + 1` → 7 = [3, 3] + 1 _(preview)_", roll_report(&[(preview, vec![roll])], UserSettings::default(), false, None));
+
+        // rerolling a preview would only show the same result again, so a
+        // command of nothing but previews gets no button
+        assert_eq!(None, reroll_data(std::iter::empty()));
+    }
+
+    #[test]
+    fn test_flood_wait() {
+        let rpc = |name: &str, value| InvocationError::Rpc(RpcError { code: 420, name: name.to_string(), value, caused_by: None });
+        assert_eq!(Some(Duration::from_secs(12)), flood_wait(&rpc("FLOOD_WAIT", Some(12))));
+        assert_eq!(None, flood_wait(&rpc("FLOOD_WAIT", Some(3600))));
+        assert_eq!(None, flood_wait(&rpc("MESSAGE_TOO_LONG", None)));
+        assert_eq!(None, flood_wait(&InvocationError::Dropped));
+    }
+
+    #[test]
+    fn test_reroll_data() {
+        let input = "/roll seed:7 6x 4d6kh3 # stats\n2d6";
+        let lines = match Command::from(input) {
+            Command::Roll { lines, .. } => lines,
+            cmd => panic!("unexpected command: {:?}", cmd),
+        };
+        let data = reroll_data(&lines).unwrap();
+        assert_eq!(input, data);
+        assert!(matches!(Command::from(data.as_str()), Command::Roll { lines: reparsed, .. } if reparsed == lines));
+
+        let long = RollLine { expr: "d6 + ".repeat(20) + "d6", repeat: 1, ..Default::default() };
+        assert_eq!(None, reroll_data([&long]));
+    }
+
+    #[test]
+    fn test_inline_report() {
+        assert_eq!(Some("`3d1 + 2` → 5 = [1, 1, 1] + 2".to_string()), inline_report(" 3d1 + 2 "));
+        assert_eq!(None, inline_report(""));
+        assert_eq!(None, inline_report("3dd6"));
+        assert_eq!(None, inline_report("9999999999 * 9999999999"));
+    }
+
+    #[test]
+    fn test_natural_crit() {
+        let (mut crits, mut fails) = (0, 0);
+        for seed in 0..200 {
+            let roll = rolls::parse_with_rng("d20", &mut StdRng::seed_from_u64(seed)).unwrap();
+            let value = roll.value().unwrap();
+            let report = roll_report(&[(RollLine { expr: "d20".to_string(), ..Default::default() }, vec![roll])], UserSettings::default(), false, None);
+            match value {
+                20 => {
+                    crits += 1;
+                    assert!(report.ends_with(" *Critical!*"), "{}", report);
+                }
+                1 => {
+                    fails += 1;
+                    assert!(report.ends_with(" *Critical fail!*"), "{}", report);
+                }
+                _ => assert!(!report.contains("Critical"), "{}", report),
+            }
+        }
+        assert!(crits > 0 && fails > 0);
+
+        // only a lone d20 counts
+        let roll = rolls::parse_with_rng("d20 + 0", &mut StdRng::seed_from_u64(0)).unwrap();
+        assert_eq!(None, natural_crit(&roll));
+    }
+
+    #[test]
+    fn test_help_msg() {
+        assert!(help_msg(None, Locale::English).starts_with(Locale::English.text(Text::Help)));
+        assert!(help_msg(None, Locale::English).ends_with("`/help math` - Arithmetic, rounding, and picking the higher or lower roll"));
+        assert_eq!(HELP_DICE, help_msg(Some("dice"), Locale::English));
+        assert_eq!(HELP_POOLS, help_msg(Some("Pools"), Locale::English));
+        assert_eq!(HELP_MATH, help_msg(Some("MATH"), Locale::English));
+
+        let unknown = help_msg(Some("*magic*"), Locale::English);
+        assert!(unknown.starts_with("_There's no help on_ \"\\*magic\\*\"\n\n"), "{}", unknown);
+        assert!(unknown.ends_with(&help_msg(None, Locale::English)));
+
+        for (_, _, text) in HELP_TOPICS {
+            assert!(text.chars().count() <= MAX_REPLY_LEN);
+        }
+        assert!(help_msg(None, Locale::English).chars().count() <= MAX_REPLY_LEN);
+    }
+
+    #[test]
+    fn test_strip_mention() {
+        assert_eq!("/roll 2d6", strip_mention("/roll@DiceGoblinBot 2d6"));
+        assert_eq!("/help", strip_mention("/help@DiceGoblinBot"));
+        assert_eq!("/roll 2d6 @someone", strip_mention("/roll 2d6 @someone"));
+        assert_eq!("roll@x 2d6", strip_mention("roll@x 2d6"));
+        assert!(matches!(Command::from("/r@SomeOtherBot 2d6"), Command::Roll { .. }));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(matches!(Command::from("/3dd6"), Command::Error(e) if e.remaining() == "dd6"));
+        assert!(matches!(Command::from("/roll fireball"), Command::Error(e) if e.remaining() == "fireball"));
+        assert!(matches!(Command::from("/random"), Command::Unknown));
+        assert!(matches!(Command::from("/start"), Command::Start));
+        assert!(matches!(Command::from("/help"), Command::Help(None)));
+        assert!(matches!(Command::from("/help  Dice "), Command::Help(Some(t)) if t == "Dice"));
+
+        assert_eq!("Couldn't parse that roll near \"dd6\". Use /help to see the syntax", parse_error_msg(&parse_err("3dd6")));
+        assert_eq!("Couldn't parse that roll near \"\\_\\_\\_\\_\\_\\_\\_\\_\\_\\_\\_\\_\\_\\_\\_\\_\\_\\_\\_\\_…\". Use /help to see the syntax", parse_error_msg(&parse_err(&"_".repeat(30))));
+        assert_eq!("That roll ends too early. Use /help to see the syntax", parse_error_msg(&parse_err(" ")));
+
+        assert!(matches!(Command::from("/roll 2d6 + 2000000d6"), Command::Error(e) if e.remaining() == "2000000d6"));
+        assert_eq!("Too many dice, the most I can roll in one term is 1000000", parse_error_msg(&parse_err("2d6 + 2000000d6")));
+        assert_eq!("Too many sides, the most a die can have is 1000000", parse_error_msg(&parse_err("d99999999 + 1")));
+        assert_eq!("That roll is too long, the most I can read is 1000 characters", parse_error_msg(&parse_err(&"1+".repeat(600))));
+    }
+
+    #[test]
+    fn test_roll_repeat() {
+        assert!(matches!(
+            Command::from("/roll 6x 4d6kh3 # stats"),
+            Command::Roll { lines, .. } if lines == vec![RollLine {
+                expr: "4d6kh3".to_string(),
+                label: Some("stats".to_string()),
+                repeat: 6,
+                seed: None,
+                preview: false,
+                error: None,
+            }]));
+
+        let rolls = [(RollLine::default(), vec![rolls::parse("3").unwrap(), rolls::parse("4").unwrap()])];
+        assert_eq!("1. 3 = 3\\\n2. 4 = 4", roll_report(&rolls, UserSettings::default(), false, None));
+    }
+
+    #[test]
+    fn test_roll_many() {
+        let lines = match Command::from("/roll 2d6; d20 # init;3d4+1\n4d6kh3") {
+            Command::Roll { lines, .. } => lines,
+            cmd => panic!("unexpected command: {:?}", cmd),
+        };
+        assert_eq!(
+            vec![("2d6", None), ("d20", Some("init")), ("3d4+1", None), ("4d6kh3", None)],
+            lines.iter().map(|l| (l.expr.as_str(), l.label.as_deref())).collect::<Vec<_>>());
+
+        // malformed rolls are reported alongside the others, unless nothing parsed at all
+        let lines = match Command::from("/roll 1d1; 3dd6 # broken; 2") {
+            Command::Roll { lines, .. } => lines,
+            cmd => panic!("unexpected command: {:?}", cmd),
+        };
+        assert_eq!(vec![None, Some("dd6"), None], lines.iter().map(|l| l.error.as_ref().map(ParseError::remaining)).collect::<Vec<_>>());
+        assert!(matches!(Command::from("/hello; 2d6"), Command::Unknown));
+
+        let rolls = [
+            (RollLine { expr: "1d1".to_string(), repeat: 1, ..Default::default() }, vec![rolls::parse("1d1").unwrap()]),
+            (RollLine { expr: "3d`d6".to_string(), label: Some("broken".to_string()), error: Some(parse_err("3d`d6")), ..Default::default() }, vec![]),
+        ];
+        assert_eq!(
+            "`1d1` → 1 = [1]\\\n_broken_: 3d\\`d6 → Couldn't parse that roll near \"d\\`d6\". Use /help to see the syntax",
+            roll_report(&rolls, UserSettings::default(), false, None));
+    }
+
+    #[test]
+    fn test_roll_labels() {
+        let lines = match Command::from("/roll 2d6 # *fire* damage\n1d4 \"cold\"\n\nd20") {
+            Command::Roll { lines, .. } => lines,
+            cmd => panic!("unexpected command: {:?}", cmd),
+        };
+        assert_eq!(vec![
+            RollLine { expr: "2d6".to_string(), label: Some("*fire* damage".to_string()), repeat: 1, seed: None, preview: false, error: None },
+            RollLine { expr: "1d4".to_string(), label: Some("cold".to_string()), repeat: 1, seed: None, preview: false, error: None },
+            RollLine { expr: "d20".to_string(), label: None, repeat: 1, seed: None, preview: false, error: None },
+        ], lines);
+
+        let rolls = [
+            (RollLine { label: Some("_fire_".to_string()), ..Default::default() }, vec![rolls::parse("1d1").unwrap()]),
+            (RollLine::default(), vec![rolls::parse("2").unwrap()]),
+        ];
+        assert_eq!(
+            "_\\_fire\\__: 1 = [1]\\\n2 = 2",
+            roll_report(&rolls, UserSettings::default(), false, None));
+
+        let rolls = [
+            (RollLine { expr: " 3d1 * 2 ".to_string(), label: Some("hit".to_string()), ..Default::default() }, vec![rolls::parse("3d1 * 2").unwrap()]),
+            (RollLine { expr: "2".to_string(), repeat: 2, ..Default::default() }, vec![rolls::parse("2").unwrap(), rolls::parse("2").unwrap()]),
+        ];
+        assert_eq!(
+            "_hit_: `3d1 * 2` → 6 = [1, 1, 1] \\* 2\\\n1. `2` → 2 = 2\\\n2. 2 = 2",
+            roll_report(&rolls, UserSettings::default(), false, None));
+    }
+
+    #[test]
+    fn test_roll_report_too_long() {
+        let line = RollLine { expr: "20d1".to_string(), repeat: 100, ..Default::default() };
+        let rolls = [(line, (0..100).map(|_| rolls::parse("20d1").unwrap()).collect())];
+        let report = roll_report(&rolls, UserSettings::default(), false, Some("12:00"));
+        assert!(report.chars().count() <= MAX_REPLY_LEN);
+        assert!(report.starts_with("1. `20d1` → 20 = 20 dice, lowest 1, highest 1\\\n2. 20 = 20 dice"), "{}", report);
+        assert!(report.ends_with("100. 20 = 20 dice, lowest 1, highest 1\\\n_12:00_"), "{}", report);
+
+        let line = RollLine { label: Some("x".repeat(100)), expr: "20d1".to_string(), repeat: 100, ..Default::default() };
+        let rolls = [(line, (0..100).map(|_| rolls::parse("20d1").unwrap()).collect())];
+        let report = roll_report(&rolls, UserSettings::default(), false, Some("12:00"));
+        assert!(report.chars().count() <= MAX_REPLY_LEN);
+        assert!(report.ends_with(" more_\\\n_12:00_"), "{}", report);
+    }
+
+    #[test]
+    fn test_threaded() {
+        assert!(threaded(false, true));
+        assert!(!threaded(true, true));
+        // channel posts have no sender
+        assert!(!threaded(false, false));
+    }
+
+    #[test]
+    fn test_escape_markdown() {
+        assert_eq!("plain text", escape_markdown("plain text"));
+        assert_eq!("\\*bold\\* \\_it\\_ \\`code\\` \\[link\\] \\~\\~gone\\~\\~ \\\\", escape_markdown("*bold* _it_ `code` [link] ~~gone~~ \\"));
+
+        let rolls = [(RollLine::default(), vec![rolls::parse("d1 * 3").unwrap()])];
+        assert_eq!("3 = [1] \\* 3", roll_report(&rolls, UserSettings::default(), false, None));
+    }
+
+    #[test]
+    fn test_roll_breakdown() {
+        let expr = "2d1 + d1 - 3 + (d1 * 2)";
+        let rolls = [(RollLine { expr: expr.to_string(), ..Default::default() }, vec![rolls::parse(expr).unwrap()])];
+        assert_eq!(
+            "`2d1 + d1 - 3 + (d1 * 2)` → [1, 1] _(2)_ + [1] - 3 + ([1] \\* 2) _(2)_\\\n= *2*",
+            roll_report(&rolls, UserSettings::default(), true, None));
+
+        // a lone term has nothing to subtotal
+        let rolls = [(RollLine::default(), vec![rolls::parse("3d1").unwrap()])];
+        assert_eq!("[1, 1, 1]\\\n= *3*", roll_report(&rolls, UserSettings::default(), true, None));
+    }
+
+    #[test]
+    fn test_average() {
+        assert!(matches!(Command::from("/avg 3d6+2"), Command::Average(e) if e == "3d6+2"));
+        assert!(matches!(Command::from("/average 3d6+2"), Command::Average(e) if e == "3d6+2"));
+        assert!(matches!(Command::from("/avg 3dd6"), Command::Unknown));
+
+        assert_eq!("12.5", fmt_average(12.5));
+        assert_eq!("7", fmt_average(7.0));
+        assert_eq!("0.33", fmt_average(1.0 / 3.0));
+        assert_eq!("-1.25", fmt_average(-1.25));
+    }
+
+    #[test]
+    fn test_stats() {
+        assert!(matches!(Command::from("/stats 2d6"), Command::Stats(e) if e == "2d6"));
+
+        let stats = rolls::stats("d4 - 2", &mut thread_rng()).unwrap();
+        assert_eq!(
+            "*Distribution of* d4 - 2 _(exact)_\n```\n-1  25.0% ████████████████████\n 0  25.0% ████████████████████\n 1  25.0% ████████████████████\n 2  25.0% ████████████████████\n```",
+            stats_report("d4 - 2", &stats));
+
+        let stats = rolls::stats("d100", &mut thread_rng()).unwrap();
+        let report = stats_report("d100", &stats);
+        assert_eq!(MAX_STATS_ROWS + 3, report.lines().count(), "{}", report);
+        assert!(report.contains("\n   1–4   4.0% "), "{}", report);
+        assert!(report.contains("\n97–100   4.0% "), "{}", report);
+    }
+
+    #[test]
+    fn test_range() {
+        assert!(matches!(Command::from("/range 2d6+3"), Command::Range(e) if e == "2d6+3"));
+        assert!(matches!(Command::from("/range"), Command::Unknown));
+        assert_eq!(Ok((5, 15)), rolls::range("2d6+3"));
+    }
+
+    #[test]
+    fn test_roll_seed() {
+        let lines = match Command::from("/roll seed:1234 2x 3d6 # shared") {
+            Command::Roll { lines, .. } => lines,
+            cmd => panic!("unexpected command: {:?}", cmd),
+        };
+        assert_eq!(vec![RollLine {
+            expr: "3d6".to_string(),
+            label: Some("shared".to_string()),
+            repeat: 2,
+            seed: Some(1234),
+            preview: false,
+            error: None,
+        }], lines);
+
+        let rolls = [(RollLine { seed: Some(7), ..Default::default() }, vec![rolls::parse("2").unwrap()])];
+        assert_eq!("2 = 2 _(seed 7)_", roll_report(&rolls, UserSettings::default(), false, None));
+    }
+
+    #[test]
+    fn test_roll_with_rng() {
+        for prefs in [UserSettings::default(), UserSettings { reroll_ones: true }] {
+            let a = roll_with("4d6kh3 + 3d6! + 2d20", prefs, &mut StdRng::seed_from_u64(42)).unwrap();
+            let b = roll_with("4d6kh3 + 3d6! + 2d20", prefs, &mut StdRng::seed_from_u64(42)).unwrap();
+            assert_eq!(a, b);
+        }
+    }
+
+    #[test]
+    fn test_last() {
+        assert!(matches!(Command::from("/last"), Command::Last(1)));
+        assert!(matches!(Command::from("/last 3 "), Command::Last(3)));
+        assert!(matches!(Command::from("/last 0"), Command::Unknown));
+        assert!(matches!(Command::from("/last 11"), Command::Unknown));
+        assert!(matches!(Command::from("/last 2d6"), Command::Unknown));
+
+        let line = |expr: &str, preview| RollLine { expr: expr.to_string(), repeat: 1, preview, ..Default::default() };
+        let prefs = UserSettings::default();
+        assert!(roll_reply(vec![line("2d6", false)], prefs, false, None, None, Locale::default()).1.is_some());
+        assert!(roll_reply(vec![line("2d6", false), line("d6", true)], prefs, false, None, None, Locale::default()).1.is_some());
+        assert_eq!(None, roll_reply(vec![line("2d6", true)], prefs, false, None, None, Locale::default()).1);
+        assert_eq!(None, roll_reply((0..=MAX_ROLLS).map(|_| line("2d6", false)).collect(), prefs, false, None, None, Locale::default()).1);
+        assert_eq!(None, roll_reply(vec![line("9223372036854775807 + 1", false)], prefs, false, None, None, Locale::default()).1);
+    }
+
+    #[test]
+    fn test_message_dice() {
+        let line = |expr: &str, repeat| RollLine { expr: expr.to_string(), repeat, ..Default::default() };
+        let prefs = UserSettings::default();
+        assert!(roll_reply(vec![line("1000000d6kh1", 2)], prefs, false, None, None, Locale::default()).1.is_some());
+
+        // too many dice across repeats or lines are turned away before any are rolled
+        let started = Instant::now();
+        assert_eq!(None, roll_reply(vec![line("1000000d6kh1", 100)], prefs, false, None, None, Locale::default()).1);
+        let segments = vec!["1000000d6kh1"; MAX_ROLLS].join("; ");
+        let Command::Roll { lines, .. } = Command::from(format!("/roll {}", segments).as_str()) else {
+            panic!("expected a roll: {}", segments);
+        };
+        assert_eq!(None, roll_reply(lines, prefs, false, None, None, Locale::default()).1);
+        let preview = RollLine { preview: true, ..line("1000000d6", 100) };
+        assert_eq!(None, roll_reply(vec![preview, line("d6", 1)], prefs, false, None, None, Locale::default()).1);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_crit_dice() {
+        // doubling is what takes these over the budget
+        let started = Instant::now();
+        assert_eq!(too_many_dice_msg(), crit_reply("1000000d6 + d6"));
+        assert_eq!(too_many_dice_msg(), crit_reply(&["1000000d6"; 80].join(" + ")));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(crit_reply("500000d6 + 500000d6").starts_with("*Critical!* "));
+    }
+
+    #[test]
+    fn test_slow_roll_dice() {
+        let started = Instant::now();
+        let reply = slow_roll(&["1000000d6"; 3].join(" + "), UserSettings::default(), Locale::default());
+        assert_eq!(Err(too_many_dice_msg()), reply.map(|_| ()));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_group_roll_dice() {
+        let groups = GroupRolls::default();
+        let player = || (1, "player".to_string());
+        let started = Instant::now();
+        let many = ["1000000d6"; 80].join(" + ");
+        assert_eq!(too_many_dice_msg(), group_roll(&groups, GroupAction::Start(many), 42, player(), Instant::now()));
+        assert_eq!(GROUP_NOT_OPEN_MSG, group_roll(&groups, GroupAction::Join, 42, player(), Instant::now()));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_random_seed() {
+        let line = |expr: &str, seed| RollLine { expr: expr.to_string(), repeat: 3, seed, ..Default::default() };
+        let prefs = UserSettings::default();
+        let report = roll_reply(vec![line("4d6kh3 + d20", None)], prefs, false, None, None, Locale::default()).1.unwrap();
+        let seed = report.rsplit("_(seed ").next().and_then(|s| s.strip_suffix(")_")).unwrap().parse().unwrap();
+        assert_eq!(Some(report), roll_reply(vec![line("4d6kh3 + d20", Some(seed))], prefs, false, None, None, Locale::default()).1);
+    }
+
+    #[test]
+    fn test_reroll_ones() {
+        assert!(matches!(Command::from("/setreroll1"), Command::RerollOnes(None)));
+        assert!(matches!(Command::from("/setreroll1 on"), Command::RerollOnes(Some(true))));
+        assert!(matches!(Command::from("/setreroll1 OFF"), Command::RerollOnes(Some(false))));
+        assert!(matches!(Command::from("/setreroll1 maybe"), Command::Unknown));
+
+        let prefs = UserSettings { reroll_ones: true };
+        let roll = roll_with("1d1", prefs, &mut thread_rng()).unwrap();
+        assert_eq!("1 = [1→1]\\\n_rerolling 1s once_", roll_report(&[(RollLine::default(), vec![roll])], prefs, false, None));
+
+        let roll = roll_with("1d1", UserSettings::default(), &mut thread_rng()).unwrap();
+        assert_eq!(
+            "1 = [1]\\\n_rolled at 12:00 UTC_",
+            roll_report(&[(RollLine::default(), vec![roll])], UserSettings::default(), false, Some("rolled at 12:00 UTC")));
+    }
+
+    #[test]
+    fn test_crit() {
+        assert!(matches!(Command::from("/crit 2d6 + 3"), Command::Crit(expr) if expr == "2d6 + 3"));
+        assert!(matches!(Command::from("/crit 99999999999dF"), Command::Unknown));
+
+        assert!(crit_reply("2d6kh9223372036854775807").starts_with("*Critical!* "));
+        let reply = crit_reply(&format!("{}dF", rolls::MAX_TIMES));
+        assert!(reply.starts_with("*Critical!* "), "{}", reply);
+        assert_eq!(OVERFLOW_MSG, crit_reply("9223372036854775807 + d1"));
+    }
+
+    #[test]
+    fn test_onboard() {
+        let settings = Settings::default();
+        let roll = Command::from("/roll d20");
+
+        // the tip goes out once, with whatever the user sends first
+        assert!(onboard(&settings, &roll, Some(1)));
+        assert!(!onboard(&settings, &roll, Some(1)));
+        assert!(!onboard(&settings, &Command::from("/last"), Some(1)));
+
+        // starting with /start or /help shows the help, so the tip is skipped
+        assert!(!onboard(&settings, &Command::from("/start"), Some(2)));
+        assert!(!onboard(&settings, &roll, Some(2)));
+        assert!(!onboard(&settings, &roll, None));
+
+        // users seen by an earlier run aren't sent it again
+        let restarted = Settings::with_seen_users(settings.seen_users());
+        assert_eq!(HashSet::from([1, 2]), restarted.seen_users());
+        assert!(!onboard(&restarted, &roll, Some(1)));
+        assert!(onboard(&restarted, &roll, Some(3)));
+    }
+}
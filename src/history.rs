@@ -0,0 +1,128 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// How many recent results are kept for each user in each chat.
+pub(crate) const HISTORY_DEPTH: usize = 10;
+
+/// How many users' histories are kept across all chats before the one rolled
+/// into least recently is forgotten.
+const MAX_HISTORIES: usize = 10_000;
+
+/// Remembers each user's recent roll results in each chat, keyed by chat and
+/// user id, so they can be shown again. History is only kept in memory, so it
+/// starts over whenever the bot restarts.
+pub(crate) struct History {
+    capacity: usize,
+    entries: Mutex<HashMap<(i64, i64), Entry>>,
+}
+
+struct Entry {
+    /// Results, newest first.
+    results: VecDeque<String>,
+    updated: Instant,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Lookup {
+    Found(String),
+    /// There are only this many results to go back through, possibly none.
+    TooFarBack(usize),
+}
+
+impl History {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a result to the user's history in `chat`, dropping the oldest
+    /// result past the depth and the stalest history past the capacity.
+    pub(crate) fn record(&self, chat: i64, user: i64, result: String, now: Instant) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = entries.entry((chat, user)).or_insert_with(|| Entry {
+            results: VecDeque::with_capacity(HISTORY_DEPTH),
+            updated: now,
+        });
+        entry.results.push_front(result);
+        entry.results.truncate(HISTORY_DEPTH);
+        entry.updated = now;
+
+        if entries.len() > self.capacity {
+            let stalest = entries.iter().min_by_key(|(_, e)| e.updated).map(|(key, _)| *key);
+            if let Some(key) = stalest {
+                entries.remove(&key);
+            }
+        }
+    }
+
+    /// The user's result `back` rolls ago in `chat`, counting the latest as 1.
+    pub(crate) fn get(&self, chat: i64, user: i64, back: usize) -> Lookup {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let results = entries.get(&(chat, user)).map(|e| &e.results);
+        match results.and_then(|r| r.get(back.checked_sub(1)?)) {
+            Some(result) => Lookup::Found(result.clone()),
+            None => Lookup::TooFarBack(results.map_or(0, VecDeque::len)),
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(MAX_HISTORIES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_get() {
+        let history = History::default();
+        let now = Instant::now();
+        assert_eq!(Lookup::TooFarBack(0), history.get(1, 2, 1));
+
+        history.record(1, 2, "first".to_string(), now);
+        history.record(1, 2, "second".to_string(), now);
+        assert_eq!(Lookup::Found("second".to_string()), history.get(1, 2, 1));
+        assert_eq!(Lookup::Found("first".to_string()), history.get(1, 2, 2));
+        assert_eq!(Lookup::TooFarBack(2), history.get(1, 2, 3));
+        assert_eq!(Lookup::TooFarBack(2), history.get(1, 2, 0));
+
+        // other users and chats have their own history
+        assert_eq!(Lookup::TooFarBack(0), history.get(1, 3, 1));
+        assert_eq!(Lookup::TooFarBack(0), history.get(4, 2, 1));
+    }
+
+    #[test]
+    fn test_depth() {
+        let history = History::default();
+        let now = Instant::now();
+        for i in 0..HISTORY_DEPTH + 5 {
+            history.record(1, 2, i.to_string(), now);
+        }
+        assert_eq!(Lookup::Found((HISTORY_DEPTH + 4).to_string()), history.get(1, 2, 1));
+        assert_eq!(Lookup::Found("5".to_string()), history.get(1, 2, HISTORY_DEPTH));
+        assert_eq!(Lookup::TooFarBack(HISTORY_DEPTH), history.get(1, 2, HISTORY_DEPTH + 1));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let history = History::new(2);
+        let now = Instant::now();
+        history.record(1, 1, "a".to_string(), now);
+        history.record(2, 2, "b".to_string(), now + Duration::from_secs(1));
+        history.record(1, 1, "c".to_string(), now + Duration::from_secs(2));
+        history.record(3, 3, "d".to_string(), now + Duration::from_secs(3));
+
+        // the history rolled into least recently is forgotten first
+        assert_eq!(Lookup::TooFarBack(0), history.get(2, 2, 1));
+        assert_eq!(Lookup::Found("c".to_string()), history.get(1, 1, 1));
+        assert_eq!(Lookup::Found("d".to_string()), history.get(3, 3, 1));
+    }
+}
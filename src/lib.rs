@@ -0,0 +1,26 @@
+//! The dice expression parser and roller behind the bot, usable on its own.
+//!
+//! Expressions are parsed once into an [`Expression`], which can be sampled
+//! any number of times into a [`Roll`] holding every die rolled. [`parse`]
+//! does both in one step.
+//!
+//! ```
+//! use rand::rngs::StdRng;
+//! use rand::SeedableRng;
+//!
+//! use tg_dice_goblin::Expression;
+//!
+//! let expr = Expression::try_from("4d6kh3 + 2").unwrap();
+//! let roll = expr.sample(&mut StdRng::seed_from_u64(7));
+//! let total = roll.value().unwrap();
+//! assert!((5..=20).contains(&total));
+//! println!("{} = {}", total, roll);
+//! ```
+//!
+//! The Telegram bot itself lives in the binary, behind the default `bot`
+//! feature. Build with `default-features = false` to depend on just the
+//! roller.
+
+pub mod rolls;
+
+pub use rolls::{parse, parse_with_rng, EvalError, Expression, ParseError, Roll};
@@ -0,0 +1,161 @@
+/// A language the bot's fixed messages can be sent in.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) enum Locale {
+    #[default]
+    English,
+    Spanish,
+}
+
+/// A fixed message, looked up in the table for the reader's locale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Text {
+    Start,
+    /// The command list heading the help overview.
+    Help,
+    Unknown,
+}
+
+impl Locale {
+    /// Every locale, in the order their replies are cached.
+    pub(crate) const ALL: [Locale; 2] = [Locale::English, Locale::Spanish];
+
+    /// The locale for a Telegram language code like `es` or `es-MX`, falling
+    /// back to English for anyone whose language hasn't been translated.
+    pub(crate) fn from_lang_code(code: Option<&str>) -> Self {
+        let lang = code.unwrap_or_default().split(['-', '_']).next().unwrap_or_default();
+        match lang.to_ascii_lowercase().as_str() {
+            "es" => Locale::Spanish,
+            _ => Locale::English,
+        }
+    }
+
+    /// `text` in this locale, or in English if it hasn't been translated yet.
+    pub(crate) fn text(self, text: Text) -> &'static str {
+        let table = match self {
+            Locale::English => EN,
+            Locale::Spanish => ES,
+        };
+        lookup(table, text).or_else(|| lookup(EN, text)).unwrap_or_default()
+    }
+}
+
+fn lookup(table: &[(Text, &'static str)], text: Text) -> Option<&'static str> {
+    table.iter().find(|(t, _)| *t == text).map(|(_, s)| *s)
+}
+
+/// Every message in English, which other locales fall back to.
+const EN: &[(Text, &str)] = &[
+    (Text::Start, "Let *Dice Goblin* roll for you!
+
+Dice Goblin will roll any-sided rolls and perform simple arithmetic to reach a total value, appropriate for many tabletop and RPG games. See /help for details on the commands and syntax available."),
+    (Text::Help, "*COMMANDS*
+
+/start\\
+_See introductory information about this bot_
+
+/help `[topic]`\\
+_See this help output, or more on one of the topics below_
+
+/roll `[expression]`\\
+_Rolls and calculate a total (see the expression syntax below)_
+
+/r `[expression]`\\
+_Alias for /roll_
+
+/`[expression]`\\
+_Alias for /roll_
+
+/grouproll start `[expression]`\\
+_Open a group roll in this chat that everyone can contribute to_
+
+/join\\
+_Roll into the open group roll_
+
+/grouproll end\\
+_Close the group roll and total everyone's contributions_
+
+/slowroll `[expression]`\\
+_Roll for dramatic effect, revealing the dice one at a time_
+
+/average `[expression]`\\
+_Calculate the expected value of an expression without rolling it (alias /avg)_
+
+/range `[expression]`\\
+_Show the lowest and highest totals an expression can roll_
+
+/stats `[expression]`\\
+_Chart the chance of each total an expression can roll_
+
+/choose `[option], [option], ...`\\
+_Let the dice pick one of the options. Start with_ `poll` _to post the options as a quiz, with the pick as its answer, before revealing it_
+
+/crit `[expression]`\\
+_Roll critical damage, doubling the dice but not the flat modifiers_
+
+/confirmcrit `[expression]` vs `[target]`\\
+_Roll an attack and, on a natural 20, roll again to confirm the critical. Add_ `crit 19` _to widen the threat range_
+
+/validate `[expressions]`\\
+_Check one expression per line without rolling, reporting any that don't parse_
+
+/timestamps `on|off`\\
+_Show the time of each roll in this chat's replies_
+
+/breakdown `on|off`\\
+_Show the subtotal of each term of this chat's rolls, with the total on its own line_
+
+/timezone `[offset]`\\
+_Set this chat's timezone as a UTC offset, like `+2` or `-05:30`_
+
+/setreroll1 `(on | off)`\\
+_Toggle rerolling any 1s once on all of your /roll and /slowroll rolls_
+
+/last `(n)`\\
+_Show your last roll in this chat again, or the one_ `n` _rolls back. Only your last 10 rolls since the bot restarted are kept_
+
+*ROLL EXPRESSION SYNTAX*
+
+Dice rolls are described in the standard `NdS` format, like `3d10` for three ten-sided dice, and can be combined with arithmetic like `2d6 + 3`. Ask for help on a topic to see more."),
+    (Text::Unknown, "Unknown command. Use /help to see available commands"),
+];
+
+/// The help is left in English until its topics are translated as well.
+const ES: &[(Text, &str)] = &[
+    (Text::Start, "¡Deja que *Dice Goblin* tire por ti!
+
+Dice Goblin tira dados de cualquier número de caras y hace cálculos sencillos para llegar a un total, ideal para muchos juegos de mesa y de rol. Consulta /help para ver los comandos y la sintaxis disponibles."),
+    (Text::Unknown, "Comando desconocido. Usa /help para ver los comandos disponibles"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_lang_code() {
+        let tests = [
+            (Some("es"), Locale::Spanish),
+            (Some("ES-mx"), Locale::Spanish),
+            (Some("es_AR"), Locale::Spanish),
+            (Some("en"), Locale::English),
+            (Some("de"), Locale::English),
+            (Some("esperanto"), Locale::English),
+            (Some(""), Locale::English),
+            (None, Locale::English),
+        ];
+
+        for (code, ex) in tests {
+            assert_eq!(ex, Locale::from_lang_code(code), "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn test_text() {
+        for text in [Text::Start, Text::Help, Text::Unknown] {
+            assert!(lookup(EN, text).is_some(), "{:?}", text);
+        }
+        assert!(Locale::Spanish.text(Text::Unknown).starts_with("Comando desconocido"));
+        // untranslated messages fall back to English
+        assert_eq!(Locale::English.text(Text::Help), Locale::Spanish.text(Text::Help));
+    }
+}
@@ -0,0 +1,133 @@
+use std::env;
+use std::fmt::Write as _;
+use std::io::{self, Write as _};
+use std::str::FromStr;
+
+use chrono::{SecondsFormat, Utc};
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use crate::commands::json_string;
+
+/// How log records are written to stderr.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub(crate) enum LogFormat {
+    /// simple_logger's human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per record, carrying the record's key-values as fields.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}, expected text or json", s)),
+        }
+    }
+}
+
+/// Installs the global logger in the given format. Either way the level comes
+/// from `RUST_LOG`, defaulting to everything.
+pub(crate) fn init(format: LogFormat) -> Result<(), SetLoggerError> {
+    match format {
+        LogFormat::Text => simple_logger::init_with_env(),
+        LogFormat::Json => {
+            let level = env::var("RUST_LOG").ok()
+                .and_then(|l| l.parse().ok())
+                .unwrap_or(LevelFilter::Trace);
+            log::set_max_level(level);
+            log::set_boxed_logger(Box::new(JsonLogger { level }))
+        }
+    }
+}
+
+struct JsonLogger {
+    level: LevelFilter,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = json_line(record, &Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true));
+        let _ = writeln!(io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+/// Renders `record` as a single-line JSON object stamped with `time`.
+fn json_line(record: &Record, time: &str) -> String {
+    let mut out = format!(
+        r#"{{"time":{},"level":{},"target":{},"message":{}"#,
+        json_string(time),
+        json_string(record.level().as_str()),
+        json_string(record.target()),
+        json_string(&record.args().to_string()),
+    );
+    let _ = record.key_values().visit(&mut Fields(&mut out));
+    out.push('}');
+    out
+}
+
+/// Appends each key-value to a JSON object, keeping integers as numbers.
+struct Fields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = match (value.to_i64(), value.to_bool()) {
+            (Some(n), _) => n.to_string(),
+            (_, Some(b)) => b.to_string(),
+            _ => json_string(&value.to_string()),
+        };
+        let _ = write!(self.0, ",{}:{}", json_string(key.as_str()), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!(Ok(LogFormat::Text), "text".parse());
+        assert_eq!(Ok(LogFormat::Json), " JSON ".parse());
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_json_line() {
+        let fields: &[(&str, Value)] = &[
+            ("chat_id", Value::from(-100)),
+            ("expr", Value::from("2d6 \"fire\"")),
+        ];
+        let line = json_line(
+            &Record::builder()
+                .args(format_args!("roll: {}", 7))
+                .level(Level::Info)
+                .target("tg_dice_goblin::handler")
+                .key_values(&fields)
+                .build(),
+            "2024-01-02T03:04:05.000Z",
+        );
+        assert_eq!(
+            r#"{"time":"2024-01-02T03:04:05.000Z","level":"INFO","target":"tg_dice_goblin::handler","message":"roll: 7","chat_id":-100,"expr":"2d6 \"fire\""}"#,
+            line,
+        );
+    }
+}
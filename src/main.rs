@@ -1,73 +1,404 @@
 use std::env;
 use std::error::Error;
 use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
 
-use grammers_client::{Client, Config, InitParams};
-use grammers_session::Session;
-use log::{error, trace};
+use grammers_client::{Client, Config, InitParams, InvocationError};
+use grammers_tl_types as tl;
+use log::{error, info, trace, warn};
 use tokio::{select, task};
+use tokio::sync::Semaphore;
+use tokio::time::{interval_at, sleep, Instant};
 
+use tg_dice_goblin::rolls;
+
+mod animate;
+mod choose;
+mod commands;
+mod config;
+mod crit;
+mod dedup;
+mod group_roll;
 mod handler;
-mod rolls;
+mod history;
+mod locale;
+mod logger;
+mod metrics;
+mod rate_limit;
+mod reconnect;
+mod session;
+mod settings;
 
+const CONFIG_VAR: &str = "DICE_GOBLIN_CONFIG";
+const LOG_FORMAT_VAR: &str = "DICE_GOBLIN_LOG_FORMAT";
 const API_ID_VAR: &str = "DICE_GOBLIN_API_ID";
 const API_HASH_VAR: &str = "DICE_GOBLIN_API_HASH";
 const TOKEN_VAR: &str = "DICE_GOBLIN_TOKEN";
 const SESSION_VAR: &str = "DICE_GOBLIN_SESSION";
+const SESSION_BACKEND_VAR: &str = "DICE_GOBLIN_SESSION_BACKEND";
+const REDIS_ADDR_VAR: &str = "DICE_GOBLIN_REDIS_ADDR";
+const REDIS_PASSWORD_VAR: &str = "DICE_GOBLIN_REDIS_PASSWORD";
+const DEDUP_WINDOW_VAR: &str = "DICE_GOBLIN_DEDUP_WINDOW_MS";
+const RATE_BURST_VAR: &str = "DICE_GOBLIN_RATE_BURST";
+const RATE_PER_MINUTE_VAR: &str = "DICE_GOBLIN_RATE_PER_MINUTE";
+const RETRY_DELAY_VAR: &str = "DICE_GOBLIN_RETRY_DELAY_MS";
+const MAX_RETRIES_VAR: &str = "DICE_GOBLIN_MAX_RETRIES";
+const MAX_IN_FLIGHT_VAR: &str = "DICE_GOBLIN_MAX_IN_FLIGHT";
+const SESSION_SAVE_VAR: &str = "DICE_GOBLIN_SESSION_SAVE_SECS";
+
+/// How many updates are handled at once when no limit is set.
+const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+
+/// How often the session is saved while running when no interval is set, so
+/// an unexpected exit still catches up on missed updates.
+const DEFAULT_SESSION_SAVE: Duration = Duration::from_secs(5 * 60);
+
+/// How many times saving the session is tried before giving up.
+const SESSION_SAVE_ATTEMPTS: u32 = 3;
+const DIVISION_VAR: &str = "DICE_GOBLIN_DIVISION";
+const STRICT_DIVISION_VAR: &str = "DICE_GOBLIN_STRICT_DIVISION";
+const MAX_DEPTH_VAR: &str = "DICE_GOBLIN_MAX_DEPTH";
+const MAX_LENGTH_VAR: &str = "DICE_GOBLIN_MAX_LENGTH";
+const MANY_DICE_VAR: &str = "DICE_GOBLIN_MANY_DICE";
+const TOO_MANY_DICE_VAR: &str = "DICE_GOBLIN_TOO_MANY_DICE";
+const MAX_DICE_VAR: &str = "DICE_GOBLIN_MAX_DICE";
+const MAX_SIDES_VAR: &str = "DICE_GOBLIN_MAX_SIDES";
+const METRICS_ADDR_VAR: &str = "DICE_GOBLIN_METRICS_ADDR";
+const NATURAL_CRITS_VAR: &str = "DICE_GOBLIN_NATURAL_CRITS";
+
+const COMMANDS_JSON_ARG: &str = "commands-json";
+const CONFIG_ARG: &str = "--config";
 
 type Result = std::result::Result<(), Box<dyn Error>>;
 
 #[tokio::main]
 async fn main() -> Result {
-    simple_logger::init_with_env()?;
+    if env::args().nth(1).as_deref() == Some(COMMANDS_JSON_ARG) {
+        println!("{}", commands::to_json());
+        return Ok(());
+    }
 
-    let session_file = env::var(SESSION_VAR)?;
-    let api_id = env::var(API_ID_VAR)?.parse()?;
-    let api_hash = env::var(API_HASH_VAR)?;
-    let token = env::var(TOKEN_VAR)?;
+    let config = match env::args().skip_while(|a| a != CONFIG_ARG).nth(1).or_else(|| env::var(CONFIG_VAR).ok()) {
+        Some(path) => config::Config::load(&path)?,
+        None => config::Config::default(),
+    };
+    let log_format = match config.var(LOG_FORMAT_VAR) {
+        Ok(format) => format.parse()?,
+        Err(_) => logger::LogFormat::default(),
+    };
+    logger::init(log_format)?;
 
-    trace!("connecting to Telegram...");
-    let client = Client::connect(
-        Config {
-            api_id,
-            api_hash,
-            session: Session::load_file_or_create(&session_file)?,
-            params: InitParams {
-                catch_up: true,
-                ..Default::default()
-            },
-        }
-    ).await?;
-    trace!("connected");
+    let session_backend = match config.var(SESSION_BACKEND_VAR) {
+        Ok(backend) => backend.parse()?,
+        Err(_) => session::SessionBackend::default(),
+    };
+    // the session names a file for the file backend, or a key for redis
+    let session = Arc::new(match session_backend {
+        session::SessionBackend::File => session::SessionStore::File(config.var(SESSION_VAR)?),
+        session::SessionBackend::Redis => session::SessionStore::Redis {
+            addr: config.var(REDIS_ADDR_VAR)?,
+            password: config.var(REDIS_PASSWORD_VAR).ok(),
+            key: config.var(SESSION_VAR)?,
+        },
+    });
+    let api_id = config.var(API_ID_VAR)?.parse()?;
+    let api_hash = config.var(API_HASH_VAR)?;
+    let token = config.var(TOKEN_VAR)?;
+    let dedup_window = match config.var(DEDUP_WINDOW_VAR) {
+        Ok(ms) => Duration::from_millis(ms.parse()?),
+        Err(_) => dedup::DEFAULT_DEDUP_WINDOW,
+    };
+    let rate_burst = match config.var(RATE_BURST_VAR) {
+        Ok(n) => n.parse()?,
+        Err(_) => rate_limit::DEFAULT_RATE_BURST,
+    };
+    let rate_per_minute = match config.var(RATE_PER_MINUTE_VAR) {
+        Ok(n) => n.parse()?,
+        Err(_) => rate_limit::DEFAULT_RATE_PER_MINUTE,
+    };
+    let retry_delay = match config.var(RETRY_DELAY_VAR) {
+        Ok(ms) => Duration::from_millis(ms.parse()?),
+        Err(_) => reconnect::DEFAULT_RETRY_DELAY,
+    };
+    let max_retries = match config.var(MAX_RETRIES_VAR) {
+        Ok(n) => n.parse()?,
+        Err(_) => reconnect::DEFAULT_MAX_RETRIES,
+    };
+    let max_in_flight = match config.var(MAX_IN_FLIGHT_VAR) {
+        Ok(n) => n.parse()?,
+        Err(_) => DEFAULT_MAX_IN_FLIGHT,
+    };
+    if max_in_flight == 0 {
+        return Err(format!("{} must be at least 1", MAX_IN_FLIGHT_VAR).into());
+    }
+    let session_save = match config.var(SESSION_SAVE_VAR) {
+        Ok(secs) => Duration::from_secs(secs.parse()?),
+        Err(_) => DEFAULT_SESSION_SAVE,
+    };
+    if session_save.is_zero() {
+        return Err(format!("{} must be at least 1", SESSION_SAVE_VAR).into());
+    }
+    if let Ok(mode) = config.var(DIVISION_VAR) {
+        rolls::set_default_div_mode(mode.parse()?);
+    }
+    if let Ok(strict) = config.var(STRICT_DIVISION_VAR) {
+        rolls::set_strict_division(strict.parse()?);
+    }
+    if let Ok(depth) = config.var(MAX_DEPTH_VAR) {
+        rolls::set_max_depth(depth.parse()?);
+    }
+    if let Ok(len) = config.var(MAX_LENGTH_VAR) {
+        rolls::set_max_length(len.parse()?);
+    }
+    let many_dice = match config.var(MANY_DICE_VAR) {
+        Ok(n) => n.parse()?,
+        Err(_) => rolls::DEFAULT_MANY_DICE,
+    };
+    let too_many_dice = match config.var(TOO_MANY_DICE_VAR) {
+        Ok(n) => n.parse()?,
+        Err(_) => rolls::DEFAULT_TOO_MANY_DICE,
+    };
+    if many_dice > too_many_dice {
+        return Err(format!("{} ({}) must not exceed {} ({})", MANY_DICE_VAR, many_dice, TOO_MANY_DICE_VAR, too_many_dice).into());
+    }
+    info!("tallying pools over {} dice, summing those with over {} sides", many_dice, too_many_dice);
+    rolls::set_dice_thresholds(many_dice, too_many_dice);
+    let max_dice = match config.var(MAX_DICE_VAR) {
+        Ok(n) => n.parse()?,
+        Err(_) => rolls::MAX_TIMES,
+    };
+    if !(1..=rolls::MAX_TIMES).contains(&max_dice) {
+        return Err(format!("{} must be between 1 and {}", MAX_DICE_VAR, rolls::MAX_TIMES).into());
+    }
+    let max_sides = match config.var(MAX_SIDES_VAR) {
+        Ok(n) => n.parse()?,
+        Err(_) => rolls::DEFAULT_MAX_SIDES,
+    };
+    if max_sides < 1 {
+        return Err(format!("{} must be at least 1", MAX_SIDES_VAR).into());
+    }
+    rolls::set_dice_limits(max_dice, max_sides);
+    if let Ok(on) = config.var(NATURAL_CRITS_VAR) {
+        handler::set_natural_crits(on.parse()?);
+    }
+    let metrics_server = match config.var(METRICS_ADDR_VAR) {
+        Ok(addr) => Some(serve_metrics(&addr).await?),
+        Err(_) => None,
+    };
+
+    let mut client = connect(api_id, &api_hash, &session).await?;
 
     if !client.is_authorized().await? {
         trace!("Signing in...");
         client.bot_sign_in(&token).await?;
-        if let Err(e) =  client.session().save_to_file(&session_file) {
+        if let Err(e) = save_session(&client, &session).await {
             client.sign_out().await?;
             return Err(e.into());
         }
         trace!("Signed in!")
     }
+    register_commands(&client).await;
 
-    let mut exit = pin!(tokio::signal::ctrl_c());
+    let state = Arc::new(handler::State::new(
+        dedup::Dedup::new(dedup_window),
+        rate_limit::RateLimit::new(rate_burst, rate_per_minute),
+        session.load_seen().await?,
+    ));
+    let in_flight = Arc::new(Semaphore::new(max_in_flight));
+    let backoff = reconnect::Backoff::new(retry_delay, max_retries);
+    let mut attempt = 0;
+    let mut exit = pin!(shutdown_signal());
+    let mut save = interval_at(Instant::now() + session_save, session_save);
+    let mut saving: Option<task::JoinHandle<()>> = None;
     loop {
         let update = select! {
-            _ = &mut exit => None,
-            upd = client.next_update() => upd?,
+            signal = &mut exit => {
+                log_shutdown(signal);
+                Ok(None)
+            }
+            _ = save.tick() => {
+                // saving may retry for a while, so it runs beside the update loop
+                // rather than holding it up, skipping a turn if the last is still going
+                if saving.as_ref().is_none_or(|s| s.is_finished()) {
+                    let (client, session, state) = (client.clone(), session.clone(), state.clone());
+                    saving = Some(task::spawn(async move {
+                        let _ = save_session(&client, &session).await;
+                        let _ = save_seen(&state, &session).await;
+                    }));
+                }
+                continue;
+            }
+            upd = client.next_update() => upd,
         };
         match update {
-            None => break,
-            Some(upd) => task::spawn(async move {
-                match handler::handle(upd).await {
-                    Ok(_) => {},
-                    Err(e) => error!("Error handling update: {}", e)
+            Ok(None) => break,
+            Ok(Some(upd)) => {
+                attempt = 0;
+                // waiting for a permit holds off fetching more updates until one finishes
+                let permit = match in_flight.clone().try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        warn!("handling {} updates already, waiting for one to finish", max_in_flight);
+                        in_flight.clone().acquire_owned().await?
+                    }
+                };
+                let (client, state) = (client.clone(), state.clone());
+                task::spawn(async move {
+                    match handler::handle(upd, client, state).await {
+                        Ok(_) => {},
+                        Err(e) => error!("Error handling update: {}", e)
+                    }
+                    drop(permit);
+                });
+            }
+            Err(InvocationError::Rpc(e)) if e.code == 401 => return Err(InvocationError::Rpc(e).into()),
+            Err(e) => {
+                let Some(delay) = backoff.delay(attempt) else {
+                    return Err(e.into());
+                };
+                attempt += 1;
+                warn!("lost connection ({}), reconnecting in {:?} (attempt {} of {})", e, delay, attempt, max_retries);
+                select! {
+                    signal = &mut exit => {
+                        log_shutdown(signal);
+                        break;
+                    }
+                    _ = sleep(delay) => {}
+                }
+                let _ = save_session(&client, &session).await;
+                match connect(api_id, &api_hash, &session).await {
+                    Ok(c) => client = c,
+                    Err(e) => warn!("reconnect failed: {}", e),
                 }
-            }),
+            }
         };
     }
 
     trace!("Exiting...");
-    client.session().save_to_file(&session_file)?;
+    if let Some(server) = metrics_server {
+        server.abort();
+    }
+    // the last save waits for any periodic one so it isn't overwritten by older state
+    if let Some(saving) = saving {
+        let _ = saving.await;
+    }
+    let _ = save_seen(&state, &session).await;
+    save_session(&client, &session).await?;
     Ok(())
+}
+
+/// Waits for ctrl-c, or for SIGTERM on unix, returning the name of the
+/// signal received.
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    select! {
+        res = tokio::signal::ctrl_c() => res.map(|_| "SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    tokio::signal::ctrl_c().await.map(|_| "ctrl-c")
+}
+
+/// Logs why the bot is shutting down. Failing to listen for signals stops the
+/// bot too, rather than leaving it running without a way to exit cleanly.
+fn log_shutdown(signal: std::io::Result<&str>) {
+    match signal {
+        Ok(name) => info!("received {}, shutting down", name),
+        Err(e) => error!("unable to listen for shutdown signals, shutting down: {}", e),
+    }
+}
+
+/// Starts serving metrics at `addr`, failing if the address can't be bound.
+#[cfg(feature = "metrics")]
+async fn serve_metrics(addr: &str) -> std::result::Result<task::JoinHandle<()>, Box<dyn Error>> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("serving metrics at http://{}/metrics", listener.local_addr()?);
+    Ok(task::spawn(metrics::serve(listener)))
+}
+
+#[cfg(not(feature = "metrics"))]
+async fn serve_metrics(_: &str) -> std::result::Result<task::JoinHandle<()>, Box<dyn Error>> {
+    Err(format!("{} is set, but this build doesn't include the metrics feature", METRICS_ADDR_VAR).into())
+}
+
+/// Sets the bot's command menu from the command catalog. Failing to do so
+/// only costs autocompletion, so it's logged rather than fatal.
+async fn register_commands(client: &Client) {
+    let commands: Vec<_> = commands::menu().into_iter()
+        .map(|(command, description)| tl::enums::BotCommand::Command(tl::types::BotCommand {
+            command: command.to_string(),
+            description: description.to_string(),
+        }))
+        .collect();
+    let count = commands.len();
+    let request = tl::functions::bots::SetBotCommands {
+        scope: tl::enums::BotCommandScope::Default,
+        lang_code: String::new(),
+        commands,
+    };
+    match client.invoke(&request).await {
+        Ok(_) => info!("registered {} commands", count),
+        Err(e) => warn!("failed to register commands: {}", e),
+    }
+}
+
+/// Saves the session to `store`, retrying a few times in case the failure is
+/// only temporary. Every failure is logged.
+async fn save_session(client: &Client, store: &session::SessionStore) -> std::io::Result<()> {
+    let mut attempt = 1;
+    loop {
+        match store.save(client.session()).await {
+            Ok(()) => {
+                trace!("session saved");
+                return Ok(());
+            }
+            Err(e) if attempt < SESSION_SAVE_ATTEMPTS => {
+                warn!("failed to save session (attempt {} of {}): {}", attempt, SESSION_SAVE_ATTEMPTS, e);
+                sleep(Duration::from_secs(1)).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                error!("failed to save session: {}", e);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Saves the users the bot has seen to `store`, so a restart doesn't send the
+/// onboarding tip to them again. A failure is logged and left for the next save.
+async fn save_seen(state: &handler::State, store: &session::SessionStore) -> std::io::Result<()> {
+    match store.save_seen(&state.seen_users()).await {
+        Ok(()) => {
+            trace!("seen users saved");
+            Ok(())
+        }
+        Err(e) => {
+            warn!("failed to save seen users: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Connects to Telegram with the session saved in `store`.
+async fn connect(api_id: i32, api_hash: &str, store: &session::SessionStore) -> std::result::Result<Client, Box<dyn Error>> {
+    trace!("connecting to Telegram...");
+    let client = Client::connect(
+        Config {
+            api_id,
+            api_hash: api_hash.to_string(),
+            session: store.load().await?,
+            params: InitParams {
+                catch_up: true,
+                ..Default::default()
+            },
+        }
+    ).await?;
+    trace!("connected");
+    Ok(client)
 }
\ No newline at end of file
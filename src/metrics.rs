@@ -0,0 +1,203 @@
+// without the metrics feature nothing serves the counters, so they're never read
+#![cfg_attr(not(feature = "metrics"), allow(dead_code))]
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The upper bounds, in seconds, of the evaluation time histogram's buckets.
+const EVAL_BUCKETS: [f64; 8] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5];
+
+/// Every metric the bot records, exposed in the Prometheus text format.
+pub(crate) static METRICS: Metrics = Metrics {
+    rolls: Counter::new(),
+    parse_failures: Counter::new(),
+    handler_errors: Counter::new(),
+    eval_time: Histogram::new(),
+};
+
+pub(crate) struct Metrics {
+    /// Roll expressions parsed and rolled.
+    pub(crate) rolls: Counter,
+    /// Roll expressions that failed to parse.
+    pub(crate) parse_failures: Counter,
+    /// Updates whose handling ended in an error.
+    pub(crate) handler_errors: Counter,
+    /// How long parsing and rolling an expression takes.
+    pub(crate) eval_time: Histogram,
+}
+
+impl Metrics {
+    /// Renders every metric in the Prometheus text exposition format.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        self.rolls.render(&mut out, "dice_goblin_rolls_total", "Roll expressions parsed and rolled.");
+        self.parse_failures.render(&mut out, "dice_goblin_parse_failures_total", "Roll expressions that failed to parse.");
+        self.handler_errors.render(&mut out, "dice_goblin_handler_errors_total", "Updates whose handling ended in an error.");
+        self.eval_time.render(&mut out, "dice_goblin_eval_seconds", "Time spent parsing and rolling an expression.");
+        out
+    }
+}
+
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub(crate) fn inc(&self) {
+        self.add(1);
+    }
+
+    pub(crate) fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, self.0.load(Ordering::Relaxed));
+    }
+}
+
+/// A histogram over fixed buckets. Each bucket counts only the observations
+/// that fell in it; they're summed into Prometheus' cumulative buckets when
+/// rendered.
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; EVAL_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; EVAL_BUCKETS.len() + 1],
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let i = EVAL_BUCKETS.iter().position(|&le| secs <= le).unwrap_or(EVAL_BUCKETS.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(elapsed.as_nanos().try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let _ = match EVAL_BUCKETS.get(i) {
+                Some(le) => writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count),
+                None => writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count),
+            };
+        }
+        let sum = Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)).as_secs_f64();
+        let _ = writeln!(out, "{}_sum {}\n{}_count {}", name, sum, name, count);
+    }
+}
+
+/// Serves the metrics at `/metrics` over plain HTTP until the task is
+/// aborted. Connections are answered one request at a time and then closed.
+#[cfg(feature = "metrics")]
+pub(crate) async fn serve(listener: tokio::net::TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::task::spawn(async move {
+                    if let Err(e) = respond(stream).await {
+                        log::trace!("metrics request failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => log::warn!("failed to accept metrics connection: {}", e),
+        }
+    }
+}
+
+/// The longest request head read before giving up on a metrics request.
+#[cfg(feature = "metrics")]
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+#[cfg(feature = "metrics")]
+async fn respond(mut stream: tokio::net::TcpStream) -> std::io::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN {
+        match stream.read(&mut buf).await? {
+            0 => break,
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    let line = request.split(|&b| b == b'\n').next().unwrap_or_default();
+    let response = response(&String::from_utf8_lossy(line), &METRICS);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// The HTTP response to a request starting with `request_line`.
+#[cfg(feature = "metrics")]
+fn response(request_line: &str, metrics: &Metrics) -> String {
+    let (status, body) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", "/metrics", _] => ("200 OK", metrics.render()),
+        [_, "/metrics", _] => ("405 Method Not Allowed", String::new()),
+        _ => ("404 Not Found", String::new()),
+    };
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics() -> Metrics {
+        Metrics {
+            rolls: Counter::new(),
+            parse_failures: Counter::new(),
+            handler_errors: Counter::new(),
+            eval_time: Histogram::new(),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = metrics();
+        metrics.rolls.add(3);
+        metrics.parse_failures.inc();
+        metrics.eval_time.observe(Duration::from_micros(50));
+        metrics.eval_time.observe(Duration::from_micros(700));
+        metrics.eval_time.observe(Duration::from_secs(2));
+
+        let out = metrics.render();
+        assert!(out.contains("# TYPE dice_goblin_rolls_total counter\ndice_goblin_rolls_total 3\n"));
+        assert!(out.contains("dice_goblin_parse_failures_total 1\n"));
+        assert!(out.contains("dice_goblin_handler_errors_total 0\n"));
+        assert!(out.contains("# TYPE dice_goblin_eval_seconds histogram\n"));
+        // buckets are cumulative
+        assert!(out.contains("dice_goblin_eval_seconds_bucket{le=\"0.0001\"} 1\n"));
+        assert!(out.contains("dice_goblin_eval_seconds_bucket{le=\"0.0005\"} 1\n"));
+        assert!(out.contains("dice_goblin_eval_seconds_bucket{le=\"0.001\"} 2\n"));
+        assert!(out.contains("dice_goblin_eval_seconds_bucket{le=\"0.5\"} 2\n"));
+        assert!(out.contains("dice_goblin_eval_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("dice_goblin_eval_seconds_sum 2.00075\ndice_goblin_eval_seconds_count 3\n"));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_response() {
+        let metrics = metrics();
+        metrics.rolls.inc();
+
+        let ok = response("GET /metrics HTTP/1.1\r", &metrics);
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(ok.ends_with(&metrics.render()));
+        assert!(response("POST /metrics HTTP/1.1", &metrics).starts_with("HTTP/1.1 405 "));
+        assert!(response("GET / HTTP/1.1", &metrics).starts_with("HTTP/1.1 404 "));
+        assert!(response("garbage", &metrics).starts_with("HTTP/1.1 404 "));
+    }
+}
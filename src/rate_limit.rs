@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// How many commands a user may send back to back before being limited.
+pub(crate) const DEFAULT_RATE_BURST: u32 = 5;
+
+/// How many commands a user may send per minute once their burst is spent.
+pub(crate) const DEFAULT_RATE_PER_MINUTE: u32 = 20;
+
+/// Limits how quickly each user may send commands with a token bucket: every
+/// command spends a token, and tokens refill steadily up to the burst size.
+pub(crate) struct RateLimit {
+    burst: f64,
+    per_sec: f64,
+    buckets: Mutex<HashMap<i64, (f64, Instant)>>,
+}
+
+impl RateLimit {
+    pub(crate) fn new(burst: u32, per_minute: u32) -> Self {
+        Self {
+            burst: f64::from(burst),
+            per_sec: f64::from(per_minute) / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Spends one of the user's tokens, returning false if they have none left.
+    /// A zero rate disables limiting.
+    pub(crate) fn allow(&self, user: i64, now: Instant) -> bool {
+        if self.per_sec == 0.0 {
+            return true;
+        }
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        // full buckets are the same as missing ones, so they're dropped
+        buckets.retain(|_, (tokens, at)| *tokens + now.duration_since(*at).as_secs_f64() * self.per_sec < self.burst);
+
+        let (tokens, at) = buckets.get(&user).copied().unwrap_or((self.burst, now));
+        let tokens = (tokens + now.duration_since(at).as_secs_f64() * self.per_sec).min(self.burst);
+        if tokens < 1.0 {
+            buckets.insert(user, (tokens, now));
+            return false;
+        }
+        buckets.insert(user, (tokens - 1.0, now));
+        true
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self::new(DEFAULT_RATE_BURST, DEFAULT_RATE_PER_MINUTE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_allow() {
+        let limit = RateLimit::new(2, 60);
+        let now = Instant::now();
+
+        assert!(limit.allow(1, now));
+        assert!(limit.allow(1, now));
+        assert!(!limit.allow(1, now));
+
+        // other users have their own bucket
+        assert!(limit.allow(2, now));
+
+        // a token refills every second at 60 per minute
+        assert!(!limit.allow(1, now + Duration::from_millis(500)));
+        assert!(limit.allow(1, now + Duration::from_millis(1000)));
+        assert!(!limit.allow(1, now + Duration::from_millis(1000)));
+
+        // buckets never refill past the burst
+        let later = now + Duration::from_secs(60);
+        assert!(limit.allow(1, later));
+        assert!(limit.allow(1, later));
+        assert!(!limit.allow(1, later));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let limit = RateLimit::new(0, 0);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(limit.allow(1, now));
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+/// The delay before the first attempt to reconnect after losing updates.
+pub(crate) const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How many reconnects in a row are attempted before giving up.
+pub(crate) const DEFAULT_MAX_RETRIES: u32 = 10;
+
+/// The longest delay between reconnect attempts, however many have failed.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Spaces out reconnect attempts with exponential backoff, so a brief network
+/// blip recovers quickly while a longer outage doesn't hammer Telegram.
+pub(crate) struct Backoff {
+    base: Duration,
+    max_retries: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new(base: Duration, max_retries: u32) -> Self {
+        Self { base, max_retries }
+    }
+
+    /// The delay before reconnect `attempt`, counting from zero, doubling with
+    /// each attempt. Returns nothing once every retry is spent.
+    pub(crate) fn delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_retries {
+            return None;
+        }
+        let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+        Some(self.base.saturating_mul(factor).min(MAX_RETRY_DELAY))
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(DEFAULT_RETRY_DELAY, DEFAULT_MAX_RETRIES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let backoff = Backoff::new(Duration::from_secs(1), 20);
+        assert_eq!(Some(Duration::from_secs(1)), backoff.delay(0));
+        assert_eq!(Some(Duration::from_secs(2)), backoff.delay(1));
+        assert_eq!(Some(Duration::from_secs(8)), backoff.delay(3));
+        assert_eq!(Some(MAX_RETRY_DELAY), backoff.delay(19));
+        assert_eq!(None, backoff.delay(20));
+
+        assert_eq!(None, Backoff::new(Duration::from_secs(1), 0).delay(0));
+    }
+}
@@ -1,37 +1,882 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::OnceLock;
 
 use nom::combinator::all_consuming;
-use nom::Err;
-use nom::error::Error;
-use super::expr;
+use super::{expr, max_length, ParseError};
+use rand::Rng;
+use super::roll::{dice_limits, MAX_EXPLOSIONS, MAX_WEIGHTED_SIDES};
+use super::Roll;
+
+/// The most distinct totals an exact distribution may track.
+pub const MAX_OUTCOMES: usize = 10_000;
+
+/// The most pairs of outcomes an exact distribution may combine before giving up.
+const MAX_COMBINATIONS: usize = 2_000_000;
+
+/// The chance of each total, keyed by the total.
+pub type Distribution = BTreeMap<i64, f64>;
 
 #[derive(Debug, PartialEq)]
 pub enum Expression {
     Num(i64),
-    Dice { times: i64, sides: i64 },
+    Dice { times: i64, sides: i64, mods: Modifiers },
+    /// Dice whose count is rolled first, as in `(d4)d6`.
+    Counted { times: Box<Expression>, sides: i64, mods: Modifiers },
+    /// Dice whose number of sides is rolled first, as in `3d(2d6)`.
+    Sided { times: i64, sides: Box<Expression>, mods: Modifiers },
+    /// Fate dice (`dF`), each landing on -1, 0, or +1.
+    Fudge { times: i64 },
+    Keep(Box<Expression>, Selection),
 
     Grp(Box<Expression>),
+    /// A negated term, as in `-(d6)` or `-2d6`.
+    Neg(Box<Expression>),
+    /// The absolute value of a term, as in `abs(d20 - d20)`.
+    Abs(Box<Expression>),
     Add(Box<Expression>, Box<Expression>),
     Sub(Box<Expression>, Box<Expression>),
     Mul(Box<Expression>, Box<Expression>),
-    Div(Box<Expression>, Box<Expression>),
+    /// Division, rounded by the given mode or the default mode if unset.
+    Div(Box<Expression>, Box<Expression>, Option<DivMode>),
+    Mod(Box<Expression>, Box<Expression>),
+    Pow(Box<Expression>, Box<Expression>),
+    Min(Vec<Expression>),
+    Max(Vec<Expression>),
+    /// A term held between two bounds, as in `clamp(d20 + 5, 1, 20)`. The
+    /// bounds may come in either order.
+    Clamp(Box<Expression>, Box<Expression>, Box<Expression>),
+}
+
+/// Optional behaviors applied to the individual dice of a single dice term.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Modifiers {
+    /// Faces run from 0 to sides - 1 instead of 1 to sides (`zS` notation).
+    pub zero_based: bool,
+    /// Reroll the single lowest die one time (`rl`).
+    pub reroll_lowest: bool,
+    /// Reroll every die showing this face or lower one time (`roN`). Also set
+    /// by the per-user "reroll 1s" preference.
+    pub reroll_once: Option<i64>,
+    /// Keep rerolling every die showing this face or lower (`rN`).
+    pub reroll: Option<i64>,
+    /// Roll again whenever a die shows its highest face.
+    pub explode: Option<Explode>,
+    /// Explode on the faces meeting this condition instead of only the highest (`!>=T`).
+    pub explode_on: Option<Condition>,
+    /// Treat every die showing less than this face as showing it (`miN`).
+    pub minimum: Option<i64>,
+    /// Count the dice meeting this condition instead of summing them (`>=T`).
+    pub successes: Option<Condition>,
+    /// Subtract the dice meeting this condition from the successes (`fT`),
+    /// so the count can go negative. Only used alongside `successes`.
+    pub failures: Option<Condition>,
+    /// Relative weights for specific faces, making the die unfair (`w[face:weight,...]`).
+    /// Faces not listed have a weight of 1.
+    pub weights: Vec<(i64, u32)>,
+    /// List the dice in face order rather than the order they were rolled
+    /// (`s` or `sd`). Only changes how the dice are shown.
+    pub sort: Option<Sort>,
+}
+
+impl Selection {
+    /// How many of `n` dice the selection keeps.
+    fn kept(self, n: f64) -> f64 {
+        match self {
+            Self::KeepHigh(k) | Self::KeepLow(k) => k as f64,
+            Self::DropHigh(k) | Self::DropLow(k) => n - k as f64,
+        }
+    }
+
+    fn doubled(self) -> Self {
+        match self {
+            Self::KeepHigh(n) => Self::KeepHigh(doubled(n)),
+            Self::KeepLow(n) => Self::KeepLow(doubled(n)),
+            Self::DropHigh(n) => Self::DropHigh(doubled(n)),
+            Self::DropLow(n) => Self::DropLow(doubled(n)),
+        }
+    }
+}
+
+/// Doubles a count of dice for a critical hit, no further than the most dice
+/// a term may roll.
+fn doubled(times: i64) -> i64 {
+    times.saturating_mul(2).min(dice_limits().0)
+}
+
+impl Modifiers {
+    /// Moves reroll thresholds outside the faces of a die with `sides` sides
+    /// onto its nearest face, which they behave like.
+    pub(super) fn clamp_rerolls(self, sides: i64) -> Self {
+        let low = if self.zero_based { 0 } else { 1 };
+        let clamp = |n: i64| n.clamp(low, (sides - 1 + low).max(low));
+        Modifiers {
+            reroll_once: self.reroll_once.map(clamp),
+            reroll: self.reroll.map(clamp),
+            ..self
+        }
+    }
+
+    /// Whether any modifier needs to inspect the individual dice after rolling.
+    pub fn per_die(&self) -> bool {
+        self.reroll_lowest || self.reroll_once.is_some() || self.reroll.is_some() || self.explode.is_some()
+            || self.minimum.is_some()
+    }
+}
+
+/// Compares a die's face against a target, as in `8d10>=7`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Condition {
+    pub cmp: Compare,
+    pub target: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compare {
+    Lt,
+    Le,
+    Eq,
+    Ge,
+    Gt,
+}
+
+impl Condition {
+    pub fn matches(&self, face: i64) -> bool {
+        match self.cmp {
+            Compare::Lt => face < self.target,
+            Compare::Le => face <= self.target,
+            Compare::Eq => face == self.target,
+            Compare::Ge => face >= self.target,
+            Compare::Gt => face > self.target,
+        }
+    }
+}
+
+/// How the extra rolls of an exploding die are counted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Explode {
+    /// Each extra roll is added to the pool as its own die (`!`).
+    Each,
+    /// Extra rolls are added into the die that exploded (`!!`).
+    Compound,
+}
+
+/// The order dice are listed in when sorted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sort {
+    /// Lowest face first (`s`).
+    Ascending,
+    /// Highest face first (`sd`).
+    Descending,
+}
+
+/// Chooses which individual dice of a pool count towards its total.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Selection {
+    /// Keep the N highest dice (`khN`).
+    KeepHigh(i64),
+    /// Keep the N lowest dice (`klN`).
+    KeepLow(i64),
+    /// Drop the N highest dice (`dhN`).
+    DropHigh(i64),
+    /// Drop the N lowest dice (`dlN`).
+    DropLow(i64),
 }
 
 impl Expression {
     pub fn dice(times: i64, sides: i64) -> Self {
-        Self::Dice { times, sides }
+        Self::Dice { times, sides, mods: Modifiers::default() }
+    }
+
+    pub fn dice_with(times: i64, sides: i64, mods: Modifiers) -> Self {
+        Self::Dice { times, sides, mods }
     }
 
     pub fn boxed(self) -> Box<Self> {
         Box::new(self)
     }
+
+    /// Rolls the expression using `rng`. The expression is left as it was, so
+    /// it can be sampled again for a fresh roll without parsing it again.
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Roll {
+        Roll::from_expr_with_rng(self, rng)
+    }
+
+    /// Evaluates the expression with every die on its average face, rounded
+    /// down, without rolling anything.
+    pub fn preview(&self) -> Roll {
+        Roll::preview(self)
+    }
+
+    /// Doubles the number of dice rolled by every dice term, as for a critical
+    /// hit, leaving constants untouched. Keep counts are doubled alongside the
+    /// dice they select from. Doubled counts stop at the most dice a term may
+    /// roll, as set by `set_dice_limits`.
+    pub fn critical(self) -> Self {
+        use Expression::*;
+
+        match self {
+            Num(i) => Num(i),
+            Dice { times, sides, mods } => Dice { times: doubled(times), sides, mods },
+            Counted { times, sides, mods } => Counted { times: Mul(times, 2.into()).boxed(), sides, mods },
+            Sided { times, sides, mods } => Sided { times: doubled(times), sides, mods },
+            Fudge { times } => Fudge { times: doubled(times) },
+            Keep(expr, sel) => Keep(expr.critical().boxed(), sel.doubled()),
+            Grp(expr) => Grp(expr.critical().boxed()),
+            Neg(expr) => Neg(expr.critical().boxed()),
+            Abs(expr) => Abs(expr.critical().boxed()),
+            Add(lhs, rhs) => Add(lhs.critical().boxed(), rhs.critical().boxed()),
+            Sub(lhs, rhs) => Sub(lhs.critical().boxed(), rhs.critical().boxed()),
+            Mul(lhs, rhs) => Mul(lhs.critical().boxed(), rhs.critical().boxed()),
+            Div(lhs, rhs, mode) => Div(lhs.critical().boxed(), rhs.critical().boxed(), mode),
+            Mod(lhs, rhs) => Mod(lhs.critical().boxed(), rhs.critical().boxed()),
+            Pow(lhs, rhs) => Pow(lhs.critical().boxed(), rhs.critical().boxed()),
+            Min(args) => Min(args.into_iter().map(Expression::critical).collect()),
+            Max(args) => Max(args.into_iter().map(Expression::critical).collect()),
+            Clamp(expr, lo, hi) => Clamp(expr.critical().boxed(), lo.critical().boxed(), hi.critical().boxed()),
+        }
+    }
+
+    /// Rerolls every 1 once on each dice term that doesn't already reroll low
+    /// faces. Zero-based dice have no 1s to speak of and are left as they are.
+    pub fn reroll_ones(self) -> Self {
+        use Expression::*;
+
+        match self {
+            Num(i) => Num(i),
+            Dice { times, sides, mods } if mods.zero_based || mods.reroll_once.is_some() => Dice { times, sides, mods },
+            Dice { times, sides, mods } => Dice { times, sides, mods: Modifiers { reroll_once: Some(1), ..mods } },
+            Counted { times, sides, mods } if mods.zero_based || mods.reroll_once.is_some() => Counted { times, sides, mods },
+            Counted { times, sides, mods } => Counted { times, sides, mods: Modifiers { reroll_once: Some(1), ..mods } },
+            Sided { times, sides, mods } if mods.zero_based || mods.reroll_once.is_some() => Sided { times, sides, mods },
+            Sided { times, sides, mods } => Sided { times, sides, mods: Modifiers { reroll_once: Some(1), ..mods } },
+            Fudge { times } => Fudge { times },
+            Keep(expr, sel) => Keep(expr.reroll_ones().boxed(), sel),
+            Grp(expr) => Grp(expr.reroll_ones().boxed()),
+            Neg(expr) => Neg(expr.reroll_ones().boxed()),
+            Abs(expr) => Abs(expr.reroll_ones().boxed()),
+            Add(lhs, rhs) => Add(lhs.reroll_ones().boxed(), rhs.reroll_ones().boxed()),
+            Sub(lhs, rhs) => Sub(lhs.reroll_ones().boxed(), rhs.reroll_ones().boxed()),
+            Mul(lhs, rhs) => Mul(lhs.reroll_ones().boxed(), rhs.reroll_ones().boxed()),
+            Div(lhs, rhs, mode) => Div(lhs.reroll_ones().boxed(), rhs.reroll_ones().boxed(), mode),
+            Mod(lhs, rhs) => Mod(lhs.reroll_ones().boxed(), rhs.reroll_ones().boxed()),
+            Pow(lhs, rhs) => Pow(lhs.reroll_ones().boxed(), rhs.reroll_ones().boxed()),
+            Min(args) => Min(args.into_iter().map(Expression::reroll_ones).collect()),
+            Max(args) => Max(args.into_iter().map(Expression::reroll_ones).collect()),
+            Clamp(expr, lo, hi) => Clamp(expr.reroll_ones().boxed(), lo.reroll_ones().boxed(), hi.reroll_ones().boxed()),
+        }
+    }
+
+    /// Evaluates the expression if it contains no dice, returning `None` as soon
+    /// as a dice term is encountered or the arithmetic overflows. Dividing by
+    /// zero is left for the roll to settle, since it may be an error.
+    pub fn constant(&self) -> Option<i64> {
+        use Expression::*;
+
+        match self {
+            Num(i) => Some(*i),
+            Dice { .. } | Counted { .. } | Sided { .. } | Fudge { .. } => None,
+            Keep(expr, _) | Grp(expr) => expr.constant(),
+            Neg(expr) => expr.constant()?.checked_neg(),
+            Abs(expr) => expr.constant()?.checked_abs(),
+            Add(lhs, rhs) => lhs.constant()?.checked_add(rhs.constant()?),
+            Sub(lhs, rhs) => lhs.constant()?.checked_sub(rhs.constant()?),
+            Mul(lhs, rhs) => lhs.constant()?.checked_mul(rhs.constant()?),
+            Div(lhs, rhs, mode) => match (lhs.constant()?, rhs.constant()?) {
+                (i64::MIN, -1) | (_, 0) => None,
+                (l, r) => Some(mode.unwrap_or_else(default_div_mode).divide(l, r)),
+            },
+            Mod(lhs, rhs) => match (lhs.constant()?, rhs.constant()?) {
+                (_, 0) => None,
+                (l, r) => Some(rem(l, r)),
+            },
+            Pow(lhs, rhs) => match (lhs.constant()?, rhs.constant()?) {
+                (0, exp) if exp < 0 => None,
+                (base, exp) => Some(pow(base, exp)),
+            },
+            Min(args) => args.iter().map(Expression::constant).collect::<Option<Vec<_>>>()?.into_iter().min(),
+            Max(args) => args.iter().map(Expression::constant).collect::<Option<Vec<_>>>()?.into_iter().max(),
+            Clamp(expr, lo, hi) => Some(clamp(expr.constant()?, lo.constant()?, hi.constant()?)),
+        }
+    }
+
+    /// The expected value of the expression, computed from the dice without
+    /// rolling them. Keeps, `rl`, minimums on exploding dice, rolled sides, and the caps on
+    /// explosions and rerolls are approximated, as are division, remainders, exponents, and calls, which
+    /// are applied to the averages of their operands.
+    pub fn average(&self) -> f64 {
+        use Expression::*;
+
+        match self {
+            Num(i) => *i as f64,
+            Dice { times, sides, mods } => *times as f64 * die_average(*sides, mods),
+            Counted { times, sides, mods } => times.average() * die_average(*sides, mods),
+            Sided { times, sides, mods } => {
+                *times as f64 * die_average((sides.average().round() as i64).clamp(1, dice_limits().1), mods)
+            }
+            Fudge { .. } => 0.0,
+            Keep(expr, sel) => match expr.pool_size() {
+                Some(n) if n > 0.0 => expr.average() * (sel.kept(n) / n).clamp(0.0, 1.0),
+                _ => expr.average(),
+            },
+            Grp(expr) => expr.average(),
+            Neg(expr) => -expr.average(),
+            Abs(expr) => expr.average().abs(),
+            Add(lhs, rhs) => lhs.average() + rhs.average(),
+            Sub(lhs, rhs) => lhs.average() - rhs.average(),
+            Mul(lhs, rhs) => lhs.average() * rhs.average(),
+            Div(lhs, rhs, _) | Mod(lhs, rhs) if rhs.average() == 0.0 => 0.0,
+            Div(lhs, rhs, _) => lhs.average() / rhs.average(),
+            Mod(lhs, rhs) => lhs.average() % rhs.average(),
+            Pow(lhs, rhs) => lhs.average().powf(rhs.average()),
+            Min(args) => args.iter().map(Expression::average).reduce(f64::min).unwrap_or(0.0),
+            Max(args) => args.iter().map(Expression::average).reduce(f64::max).unwrap_or(0.0),
+            Clamp(expr, lo, hi) => {
+                let (lo, hi) = (lo.average(), hi.average());
+                expr.average().max(lo.min(hi)).min(lo.max(hi))
+            }
+        }
+    }
+
+    /// The lowest total the expression can roll. Keeps, remainders, and
+    /// exponents may widen the range slightly beyond what can actually be rolled.
+    pub fn min_value(&self) -> i64 {
+        self.bounds(false).0
+    }
+
+    /// The highest total the expression can roll. Explosions are limited by
+    /// the cap on extra dice, while `rN` rerolls are assumed to escape their
+    /// threshold.
+    pub fn max_value(&self) -> i64 {
+        self.bounds(false).1
+    }
+
+    /// The lowest and highest totals of the expression. When `droppable` is set,
+    /// the expression sits under a keep modifier that may drop any of its dice,
+    /// so each dice term can also contribute nothing.
+    fn bounds(&self, droppable: bool) -> (i64, i64) {
+        use Expression::*;
+
+        if let Some(c) = self.constant() {
+            return (c, c);
+        }
+
+        let (lo, hi) = match self {
+            Num(i) => (*i, *i),
+            Dice { times, sides, mods } => dice_bounds(*times, *times, *sides, mods),
+            Counted { times, sides, mods } => {
+                let (lo, hi) = times.bounds(false);
+                let max = dice_limits().0;
+                dice_bounds(lo.clamp(0, max), hi.clamp(0, max), *sides, mods)
+            }
+            Sided { times, sides, mods } => {
+                let (lo, hi) = sides.bounds(false);
+                let max = dice_limits().1;
+                let (a, b) = dice_bounds(*times, *times, lo.clamp(1, max), mods);
+                let (c, d) = dice_bounds(*times, *times, hi.clamp(1, max), mods);
+                // sides below one roll no dice at all
+                if lo < 1 { (a.min(c).min(0), b.max(d).max(0)) } else { (a.min(c), b.max(d)) }
+            }
+            Fudge { times } => (-times, *times),
+            Keep(expr, sel) => match &**expr {
+                Dice { times, sides, mods } if mods.explode.is_none() && mods.successes.is_none() => {
+                    let kept = sel.kept(*times as f64).clamp(0.0, *times as f64) as i64;
+                    dice_bounds(kept, kept, *sides, mods)
+                }
+                Fudge { times } => {
+                    let kept = sel.kept(*times as f64).clamp(0.0, *times as f64) as i64;
+                    (-kept, kept)
+                }
+                expr => expr.bounds(true),
+            },
+            Grp(expr) => expr.bounds(droppable),
+            Neg(expr) => {
+                let (lo, hi) = expr.bounds(droppable);
+                (hi.saturating_neg(), lo.saturating_neg())
+            }
+            Abs(expr) => match expr.bounds(droppable) {
+                (lo, hi) if lo >= 0 => (lo, hi),
+                (lo, hi) if hi <= 0 => (hi.saturating_abs(), lo.saturating_abs()),
+                (lo, hi) => (0, lo.saturating_abs().max(hi)),
+            },
+            Add(lhs, rhs) => {
+                let ((a, b), (c, d)) = (lhs.bounds(droppable), rhs.bounds(droppable));
+                (a.saturating_add(c), b.saturating_add(d))
+            }
+            Sub(lhs, rhs) => {
+                let ((a, b), (c, d)) = (lhs.bounds(droppable), rhs.bounds(droppable));
+                (a.saturating_sub(d), b.saturating_sub(c))
+            }
+            Mul(lhs, rhs) => {
+                let ((a, b), (c, d)) = (lhs.bounds(droppable), rhs.bounds(droppable));
+                extremes([(a, c), (a, d), (b, c), (b, d)].map(|(x, y)| x.saturating_mul(y)))
+            }
+            Div(lhs, rhs, mode) => {
+                let mode = mode.unwrap_or_else(default_div_mode);
+                let ((a, b), (c, d)) = (lhs.bounds(droppable), rhs.bounds(droppable));
+                // quotients are most extreme at the divisors nearest zero on either side
+                let divisors = [c, d, -1, 0, 1].into_iter().filter(|n| (c..=d).contains(n));
+                extremes(divisors.flat_map(|n| [mode.divide(a, n), mode.divide(b, n)]))
+            }
+            Mod(lhs, rhs) => {
+                let ((a, b), (c, d)) = (lhs.bounds(droppable), rhs.bounds(droppable));
+                // remainders take the sign of the dividend and are smaller than the divisor
+                let limit = c.unsigned_abs().max(d.unsigned_abs()).saturating_sub(1).min(i64::MAX as u64) as i64;
+                (a.max(-limit).min(0), b.min(limit).max(0))
+            }
+            Pow(lhs, rhs) => {
+                let ((a, b), (c, d)) = (lhs.bounds(droppable), rhs.bounds(droppable));
+                let bases = [a, b, -1, 0, 1].into_iter().filter(|n| (a..=b).contains(n));
+                let exps: Vec<_> = [c, c.saturating_add(1), d.saturating_sub(1), d, -1, 0, 1]
+                    .into_iter()
+                    .filter(|n| (c..=d).contains(n))
+                    .collect();
+                extremes(bases.flat_map(|base| exps.iter().map(move |exp| pow(base, *exp))))
+            }
+            Min(args) => {
+                let bounds: Vec<_> = args.iter().map(|e| e.bounds(droppable)).collect();
+                (bounds.iter().map(|b| b.0).min().unwrap_or(0), bounds.iter().map(|b| b.1).min().unwrap_or(0))
+            }
+            Max(args) => {
+                let bounds: Vec<_> = args.iter().map(|e| e.bounds(droppable)).collect();
+                (bounds.iter().map(|b| b.0).max().unwrap_or(0), bounds.iter().map(|b| b.1).max().unwrap_or(0))
+            }
+            // clamping only ever grows with its term and either bound
+            Clamp(expr, lo, hi) => {
+                let ((a, b), (c, d), (e, g)) = (expr.bounds(droppable), lo.bounds(droppable), hi.bounds(droppable));
+                (clamp(a, c, e), clamp(b, d, g))
+            }
+        };
+
+        match (droppable, self) {
+            (true, Dice { .. } | Counted { .. } | Sided { .. } | Fudge { .. }) => (lo.min(0), hi.max(0)),
+            _ => (lo, hi),
+        }
+    }
+
+    /// The exact chance of every total the expression can roll, or `None` if
+    /// it uses modifiers that depend on the other dice (keeps, explosions, `rl`,
+    /// rolled counts or sides), has too many outcomes to enumerate, or can fail
+    /// to total at all.
+    pub fn distribution(&self) -> Option<Distribution> {
+        let mut budget = MAX_COMBINATIONS;
+        self.distribution_within(&mut budget)
+    }
+
+    fn distribution_within(&self, budget: &mut usize) -> Option<Distribution> {
+        use Expression::*;
+
+        let binary = |lhs: &Expression, rhs: &Expression, budget: &mut usize, op: &dyn Fn(i64, i64) -> Option<i64>| {
+            let lhs = lhs.distribution_within(budget)?;
+            let rhs = rhs.distribution_within(budget)?;
+            combine(&lhs, &rhs, budget, op)
+        };
+
+        match self {
+            Num(i) => Some(BTreeMap::from([(*i, 1.0)])),
+            Dice { times, sides, mods } => repeat(&die_distribution(*sides, mods)?, *times, budget),
+            Fudge { times } => repeat(&BTreeMap::from([(-1, 1.0 / 3.0), (0, 1.0 / 3.0), (1, 1.0 / 3.0)]), *times, budget),
+            Counted { .. } | Sided { .. } | Keep(..) => None,
+            Grp(expr) => expr.distribution_within(budget),
+            Neg(expr) => expr.distribution_within(budget)?.into_iter()
+                .map(|(total, p)| Some((total.checked_neg()?, p)))
+                .collect(),
+            // opposite totals land on the same absolute value
+            Abs(expr) => expr.distribution_within(budget)?.into_iter()
+                .try_fold(BTreeMap::new(), |mut dist, (total, p)| {
+                    *dist.entry(total.checked_abs()?).or_insert(0.0) += p;
+                    Some(dist)
+                }),
+            Add(lhs, rhs) => binary(lhs, rhs, budget, &i64::checked_add),
+            Sub(lhs, rhs) => binary(lhs, rhs, budget, &i64::checked_sub),
+            Mul(lhs, rhs) => binary(lhs, rhs, budget, &i64::checked_mul),
+            Div(lhs, rhs, mode) => {
+                let mode = mode.unwrap_or_else(default_div_mode);
+                binary(lhs, rhs, budget, &|l, r| match (l, r) {
+                    (i64::MIN, -1) => None,
+                    (_, 0) if strict_division() => None,
+                    (l, r) => Some(mode.divide(l, r)),
+                })
+            }
+            Mod(lhs, rhs) => binary(lhs, rhs, budget, &|l, r| match (l, r) {
+                (_, 0) if strict_division() => None,
+                (l, r) => Some(rem(l, r)),
+            }),
+            Pow(lhs, rhs) => binary(lhs, rhs, budget, &|l, r| match (l, r) {
+                (0, exp) if exp < 0 && strict_division() => None,
+                (l, r) => Some(pow(l, r)),
+            }),
+            Min(args) | Max(args) => {
+                let pick = |l: i64, r: i64| Some(if matches!(self, Min(_)) { l.min(r) } else { l.max(r) });
+                let mut args = args.iter();
+                let first = args.next()?.distribution_within(budget)?;
+                args.try_fold(first, |acc, arg| combine(&acc, &arg.distribution_within(budget)?, budget, &pick))
+            }
+            Clamp(expr, lo, hi) => {
+                let dist = expr.distribution_within(budget)?;
+                let (lo, hi) = (lo.distribution_within(budget)?, hi.distribution_within(budget)?);
+                *budget = budget.checked_sub(dist.len().checked_mul(lo.len())?.checked_mul(hi.len())?)?;
+
+                let mut out = BTreeMap::new();
+                for (l, p) in &lo {
+                    for (h, q) in &hi {
+                        for (total, r) in &dist {
+                            *out.entry(clamp(*total, *l, *h)).or_insert(0.0) += p * q * r;
+                        }
+                    }
+                }
+                Some(out)
+            }
+        }
+    }
+
+    /// An upper bound on how many dice rolling the expression samples.
+    pub fn dice_count(&self) -> i64 {
+        use Expression::*;
+
+        match self {
+            Num(_) => 0,
+            Dice { times, mods, .. } if mods.explode.is_some() => times.saturating_add(MAX_EXPLOSIONS as i64),
+            Dice { times, .. } | Fudge { times } => *times,
+            Counted { times, mods, .. } => {
+                let extra = if mods.explode.is_some() { MAX_EXPLOSIONS as i64 } else { 0 };
+                times.dice_count().saturating_add(times.max_value().clamp(0, dice_limits().0)).saturating_add(extra)
+            }
+            Sided { times, sides, mods } => {
+                let extra = if mods.explode.is_some() { MAX_EXPLOSIONS as i64 } else { 0 };
+                sides.dice_count().saturating_add(*times).saturating_add(extra)
+            }
+            Keep(expr, _) | Grp(expr) | Neg(expr) | Abs(expr) => expr.dice_count(),
+            Add(lhs, rhs) | Sub(lhs, rhs) | Mul(lhs, rhs) | Div(lhs, rhs, _) | Mod(lhs, rhs) | Pow(lhs, rhs) => {
+                lhs.dice_count().saturating_add(rhs.dice_count())
+            }
+            Min(args) | Max(args) => args.iter().map(Expression::dice_count).fold(0, i64::saturating_add),
+            Clamp(expr, lo, hi) => expr.dice_count().saturating_add(lo.dice_count()).saturating_add(hi.dice_count()),
+        }
+    }
+
+    /// How many dice a keep modifier selects from, if the expression is a pool.
+    fn pool_size(&self) -> Option<f64> {
+        match self {
+            Expression::Dice { times, .. } | Expression::Sided { times, .. } | Expression::Fudge { times } => Some(*times as f64),
+            Expression::Counted { times, .. } => Some(times.average()),
+            _ => None,
+        }
+    }
+}
+
+/// The average face a single die lands on, counting rerolls and minimums but
+/// not explosions or successes.
+pub(super) fn face_average(sides: i64, mods: &Modifiers) -> f64 {
+    die_average(sides, &Modifiers {
+        zero_based: mods.zero_based,
+        reroll_once: mods.reroll_once,
+        reroll: mods.reroll,
+        minimum: mods.minimum,
+        weights: mods.weights.clone(),
+        ..Default::default()
+    })
+}
+
+/// The expected value of a single die, or its expected net successes if its
+/// term counts them.
+fn die_average(sides: i64, mods: &Modifiers) -> f64 {
+    if mods.minimum.is_some() {
+        if let Some(faces) = die_distribution(sides, mods) {
+            return faces.iter().map(|(face, p)| *face as f64 * p).sum();
+        }
+    }
+
+    let low = if mods.zero_based { 0 } else { 1 };
+    let high = sides - 1 + low;
+    let faces = |lo: i64, hi: i64| face_share(sides, low, &mods.weights, lo.max(low), hi.min(high));
+    let chance = |Condition { cmp, target }: Condition| {
+        let (lo, hi) = match cmp {
+            Compare::Lt => (low, target.saturating_sub(1)),
+            Compare::Le => (low, target),
+            Compare::Eq => (target, target),
+            Compare::Ge => (target, high),
+            Compare::Gt => (target.saturating_add(1), high),
+        };
+        faces(lo, hi).0
+    };
+
+    if let Some(cond) = mods.successes {
+        return chance(cond) - mods.failures.map_or(0.0, chance);
+    }
+
+    let mut avg = faces(low, high).1;
+    if let Some(t) = mods.reroll {
+        let (chance, mean) = faces(t.saturating_add(1), high);
+        if chance > 0.0 {
+            avg = mean;
+        }
+    } else if let Some(t) = mods.reroll_once {
+        let (chance, mean) = faces(t.saturating_add(1), high);
+        avg = chance * mean + (1.0 - chance) * avg;
+    }
+    if mods.explode.is_some() {
+        let chance = chance(mods.explode_on.unwrap_or(Condition { cmp: Compare::Eq, target: high }));
+        if chance < 1.0 {
+            avg /= 1.0 - chance;
+        }
+    }
+    avg
+}
+
+/// The chance of each face of a single die, or of each net count of successes
+/// if its term counts them. Dice whose faces depend on other rolls aren't supported.
+fn die_distribution(sides: i64, mods: &Modifiers) -> Option<Distribution> {
+    if mods.explode.is_some() || mods.reroll_lowest || sides > MAX_WEIGHTED_SIDES {
+        return None;
+    }
+
+    let low = if mods.zero_based { 0 } else { 1 };
+    let weight = |face: i64| mods.weights.iter().find(|(f, _)| *f == face).map_or(1.0, |(_, w)| *w as f64);
+    let total: f64 = (low..low + sides).map(weight).sum();
+    let mut faces: Distribution = (low..low + sides).map(|f| (f, weight(f) / total)).collect();
+
+    if let Some(t) = mods.reroll_once {
+        let low_chance: f64 = faces.range(..=t).map(|(_, p)| p).sum();
+        faces = faces.iter()
+            .map(|(f, p)| {
+                let kept = if *f > t { *p } else { 0.0 };
+                (*f, kept + low_chance * p)
+            })
+            .collect();
+    }
+    if let Some(t) = mods.reroll {
+        let high_chance: f64 = faces.range(t + 1..).map(|(_, p)| p).sum();
+        if high_chance > 0.0 {
+            faces = faces.range(t + 1..).map(|(f, p)| (*f, p / high_chance)).collect();
+        }
+    }
+    if let Some(m) = mods.minimum {
+        let raised: f64 = faces.range(..m).map(|(_, p)| p).sum();
+        faces = faces.split_off(&m);
+        *faces.entry(m).or_insert(0.0) += raised;
+    }
+    if let Some(cond) = mods.successes {
+        let mut net = BTreeMap::from([(0, 0.0), (1, 0.0)]);
+        if mods.failures.is_some() {
+            net.insert(-1, 0.0);
+        }
+        for (face, p) in &faces {
+            let miss = mods.failures.is_some_and(|c| c.matches(*face));
+            *net.entry(i64::from(cond.matches(*face)) - i64::from(miss)).or_insert(0.0) += p;
+        }
+        faces = net;
+    }
+    Some(faces)
 }
 
-impl<'a> TryFrom<&'a str> for Expression {
-    type Error = Err<Error<&'a str>>;
+/// Combines two independent distributions with `op`, spending one unit of
+/// `budget` per pair of outcomes. Gives up if the budget runs out, the totals
+/// overflow, or there are too many distinct totals.
+fn combine(lhs: &Distribution, rhs: &Distribution, budget: &mut usize, op: &dyn Fn(i64, i64) -> Option<i64>) -> Option<Distribution> {
+    *budget = budget.checked_sub(lhs.len() * rhs.len())?;
 
-    fn try_from(value: &'a str) -> Result<Self, Self::Error> {
-        let (_, expr) = all_consuming(expr)(value)?;
-        Ok(expr)
+    let mut out = BTreeMap::new();
+    for (l, p) in lhs {
+        for (r, q) in rhs {
+            *out.entry(op(*l, *r)?).or_insert(0.0) += p * q;
+        }
+    }
+    match out.len() {
+        n if n > MAX_OUTCOMES => None,
+        _ => Some(out),
+    }
+}
+
+/// The distribution of the sum of `times` independent rolls of `die`.
+fn repeat(die: &Distribution, times: i64, budget: &mut usize) -> Option<Distribution> {
+    let mut out = BTreeMap::from([(0, 1.0)]);
+    for _ in 0..times {
+        out = combine(&out, die, budget, &i64::checked_add)?;
+    }
+    Some(out)
+}
+
+/// The lowest total of `min_times` dice and the highest total of `max_times`
+/// dice, or the range of net successes if the term counts them.
+fn dice_bounds(min_times: i64, max_times: i64, sides: i64, mods: &Modifiers) -> (i64, i64) {
+    let low = if mods.zero_based { 0 } else { 1 };
+    let high = sides - 1 + low;
+    let lowest = match mods.reroll {
+        Some(t) if t < high => t + 1,
+        _ => low,
+    }.max(mods.minimum.unwrap_or(low));
+    let most_dice = match mods.explode {
+        Some(_) => max_times.saturating_add(MAX_EXPLOSIONS as i64),
+        None => max_times,
+    };
+
+    match mods.successes {
+        Some(cond) => {
+            let (hit_always, hit_ever) = reachable(cond, lowest, high);
+            let (miss_always, miss_ever) = mods.failures.map_or((false, false), |c| reachable(c, lowest, high));
+            // each die nets between -1 and 1, and more dice push the total further from zero
+            let (die_lo, die_hi) = (i64::from(hit_always) - i64::from(miss_ever), i64::from(hit_ever) - i64::from(miss_always));
+            let lo = die_lo.saturating_mul(if die_lo < 0 { most_dice } else { min_times });
+            let hi = die_hi.saturating_mul(if die_hi > 0 { most_dice } else { min_times });
+            (lo, hi)
+        }
+        None => (min_times.saturating_mul(lowest), most_dice.saturating_mul(high)),
+    }
+}
+
+/// Whether every face from `lowest` to `high` meets `cond`, and whether any do.
+fn reachable(cond: Condition, lowest: i64, high: i64) -> (bool, bool) {
+    // faces form a contiguous range, so checking its ends covers every comparison but `=`
+    let always = cond.matches(lowest) && cond.matches(high);
+    let ever = cond.matches(lowest) || cond.matches(high)
+        || (cond.cmp == Compare::Eq && (lowest..=high).contains(&cond.target));
+    (always, ever)
+}
+
+/// The smallest and largest of `values`.
+fn extremes(values: impl IntoIterator<Item=i64>) -> (i64, i64) {
+    values.into_iter().fold((i64::MAX, i64::MIN), |(lo, hi), v| (lo.min(v), hi.max(v)))
+}
+
+/// The chance a die lands between `lo` and `hi` inclusive, along with the
+/// average face when it does.
+fn face_share(sides: i64, low: i64, weights: &[(i64, u32)], lo: i64, hi: i64) -> (f64, f64) {
+    if lo > hi {
+        return (0.0, 0.0);
+    }
+    if weights.is_empty() {
+        return ((hi - lo + 1) as f64 / sides as f64, (lo as f64 + hi as f64) / 2.0);
+    }
+
+    let weight = |face: i64| weights.iter().find(|(f, _)| *f == face).map_or(1.0, |(_, w)| *w as f64);
+    let total: f64 = (low..low + sides).map(weight).sum();
+    let share: f64 = (lo..=hi).map(weight).sum();
+    let sum: f64 = (lo..=hi).map(|face| weight(face) * face as f64).sum();
+    (share / total, sum / share)
+}
+
+/// How the result of a division is rounded to a whole number.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DivMode {
+    /// Round towards zero (`/t`). This is the default.
+    Trunc,
+    /// Round down (`/f` or `//`).
+    Floor,
+    /// Round up (`/c`).
+    Ceil,
+    /// Round to the nearest number, with halves rounded away from zero (`/r`).
+    Round,
+}
+
+static DEFAULT_DIV_MODE: OnceLock<DivMode> = OnceLock::new();
+
+/// Sets the rounding used by divisions that don't choose their own mode. Only
+/// the first call has any effect.
+pub fn set_default_div_mode(mode: DivMode) {
+    let _ = DEFAULT_DIV_MODE.set(mode);
+}
+
+pub fn default_div_mode() -> DivMode {
+    DEFAULT_DIV_MODE.get().copied().unwrap_or(DivMode::Trunc)
+}
+
+static STRICT_DIVISION: OnceLock<bool> = OnceLock::new();
+
+/// Sets whether dividing by zero, or taking a remainder by zero, is reported
+/// as an error instead of equaling zero. Only the first call has any effect.
+pub fn set_strict_division(strict: bool) {
+    let _ = STRICT_DIVISION.set(strict);
+}
+
+pub fn strict_division() -> bool {
+    STRICT_DIVISION.get().copied().unwrap_or(false)
+}
+
+impl DivMode {
+    /// Divides `lhs` by `rhs`, rounding by this mode. Division by zero always
+    /// equals zero.
+    pub fn divide(self, lhs: i64, rhs: i64) -> i64 {
+        if rhs == 0 {
+            return 0;
+        }
+
+        let (q, r) = (lhs.wrapping_div(rhs), lhs.wrapping_rem(rhs));
+        if r == 0 {
+            return q;
+        }
+        // the exact quotient is negative when the remainder and divisor differ in sign
+        let negative = (r < 0) != (rhs < 0);
+        match self {
+            DivMode::Trunc => q,
+            DivMode::Floor if negative => q - 1,
+            DivMode::Ceil if !negative => q + 1,
+            DivMode::Floor | DivMode::Ceil => q,
+            DivMode::Round if r.unsigned_abs() * 2 < rhs.unsigned_abs() => q,
+            DivMode::Round if negative => q - 1,
+            DivMode::Round => q + 1,
+        }
+    }
+}
+
+impl FromStr for DivMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "trunc" => Ok(DivMode::Trunc),
+            "floor" => Ok(DivMode::Floor),
+            "ceil" => Ok(DivMode::Ceil),
+            "round" => Ok(DivMode::Round),
+            _ => Err(format!("unknown division mode {:?}, expected trunc, floor, ceil, or round", s)),
+        }
+    }
+}
+
+/// The remainder of `lhs / rhs`, taking the sign of `lhs`. Like division, a
+/// remainder by zero equals zero.
+pub fn rem(lhs: i64, rhs: i64) -> i64 {
+    if rhs == 0 {
+        return 0;
+    }
+    lhs.wrapping_rem(rhs)
+}
+
+/// Raises `base` to `exp`, saturating at the bounds of `i64` rather than
+/// overflowing. Negative exponents round towards zero like division, and zero
+/// to a negative power follows the division by zero rule and equals zero.
+pub fn pow(base: i64, exp: i64) -> i64 {
+    match (base, exp) {
+        (1, _) => 1,
+        (-1, e) if e % 2 == 0 => 1,
+        (-1, _) => -1,
+        (_, e) if e < 0 => 0,
+        (b, e) => match u32::try_from(e) {
+            Ok(e) => b.saturating_pow(e),
+            Err(_) if b == 0 => 0,
+            Err(_) if b < 0 && e % 2 == 1 => i64::MIN,
+            Err(_) => i64::MAX,
+        },
+    }
+}
+
+/// Holds `value` between `lo` and `hi`, swapping the bounds if they're given
+/// the wrong way around.
+pub fn clamp(value: i64, lo: i64, hi: i64) -> i64 {
+    value.clamp(lo.min(hi), lo.max(hi))
+}
+
+impl TryFrom<&str> for Expression {
+    type Error = ParseError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        // overly long input is turned away before the parser does any work on it
+        if value.chars().count() > max_length() {
+            return Err(ParseError::too_long(value));
+        }
+        all_consuming(expr)(value)
+            .map(|(_, expr)| expr)
+            .map_err(|e| ParseError::new(value, e))
     }
 }
 
@@ -43,4 +888,299 @@ impl From<i64> for Box<Expression> {
     fn from(i: i64) -> Self {
         Expression::from(i).boxed()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Expression {
+        Expression::try_from(input).unwrap()
+    }
+
+    #[test]
+    fn test_divide() {
+        let tests = [
+            (7, 2, [3, 3, 4, 4]),
+            (-7, 2, [-3, -4, -3, -4]),
+            (7, -2, [-3, -4, -3, -4]),
+            (-7, -2, [3, 3, 4, 4]),
+            (5, 3, [1, 1, 2, 2]),
+            (-5, 3, [-1, -2, -1, -2]),
+            (6, 3, [2, 2, 2, 2]),
+            (7, 0, [0, 0, 0, 0]),
+            (i64::MIN, -1, [i64::MIN; 4]),
+        ];
+
+        let modes = [DivMode::Trunc, DivMode::Floor, DivMode::Ceil, DivMode::Round];
+        for (lhs, rhs, ex) in tests {
+            for (mode, ex) in modes.iter().zip(ex) {
+                assert_eq!(ex, mode.divide(lhs, rhs), "{} /{:?} {}", lhs, mode, rhs);
+            }
+        }
+    }
+
+    #[test]
+    fn test_div_mode_from_str() {
+        assert_eq!(Ok(DivMode::Floor), "floor".parse());
+        assert_eq!(Ok(DivMode::Round), " ROUND ".parse());
+        assert!("nearest".parse::<DivMode>().is_err());
+    }
+
+    #[test]
+    fn test_rem() {
+        assert_eq!(2, rem(17, 5));
+        assert_eq!(-2, rem(-17, 5));
+        assert_eq!(0, rem(17, 0));
+        assert_eq!(0, rem(i64::MIN, -1));
+    }
+
+    #[test]
+    fn test_clamp() {
+        assert_eq!(5, clamp(5, 1, 20));
+        assert_eq!(1, clamp(-3, 1, 20));
+        assert_eq!(20, clamp(25, 1, 20));
+        assert_eq!(20, clamp(25, 20, 1));
+        assert_eq!(4, clamp(9, 4, 4));
+    }
+
+    #[test]
+    fn test_pow() {
+        let tests = [
+            (2, 10, 1024),
+            (-3, 3, -27),
+            (0, 0, 1),
+            (2, 64, i64::MAX),
+            (-2, 63, i64::MIN),
+            (-2, 65, i64::MIN),
+            (2, -1, 0),
+            (-1, -3, -1),
+            (0, -2, 0),
+            (7, i64::MAX, i64::MAX),
+            (-1, i64::MAX, -1),
+        ];
+
+        for (base, exp, ex) in tests {
+            assert_eq!(ex, pow(base, exp), "{}^{}", base, exp);
+        }
+    }
+
+    #[test]
+    fn test_average() {
+        let tests = [
+            ("3d6+2", 12.5),
+            ("z10", 4.5),
+            ("d%", 50.5),
+            ("4dF + 1", 1.0),
+            ("8d10>=7", 3.2),
+            ("d6<3", 2.0 / 6.0),
+            ("10d10>=8f<=1", 2.0),
+            ("d6mi3", 4.0),
+            ("2d6mi3>=3", 2.0),
+            ("d6r1", 4.0),
+            ("2d6ro1", 2.0 * (5.0 / 6.0 * 4.0 + 3.5 / 6.0)),
+            ("d6!", 4.2),
+            ("d10!>=9", 6.875),
+            ("d6w[6:5]", 45.0 / 10.0),
+            ("4d6kh3", 10.5),
+            ("4d6dl1", 10.5),
+            ("(1d4)d6", 8.75),
+            ("2 * (d6 - 1)", 5.0),
+            ("7 / 2", 3.5),
+            ("d6 / 0", 0.0),
+            ("max(d4, d8)", 4.5),
+            ("min(d4, d8)", 2.5),
+            ("2 ^ 3", 8.0),
+            ("-2d6 + 10", 3.0),
+            ("clamp(d6, 2, 5)", 3.5),
+            ("clamp(d6, 9, 7)", 7.0),
+        ];
+
+        for (input, ex) in tests {
+            let avg = Expression::try_from(input).unwrap().average();
+            assert!((ex - avg).abs() < 1e-9, "{}: expected {}, got {}", input, ex, avg);
+        }
+    }
+
+    #[test]
+    fn test_min_max_value() {
+        let tests = [
+            ("7", (7, 7)),
+            ("7 % 3", (1, 1)),
+            ("2d6+3", (5, 15)),
+            ("d20 - d4", (-3, 19)),
+            ("2d6 * -1", (-12, -2)),
+            ("(d6-3) * (d6-3)", (-6, 9)),
+            ("d6 / 2", (0, 3)),
+            ("d6 /c 2", (1, 3)),
+            ("12 / d4", (3, 12)),
+            ("d6 / (d3-2)", (-6, 6)),
+            ("d6 / (d3-4)", (-6, 0)),
+            ("d20 % 6", (0, 5)),
+            ("(1 - d20) % 6", (-5, 0)),
+            ("d6 % (d3-2)", (0, 0)),
+            ("2 ^ d4", (2, 16)),
+            ("(d3-2) ^ 3", (-1, 1)),
+            ("(d5-3) ^ d2", (-2, 4)),
+            ("d6 ^ (d3-3)", (0, 1)),
+            ("z10", (0, 9)),
+            ("d%", (1, 100)),
+            ("4dF", (-4, 4)),
+            ("4dFkh2", (-2, 2)),
+            ("4d6kh3", (3, 18)),
+            ("4d6dl1", (3, 18)),
+            ("2d6kh5", (2, 12)),
+            ("(2d6 - d4)kh1", (-4, 12)),
+            ("8d10>=7", (0, 8)),
+            ("3d6>=1", (3, 3)),
+            ("3d6>6", (0, 0)),
+            ("3d6=4", (0, 3)),
+            ("10d10>=8f<=1", (-10, 10)),
+            ("3d6>=1f=6", (0, 3)),
+            ("3d6>6f<=6", (-3, -3)),
+            ("2d6mi3", (6, 12)),
+            ("4d6mi2kh3", (6, 18)),
+            ("d6r2", (3, 6)),
+            ("d6r9", (1, 6)),
+            ("d6ro2", (1, 6)),
+            ("d6!", (1, 6 * (1 + MAX_EXPLOSIONS as i64))),
+            ("max(d4, d8)", (1, 8)),
+            ("min(d4, 2d8)", (1, 4)),
+            ("(d4)d6", (1, 24)),
+            ("(d4-2)d6", (0, 12)),
+            ("3d(2d6)", (3, 36)),
+            ("2d(d4 - 2)", (0, 4)),
+            ("-(2d6) + 1", (-11, -1)),
+            ("-(4d6kh1)", (-6, -1)),
+            ("abs(d6 - 2)", (0, 4)),
+            ("abs(d6 - 9)", (3, 8)),
+            ("abs(2d6)", (2, 12)),
+            ("clamp(d20 + 5, 1, 20)", (6, 20)),
+            ("clamp(d20, 20, 10)", (10, 20)),
+            ("clamp(3d6, d4, 2d6)", (2, 12)),
+            ("9223372036854775807 + d6", (i64::MAX, i64::MAX)),
+            ("-9223372036854775807 - d6", (i64::MIN, i64::MIN)),
+        ];
+
+        for (input, ex) in tests {
+            let e = Expression::try_from(input).unwrap();
+            assert_eq!(ex, (e.min_value(), e.max_value()), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_distribution() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+
+        let d = Expression::try_from("2d6").unwrap().distribution().unwrap();
+        assert_eq!(11, d.len());
+        assert!(close(6.0 / 36.0, d[&7]));
+        assert!(close(1.0 / 36.0, d[&12]));
+
+        let tests = [
+            ("d6 + 2", 3, 1.0 / 6.0),
+            ("z4", 0, 0.25),
+            ("2dF", -2, 1.0 / 9.0),
+            ("d6ro1", 1, 1.0 / 36.0),
+            ("d6r2", 3, 0.25),
+            ("4d6>=5", 4, 1.0 / 81.0),
+            ("2d10>=8f<=1", -2, 0.01),
+            ("d6>=6f=6", 0, 1.0),
+            ("d6mi3", 3, 0.5),
+            ("d6ro1mi2", 2, 8.0 / 36.0),
+            ("d6w[6:5]", 6, 0.5),
+            ("max(d6, d6)", 6, 11.0 / 36.0),
+            ("min(d6, d6)", 6, 1.0 / 36.0),
+            ("d6 * d6", 36, 1.0 / 36.0),
+            ("d6 / 2", 0, 1.0 / 6.0),
+            ("d6 % 3", 0, 1.0 / 3.0),
+            ("2 ^ d2", 4, 0.5),
+            ("-d6", -6, 1.0 / 6.0),
+            ("abs(d20 - d20)", 0, 1.0 / 20.0),
+            ("abs(d4 - 2)", 1, 0.5),
+            ("clamp(d20 + 5, 1, 20)", 20, 6.0 / 20.0),
+            ("clamp(d6, 5, 2)", 2, 2.0 / 6.0),
+            ("clamp(d6, d2, 3)", 3, 4.0 / 6.0),
+        ];
+        for (input, total, ex) in tests {
+            let d = Expression::try_from(input).unwrap().distribution().unwrap();
+            assert!(close(ex, d[&total]), "{}: {:?}", input, d);
+            assert!(close(1.0, d.values().sum()), "{}: {:?}", input, d);
+        }
+
+        for input in ["4d6kh3", "3d6!", "(d4)d6", "3d(2d6)", "2d6rl", "1000d1000", "d5000", "9223372036854775807 + d6"] {
+            assert_eq!(None, Expression::try_from(input).unwrap().distribution(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_dice_count() {
+        let tests = [
+            ("3", 0),
+            ("2d6 + d20", 3),
+            ("4dF", 4),
+            ("3d6!", 3 + MAX_EXPLOSIONS as i64),
+            ("(d4)d6", 5),
+            ("3d(2d6)", 5),
+            ("max(4d6kh3, 2d8)", 6),
+            ("clamp(2d6, d4, 10)", 3),
+        ];
+
+        for (input, ex) in tests {
+            assert_eq!(ex, Expression::try_from(input).unwrap().dice_count(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_critical() {
+        let tests = [
+            ("2d6+3", "4d6+3"),
+            ("d8 + d6 - 1", "2d8 + 2d6 - 1"),
+            ("(1d6 + 2) * 2", "(2d6 + 2) * 2"),
+            ("2d20kh1 + 5", "4d20kh2 + 5"),
+            ("4d6dl1", "8d6dl2"),
+            ("4dF + 1", "8dF + 1"),
+            ("2 ^ d4", "2 ^ 2d4"),
+            ("max(2d6, 1d12 + 1)", "max(4d6, 2d12 + 1)"),
+            ("clamp(d20 + 5, 1, 20)", "clamp(2d20 + 5, 1, 20)"),
+            ("7", "7"),
+            // counts stop at the most dice a term may roll
+            ("2d6kh9223372036854775807", "4d6kh1000000"),
+            ("600000d6 + 1000000dF", "1000000d6 + 1000000dF"),
+        ];
+
+        for (input, ex) in tests {
+            assert_eq!(parse(ex), parse(input).critical(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_reroll_ones() {
+        let ones = |times, sides| Expression::dice_with(times, sides, Modifiers {
+            reroll_once: Some(1),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            Expression::Add(ones(2, 6).boxed(), 3.into()),
+            parse("2d6 + 3").reroll_ones());
+        assert_eq!(
+            Expression::Keep(Expression::Grp(Expression::Sub(ones(1, 8).boxed(), ones(1, 4).boxed()).boxed()).boxed(), Selection::KeepHigh(1)),
+            parse("(d8 - d4)kh1").reroll_ones());
+        assert_eq!(parse("z10"), parse("z10").reroll_ones());
+        assert_eq!(parse("7"), parse("7").reroll_ones());
+    }
+
+    #[test]
+    fn test_sample() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let expr = parse("4d6kh3 + 2");
+        let first = expr.sample(&mut StdRng::seed_from_u64(3));
+        let again = expr.sample(&mut StdRng::seed_from_u64(3));
+        assert_eq!(first, again);
+        assert_eq!(parse("4d6kh3 + 2"), expr);
+
+        assert_eq!(Ok(7), parse("3 + 4").sample(&mut StdRng::seed_from_u64(3)).value());
+    }
+}
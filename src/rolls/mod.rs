@@ -2,13 +2,500 @@ mod expression;
 mod parser;
 mod roll;
 
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
 use nom::Err;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use parser::expr;
-use expression::Expression;
+use expression::{clamp, default_div_mode, pow, rem, strict_division};
+
+pub use expression::{set_default_div_mode, set_strict_division, Compare, Condition, DivMode, Distribution, Explode, Expression, Modifiers, Selection, Sort};
+pub use parser::{max_length, set_max_depth, set_max_length, DEFAULT_MAX_DEPTH, DEFAULT_MAX_LENGTH};
+pub use roll::{dice_limits, set_dice_limits, set_dice_thresholds, Die, EvalError, PoolSummary, Roll, DEFAULT_MANY_DICE, DEFAULT_MAX_SIDES, DEFAULT_TOO_MANY_DICE, MAX_TIMES};
+
+/// The most times a single expression may be rolled with a repeat prefix.
+pub const MAX_REPEAT: i64 = 100;
+
+/// The most separate expressions a single message may roll. Their dice all
+/// count towards the one `MAX_MESSAGE_DICE` budget.
+pub const MAX_ROLLS: usize = 20;
+
+/// The most dice a single message may roll across every expression and repeat
+/// in it, so that a message of many huge pools can't tie up the bot.
+pub const MAX_MESSAGE_DICE: i64 = 2 * MAX_TIMES;
+
+/// How many rolls estimate a distribution that can't be calculated exactly.
+pub const STATS_SAMPLES: usize = 10_000;
+
+/// The most dice an estimated distribution may roll across all of its samples.
+const MAX_STATS_DICE: i64 = 10_000_000;
+
+/// The chance of each total an expression can roll.
+#[derive(Debug, PartialEq)]
+pub struct Stats {
+    pub distribution: Distribution,
+    /// How many rolls the distribution was estimated from, or `None` if it's exact.
+    pub samples: Option<usize>,
+}
+
+/// Why an expression failed to parse, holding on to the expression along with
+/// where parsing stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    input: String,
+    offset: usize,
+}
+
+impl ParseError {
+    fn new(input: &str, err: Err<nom::error::Error<&str>>) -> Self {
+        let offset = match err {
+            Err::Error(e) | Err::Failure(e) => input.len() - e.input.len(),
+            Err::Incomplete(_) => input.len(),
+        };
+        Self { input: input.to_string(), offset }
+    }
+
+    /// An error for input over the length limit, which stops before parsing
+    /// anything.
+    fn too_long(input: &str) -> Self {
+        Self { input: input.to_string(), offset: 0 }
+    }
+
+    /// The expression that failed to parse.
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+
+    /// The byte offset into the input where parsing stopped.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// What's left of the input from where parsing stopped.
+    pub fn remaining(&self) -> &str {
+        &self.input[self.offset..]
+    }
+
+    /// Which limit the expression went over, if that's why parsing stopped.
+    pub fn limit(&self) -> Option<Limit> {
+        if self.input.chars().count() > max_length() {
+            return Some(Limit::Length);
+        }
+        parser::exceeded_limit(self.remaining())
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (max_dice, max_sides) = dice_limits();
+        match self.limit() {
+            Some(Limit::Dice) => write!(f, "too many dice in one term at position {}, the most is {}", self.offset + 1, max_dice),
+            Some(Limit::Sides) => write!(f, "too many sides at position {}, the most is {}", self.offset + 1, max_sides),
+            Some(Limit::Length) => write!(f, "roll expression is too long, the most is {} characters", max_length()),
+            None if self.remaining().trim().is_empty() => write!(f, "roll expression ends too early"),
+            None => write!(f, "invalid roll expression at position {}", self.offset + 1),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// An owned error for callers that don't want to hold on to the input.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RollError {
+    /// The expression is not valid roll syntax. The offset is the byte
+    /// position where parsing stopped, if known.
+    Invalid { offset: Option<usize> },
+    /// The expression rolled a total too large to calculate.
+    Overflow,
+    /// The expression divided by zero while strict division is set.
+    DivisionByZero,
+}
+
+impl Display for RollError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RollError::Invalid { offset: Some(pos) } => write!(f, "invalid roll expression at position {}", pos + 1),
+            RollError::Invalid { offset: None } => write!(f, "invalid roll expression"),
+            RollError::Overflow => write!(f, "{}", EvalError::Overflow),
+            RollError::DivisionByZero => write!(f, "{}", EvalError::DivisionByZero),
+        }
+    }
+}
+
+impl std::error::Error for RollError {}
+
+impl From<EvalError> for RollError {
+    fn from(err: EvalError) -> Self {
+        match err {
+            EvalError::Overflow => RollError::Overflow,
+            EvalError::DivisionByZero => RollError::DivisionByZero,
+        }
+    }
+}
+
+/// A limit on the size of an expression, as set by `set_dice_limits` for its
+/// dice terms or `set_max_length` for the whole of it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    /// The term rolls too many dice.
+    Dice,
+    /// The term's dice have too many sides.
+    Sides,
+    /// The expression has too many characters.
+    Length,
+}
+
+/// Parses `input` and rolls it. A leading `seed:N` rolls with an RNG seeded by
+/// `N`, so the same seeded expression always rolls the same result; otherwise
+/// the roll uses `thread_rng`. A leading `preview:` rolls nothing,
+/// landing every die on its average face instead.
+pub fn parse(input: &str) -> Result<Roll, ParseError> {
+    if let (true, expr) = split_preview(input) {
+        return Expression::try_from(split_seed(expr).1).map(|e| e.preview());
+    }
+    match split_seed(input) {
+        (Some(seed), expr) => parse_with_rng(expr, &mut StdRng::seed_from_u64(seed)),
+        (None, _) => input.try_into(),
+    }
+}
+
+/// Parses and rolls each of the expressions in `input`, as split by
+/// `split_rolls`. Each is parsed on its own, so one that's malformed doesn't
+/// stop the others from rolling.
+pub fn parse_many(input: &str) -> Vec<Result<Roll, ParseError>> {
+    split_rolls(input).map(parse).collect()
+}
+
+/// Parses `input` and rolls it with the provided RNG.
+pub fn parse_with_rng<R: Rng + ?Sized>(input: &str, rng: &mut R) -> Result<Roll, ParseError> {
+    Expression::try_from(input).map(|e| e.sample(rng))
+}
+
+/// Parses `input` and rolls it as a critical hit, doubling every dice term.
+pub fn parse_critical(input: &str) -> Result<Roll, ParseError> {
+    Expression::try_from(input).map(|e| (&e.critical()).into())
+}
+
+/// Parses `input` and rolls it, rerolling any die that lands on a 1 one time.
+pub fn parse_rerolling_ones(input: &str) -> Result<Roll, ParseError> {
+    Expression::try_from(input).map(|e| (&e.reroll_ones()).into())
+}
+
+/// Like `parse_rerolling_ones`, but rolls with the provided RNG.
+pub fn parse_rerolling_ones_with_rng<R: Rng + ?Sized>(input: &str, rng: &mut R) -> Result<Roll, ParseError> {
+    Expression::try_from(input).map(|e| e.reroll_ones().sample(rng))
+}
+
+/// Computes the expected value of `input` without rolling any dice.
+pub fn average(input: &str) -> Result<f64, ParseError> {
+    Expression::try_from(split_seed(input).1).map(|e| e.average())
+}
+
+/// An upper bound on how many dice rolling `input` samples, without rolling it.
+pub fn dice_count(input: &str) -> Result<i64, ParseError> {
+    Expression::try_from(split_seed(input).1).map(|e| e.dice_count())
+}
+
+/// Computes the lowest and highest totals `input` can roll without rolling it.
+pub fn range(input: &str) -> Result<(i64, i64), ParseError> {
+    Expression::try_from(split_seed(input).1).map(|e| (e.min_value(), e.max_value()))
+}
+
+/// Calculates the distribution of `input`'s totals, falling back to an
+/// estimate from rolling it many times with `rng` when there are too many
+/// outcomes or the dice depend on each other. Rolls that overflow are left out
+/// of estimates.
+pub fn stats<R: Rng + ?Sized>(input: &str, rng: &mut R) -> Result<Stats, ParseError> {
+    let expr = Expression::try_from(split_seed(input).1)?;
+    if let Some(distribution) = expr.distribution() {
+        return Ok(Stats { distribution, samples: None });
+    }
+
+    let samples = (MAX_STATS_DICE / expr.dice_count().max(1)).clamp(1, STATS_SAMPLES as i64) as usize;
+    let mut counts = BTreeMap::new();
+    for _ in 0..samples {
+        if let Ok(total) = expr.sample(rng).value() {
+            *counts.entry(total).or_insert(0usize) += 1;
+        }
+    }
+    Ok(Stats {
+        distribution: counts.into_iter().map(|(total, n)| (total, n as f64 / samples as f64)).collect(),
+        samples: Some(samples),
+    })
+}
+
+/// Checks that `input` is a valid roll expression without rolling any dice. A
+/// leading seed is allowed, as with `parse`.
+pub fn validate(input: &str) -> Result<(), ParseError> {
+    Expression::try_from(split_seed(input).1).map(|_| ())
+}
+
+/// Splits a seed prefix like `seed:1234` off of `input`, returning the seed
+/// and the remaining expression.
+pub fn split_seed(input: &str) -> (Option<u64>, &str) {
+    let trimmed = input.trim_start();
+    let rest = match trimmed.get(..5) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("seed:") => &trimmed[5..],
+        _ => return (None, input),
+    };
+    let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    match rest[..digits].parse() {
+        Ok(seed) => (Some(seed), rest[digits..].trim_start()),
+        Err(_) => (None, input),
+    }
+}
+
+/// Splits a `preview:` prefix off of `input`, returning whether it was there
+/// and the remaining expression.
+pub fn split_preview(input: &str) -> (bool, &str) {
+    let trimmed = input.trim_start();
+    match trimmed.get(..8) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("preview:") => (true, trimmed[8..].trim_start()),
+        _ => (false, input),
+    }
+}
+
+/// Splits `input` into its separate roll expressions, one per line or per
+/// `;`, skipping any that are blank. Labels are split off afterwards, so they
+/// can't contain a `;` either. Each expression is only checked on its own, so
+/// callers limit the dice of every expression together.
+pub fn split_rolls(input: &str) -> impl Iterator<Item=&str> {
+    input.split(['\n', ';']).filter(|s| !s.trim().is_empty())
+}
+
+/// Splits a trailing label off of a single roll expression: either everything
+/// after a `#`, or a quoted string at the end. Empty labels are dropped.
+pub fn split_label(input: &str) -> (&str, Option<&str>) {
+    let (expr, label) = match input.split_once('#') {
+        Some(split) => split,
+        None => match input.trim_end().strip_suffix('"').and_then(|s| s.rsplit_once('"')) {
+            Some(split) => split,
+            None => (input, ""),
+        },
+    };
+    let label = label.trim();
+    (expr.trim(), if label.is_empty() { None } else { Some(label) })
+}
+
+/// Splits a repeat prefix like `6x` off of `input`, returning how many times to
+/// roll the rest of the expression. Expressions without a prefix roll once.
+/// Counts are not checked against `MAX_REPEAT` so callers can report it.
+pub fn split_repeat(input: &str) -> (i64, &str) {
+    let trimmed = input.trim_start();
+    let digits = trimmed.len() - trimmed.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    match trimmed[digits..].strip_prefix(['x', 'X']) {
+        Some(expr) if digits > 0 => (trimmed[..digits].parse().unwrap_or(i64::MAX), expr.trim_start()),
+        _ => (1, input),
+    }
+}
+
+/// Parses and rolls `input` with the provided RNG, returning the total along
+/// with the formatted breakdown of every die.
+///
+/// The breakdown is marked up the way the bot sends it to Telegram: a die's
+/// highest face is `*bold*` and its lowest `_italic_`, as in Telegram's
+/// MarkdownV1, and dropped dice are struck through with `~~`. Escape or strip
+/// the markup to show it anywhere else.
+///
+/// ```
+/// use rand::rngs::StdRng;
+/// use rand::SeedableRng;
+///
+/// use tg_dice_goblin::rolls;
+///
+/// let (total, breakdown) = rolls::parse_and_roll("2d6 + 3", &mut StdRng::seed_from_u64(7)).unwrap();
+/// assert_eq!(6, total);
+/// assert_eq!("[_1_, 2] + 3", breakdown);
+/// ```
+pub fn parse_and_roll<R: Rng + ?Sized>(input: &str, rng: &mut R) -> Result<(i64, String), RollError> {
+    roll_with(input, rng, strict_division())
+}
+
+/// Parses and rolls `input` like `parse_and_roll`, where dividing by zero is
+/// an error if `strict` is set.
+fn roll_with<R: Rng + ?Sized>(input: &str, rng: &mut R, strict: bool) -> Result<(i64, String), RollError> {
+    let roll = parse_with_rng(input, rng)
+        .map_err(|e| RollError::Invalid { offset: Some(e.offset()) })?;
+    let total = roll.total(strict)?;
+    Ok((total, roll.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_parse_and_roll() {
+        assert_eq!(Ok((6, "[_1_, 2] + 3".to_string())), parse_and_roll("2d6 + 3", &mut StdRng::seed_from_u64(7)));
+        assert_eq!(Ok((6, "[_1_, 2, ~~1~~] + 3".to_string())), parse_and_roll("3d6kh2 + 3", &mut StdRng::seed_from_u64(7)));
+
+        assert_eq!(Ok((7, "7".to_string())), parse_and_roll("7", &mut StdRng::seed_from_u64(0)));
+    }
+
+    #[test]
+    fn test_stats() {
+        let exact = stats("2d6", &mut StdRng::seed_from_u64(3)).unwrap();
+        assert_eq!(None, exact.samples);
+        assert_eq!(11, exact.distribution.len());
+
+        let estimated = stats("4d6kh3", &mut StdRng::seed_from_u64(3)).unwrap();
+        assert_eq!(Some(STATS_SAMPLES), estimated.samples);
+        assert!(estimated.distribution.keys().all(|total| (3..=18).contains(total)));
+        assert!((1.0 - estimated.distribution.values().sum::<f64>()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_split_seed() {
+        let tests = [
+            ("seed:1234 3d6", (Some(1234), "3d6")),
+            ("  SEED:7 d20", (Some(7), "d20")),
+            ("seed: 3d6", (None, "seed: 3d6")),
+            ("seed:99999999999999999999 3d6", (None, "seed:99999999999999999999 3d6")),
+            ("3d6", (None, "3d6")),
+        ];
+
+        for (input, ex) in tests {
+            assert_eq!(ex, split_seed(input), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_split_preview() {
+        assert_eq!((true, "3d6"), split_preview("preview: 3d6"));
+        assert_eq!((true, "seed:1 d20"), split_preview("  PREVIEW:seed:1 d20"));
+        assert_eq!((false, "preview 3d6"), split_preview("preview 3d6"));
+        assert_eq!((false, "3d6"), split_preview("3d6"));
+    }
+
+    #[test]
+    fn test_parse_preview() {
+        let roll = parse("preview: 3d6 + 2").unwrap();
+        assert_eq!("[3, 3, 3] + 2", roll.to_string());
+        assert_eq!(Ok(11), roll.value());
+        // the seed has nothing left to decide
+        assert_eq!(Ok(3), parse("preview: seed:5 d6").unwrap().value());
+        assert!(parse("preview: 3dd6").is_err());
+    }
+
+    #[test]
+    fn test_parse_seeded() {
+        let a = parse("seed:1234 3d6 + 2d20").unwrap();
+        let b = parse("seed:1234 3d6 + 2d20").unwrap();
+        let c = parse_with_rng("3d6 + 2d20", &mut StdRng::seed_from_u64(1234)).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a, c);
+        assert!(validate("seed:1 d6").is_ok());
+        assert!(parse("seed:1").is_err());
+    }
+
+    #[test]
+    fn test_split_repeat() {
+        let tests = [
+            ("6x 4d6", (6, "4d6")),
+            ("3X2d8+1", (3, "2d8+1")),
+            ("99999999999999999999x d6", (i64::MAX, "d6")),
+            ("4d6", (1, "4d6")),
+            ("x4d6", (1, "x4d6")),
+        ];
+
+        for (input, ex) in tests {
+            assert_eq!(ex, split_repeat(input), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_split_rolls() {
+        assert_eq!(vec!["2d6", " d20", " 3d4+1"], split_rolls("2d6; d20; 3d4+1").collect::<Vec<_>>());
+        assert_eq!(vec!["2d6 # fire", "d20 ", " d4"], split_rolls("2d6 # fire\nd20 ;; d4\n\n").collect::<Vec<_>>());
+        assert_eq!(0, split_rolls(" ; \n").count());
+    }
+
+    #[test]
+    fn test_parse_many() {
+        let rolls = parse_many("seed:1 2d6; 3dd6\n7");
+        assert_eq!(3, rolls.len());
+        assert_eq!(parse("seed:1 2d6").as_ref(), rolls[0].as_ref());
+        assert!(rolls[1].is_err());
+        assert_eq!(Ok(7), rolls[2].as_ref().unwrap().value());
+    }
+
+    #[test]
+    fn test_split_label() {
+        let tests = [
+            ("2d6 # fire damage", ("2d6", Some("fire damage"))),
+            ("2d6+1 \"sneak attack\"", ("2d6+1", Some("sneak attack"))),
+            ("d20#", ("d20", None)),
+            ("d20 \"\"", ("d20", None)),
+            (" d20 ", ("d20", None)),
+            ("d20 \"unterminated", ("d20 \"unterminated", None)),
+        ];
+
+        for (input, ex) in tests {
+            assert_eq!(ex, split_label(input), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let err = parse("2d6 + 3dd6").unwrap_err();
+        assert_eq!("2d6 + 3dd6", err.input());
+        assert_eq!(7, err.offset());
+        assert_eq!("dd6", err.remaining());
+        assert_eq!(None, err.limit());
+        assert_eq!("invalid roll expression at position 8", err.to_string());
+
+        let err = validate("1 + 2000000d6").unwrap_err();
+        assert_eq!((4, Some(Limit::Dice)), (err.offset(), err.limit()));
+        assert_eq!("too many dice in one term at position 5, the most is 1000000", err.to_string());
+
+        let err = validate("99999999999dF").unwrap_err();
+        assert_eq!((0, Some(Limit::Dice)), (err.offset(), err.limit()));
+        assert!(dice_count(&format!("{}dF", MAX_TIMES)).is_ok());
+
+        let err = validate("d99999999").unwrap_err();
+        assert_eq!(Some(Limit::Sides), err.limit());
+        assert_eq!("too many sides at position 1, the most is 1000000", err.to_string());
+
+        assert_eq!("roll expression ends too early", validate("").unwrap_err().to_string());
+
+        let long = "1+".repeat(DEFAULT_MAX_LENGTH / 2) + "1";
+        let err = validate(&long).unwrap_err();
+        assert_eq!((0, Some(Limit::Length)), (err.offset(), err.limit()));
+        assert_eq!(format!("roll expression is too long, the most is {} characters", DEFAULT_MAX_LENGTH), err.to_string());
+        assert!(validate(&long[2..]).is_ok());
+    }
+
+    #[test]
+    fn test_parse_critical() {
+        assert_eq!(Ok(4), parse_critical("2d6kh9223372036854775807").map(|r| r.evaluate_all().len()));
+        assert!(parse_critical(&format!("{}dF", MAX_TIMES)).is_ok());
+        assert_eq!(Some(Limit::Dice), parse_critical("4611686018427387904dF").unwrap_err().limit());
+    }
+
+    #[test]
+    fn test_parse_and_roll_invalid() {
+        let err = parse_and_roll("3dd6", &mut StdRng::seed_from_u64(0)).unwrap_err();
+        assert_eq!(RollError::Invalid { offset: Some(1) }, err);
+        assert_eq!("invalid roll expression at position 2", err.to_string());
+
+        let err = parse_and_roll("9999999999 * 9999999999", &mut StdRng::seed_from_u64(0)).unwrap_err();
+        assert_eq!(RollError::Overflow, err);
+    }
+
+    #[test]
+    fn test_parse_and_roll_strict() {
+        assert_eq!(Ok((0, "7 / 0".to_string())), roll_with("7 / 0", &mut StdRng::seed_from_u64(0), false));
 
-pub use roll::Roll;
-pub type Error<'a> = Err<nom::error::Error<&'a str>>;
+        let err = roll_with("7 / 0", &mut StdRng::seed_from_u64(0), true).unwrap_err();
+        assert_eq!(RollError::DivisionByZero, err);
+        assert_eq!("division by zero", err.to_string());
 
-pub fn parse(input: &str) -> Result<Roll, Error> {
-    input.try_into()
-}
\ No newline at end of file
+        let err = roll_with("9999999999 * 9999999999", &mut StdRng::seed_from_u64(0), true).unwrap_err();
+        assert_eq!(RollError::Overflow, err);
+    }
+}
@@ -1,13 +1,18 @@
 extern crate nom;
 
-use super::Expression;
+use std::cell::Cell;
+use std::sync::OnceLock;
+
+use super::{Compare, Condition, DivMode, Explode, Expression, Limit, Modifiers, Selection, Sort};
+use super::roll::{dice_limits, MAX_WEIGHTED_SIDES};
 
 use nom::{
+    Err,
     IResult,
-    error::ParseError,
+    error::{Error, ErrorKind, ParseError},
     branch::alt,
-    bytes::complete::tag,
-    multi::many0,
+    bytes::complete::{tag, tag_no_case},
+    multi::{fold_many0, many0, separated_list1},
     character::complete::{
         char,
         digit1,
@@ -16,12 +21,16 @@ use nom::{
     },
     combinator::{
         map,
+        map_opt,
         map_res,
         opt,
+        value,
+        verify,
     },
     sequence::{
         delimited,
         pair,
+        preceded,
         separated_pair,
         tuple,
     },
@@ -30,10 +39,25 @@ use nom::{
 /*
 
 expr    -> factor ( ( "-" | "+" ) factor )* ;
-factor  -> primary ( ( "/" | "*" ) primary )* ;
-primary -> dice | number | group ;
+factor  -> power ( ( "//" | "/" round? | "*" | "%" ) power )* ;
+round   -> "t" | "f" | "c" | "r" ;
+power   -> primary ( "^" power )? ;
+primary -> call | pool | neg | number ;
+neg     -> "-" primary ;
+call    -> ( "min" | "max" | "abs" | "clamp" ) "(" expr ( "," expr )* ")" ;
+pool    -> dice adv | ( dice | sided | fudge | group die? ) keep? ;
+adv     -> "adv" | "dis" ;
+keep    -> ( "kh" | "kl" | "dh" | "dl" ) INT? ;
 group   -> "(" expr ")" ;
-dice    -> INT("d" | "D")INT | ("d" | "D")INT ;
+dice    -> INT? die ;
+die     -> ( "d" | "D" | "z" | "Z" ) sides mod* ;
+sided   -> INT? ( "d" | "D" | "z" | "Z" ) group mod* ;
+sides   -> INT | "%" ;
+mod     -> "rl" | "ro" INT? | "r" INT? | "!!" success? | "!" success? | "mi" INT | weights | success | failure | "sd" | "s" ;
+success -> ( ">=" | ">" | "<=" | "<" | "=" ) INT ;
+failure -> "f" success ;
+fudge   -> INT? ( "d" | "D" ) ( "F" | "f" ) ;
+weights -> "w[" INT ":" INT ( "," INT ":" INT )* "]" ;
 number  -> -INT | INT ;
 
 */
@@ -43,6 +67,16 @@ fn int(input: &str) -> IResult<&str, i64> {
     map_res(digit1, str::parse)(input)
 }
 
+/// Parses an integer, saturating rather than failing when it's too large, so
+/// that oversized dice terms are reported as too large rather than malformed.
+fn saturating_int(input: &str) -> IResult<&str, i64> {
+    map(digit1, |d: &str| d.parse().unwrap_or(i64::MAX))(input)
+}
+
+fn times(input: &str) -> IResult<&str, i64> {
+    saturating_int(input)
+}
+
 fn number(input: &str) -> IResult<&str, Expression> {
     map(
         tuple((opt(tag("-")), int)),
@@ -54,33 +88,390 @@ fn number(input: &str) -> IResult<&str, Expression> {
 }
 
 fn dice(input: &str) -> IResult<&str, Expression> {
+    let (rest, (times, (sides, mods))) = pair(opt(times), die)(input)?;
+    let times = times.unwrap_or(1);
+    // terms with too many dice fail outright, rather than parsing as something else
+    if times > dice_limits().0 {
+        return Err(Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+    }
+    Ok((rest, Expression::dice_with(times, sides, mods)))
+}
+
+/// Everything in a dice term after the count: its kind, sides, and modifiers.
+/// Spaces are allowed on either side of the kind, as in `2 d 6`.
+fn die(input: &str) -> IResult<&str, (i64, Modifiers)> {
+    let (rest, (kind, sides)) = pair(preceded(multispace0, one_of("dDzZ")), preceded(multispace0, sides))(input)?;
+    // like too many dice, too many sides fail outright
+    if sides > dice_limits().1 {
+        return Err(Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+    }
+
+    let zero_based = matches!(kind, 'z' | 'Z');
     map(
-        separated_pair(opt(int), one_of("dD"), int),
-        |(times, sides)| Expression::dice(times.unwrap_or(1), sides),
+        verify(
+            modifiers,
+            move |mods| valid_weights(zero_based, sides, &mods.weights)
+                && mods.minimum.is_none_or(|m| valid_face(zero_based, sides, m))
+                && mods.explode_on.is_none_or(|c| valid_explosion(zero_based, sides, c))
+                // failures only subtract from a count of successes
+                && (mods.failures.is_none() || mods.successes.is_some()),
+        ),
+        move |mods| (sides, Modifiers { zero_based, ..mods }.clamp_rerolls(sides)),
+    )(rest)
+}
+
+/// Which limit the dice term at the start of `input` goes over, if any. Terms
+/// over a limit fail to parse right where they start, so this is given what's
+/// left of an expression that failed. Fate dice (`NdF`) have no sides to go
+/// over, only too many dice.
+pub(super) fn exceeded_limit(input: &str) -> Option<Limit> {
+    let die = pair(preceded(multispace0, one_of("dDzZ")), preceded(multispace0, opt(saturating_int)));
+    let (_, (times, (_, sides))) = pair(opt(saturating_int), die)(input.trim_start()).ok()?;
+    let (max_times, max_sides) = dice_limits();
+    match (times, sides) {
+        (_, Some(sides)) if sides > max_sides => Some(Limit::Sides),
+        (Some(times), _) if times > max_times => Some(Limit::Dice),
+        _ => None,
+    }
+}
+
+fn fudge(input: &str) -> IResult<&str, Expression> {
+    let (rest, (times, _, _)) = tuple((opt(times), preceded(multispace0, one_of("dD")), preceded(multispace0, one_of("fF"))))(input)?;
+    let times = times.unwrap_or(1);
+    if times > dice_limits().0 {
+        return Err(Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+    }
+    Ok((rest, Expression::Fudge { times }))
+}
+
+/// Percentile dice (`d%`) are shorthand for 100 sides. Dice need at least one
+/// side to roll, so `d0` is rejected.
+fn sides(input: &str) -> IResult<&str, i64> {
+    alt((verify(saturating_int, |s| *s > 0), value(100, char('%'))))(input)
+}
+
+#[derive(Clone)]
+enum Modifier {
+    RerollLowest,
+    RerollOnce(i64),
+    Reroll(i64),
+    Explode(Explode, Option<Condition>),
+    Minimum(i64),
+    Successes(Condition),
+    Failures(Condition),
+    Weights(Vec<(i64, u32)>),
+    Sort(Sort),
+}
+
+fn modifiers(input: &str) -> IResult<&str, Modifiers> {
+    fold_many0(
+        alt((
+            value(Modifier::RerollLowest, tag_no_case("rl")),
+            map(preceded(tag_no_case("ro"), opt(int)), |n| Modifier::RerollOnce(n.unwrap_or(1))),
+            map(preceded(tag_no_case("r"), opt(int)), |n| Modifier::Reroll(n.unwrap_or(1))),
+            map(preceded(tag("!!"), opt(condition)), |c| Modifier::Explode(Explode::Compound, c)),
+            map(preceded(char('!'), opt(condition)), |c| Modifier::Explode(Explode::Each, c)),
+            map(preceded(tag_no_case("mi"), int), Modifier::Minimum),
+            map(weights, Modifier::Weights),
+            map(condition, Modifier::Successes),
+            map(preceded(tag_no_case("f"), condition), Modifier::Failures),
+            value(Modifier::Sort(Sort::Descending), tag_no_case("sd")),
+            value(Modifier::Sort(Sort::Ascending), tag_no_case("s")),
+        )),
+        Modifiers::default,
+        |mut mods, m| {
+            match m {
+                Modifier::RerollLowest => mods.reroll_lowest = true,
+                Modifier::RerollOnce(n) => mods.reroll_once = Some(n),
+                Modifier::Reroll(n) => mods.reroll = Some(n),
+                Modifier::Explode(e, c) => {
+                    mods.explode = Some(e);
+                    mods.explode_on = c;
+                }
+                Modifier::Minimum(m) => mods.minimum = Some(m),
+                Modifier::Successes(c) => mods.successes = Some(c),
+                Modifier::Failures(c) => mods.failures = Some(c),
+                Modifier::Weights(w) => mods.weights = w,
+                Modifier::Sort(s) => mods.sort = Some(s),
+            }
+            mods
+        },
+    )(input)
+}
+
+fn weights(input: &str) -> IResult<&str, Vec<(i64, u32)>> {
+    delimited(
+        pair(tag_no_case("w"), char('[')),
+        separated_list1(
+            ws(char(',')),
+            separated_pair(int, ws(char(':')), map_res(int, u32::try_from)),
+        ),
+        char(']'),
+    )(input)
+}
+
+fn condition(input: &str) -> IResult<&str, Condition> {
+    map(
+        pair(
+            alt((
+                value(Compare::Ge, tag(">=")),
+                value(Compare::Le, tag("<=")),
+                value(Compare::Gt, char('>')),
+                value(Compare::Lt, char('<')),
+                value(Compare::Eq, char('=')),
+            )),
+            int,
+        ),
+        |(cmp, target)| Condition { cmp, target },
     )(input)
 }
 
+/// Weighted faces must exist on the die and carry a positive weight.
+fn valid_weights(zero_based: bool, sides: i64, weights: &[(i64, u32)]) -> bool {
+    if weights.is_empty() {
+        return true;
+    }
+
+    (1..=MAX_WEIGHTED_SIDES).contains(&sides)
+        && weights.iter().all(|(face, weight)| *weight > 0 && valid_face(zero_based, sides, *face))
+}
+
+/// Whether `face` is one of the die's faces.
+fn valid_face(zero_based: bool, sides: i64, face: i64) -> bool {
+    let low = if zero_based { 0 } else { 1 };
+    (low..low + sides).contains(&face)
+}
+
+/// Explosions must be triggered by at least one of the die's faces, so
+/// `d6!>=7` is rejected rather than never exploding.
+fn valid_explosion(zero_based: bool, sides: i64, cond: Condition) -> bool {
+    let low = if zero_based { 0 } else { 1 };
+    // faces form a contiguous range, so checking its ends covers every comparison but `=`
+    cond.matches(low) || cond.matches(low + sides - 1)
+        || (cond.cmp == Compare::Eq && valid_face(zero_based, sides, cond.target))
+}
+
+/// How deeply groups, calls, and exponents may nest when no limit is set.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+static MAX_DEPTH: OnceLock<usize> = OnceLock::new();
+
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Sets how deeply expressions may nest before parsing fails. Only the first
+/// call has any effect.
+pub fn set_max_depth(depth: usize) {
+    let _ = MAX_DEPTH.set(depth);
+}
+
+fn max_depth() -> usize {
+    MAX_DEPTH.get().copied().unwrap_or(DEFAULT_MAX_DEPTH)
+}
+
+/// The most characters an expression may have when no limit is set.
+pub const DEFAULT_MAX_LENGTH: usize = 1000;
+
+static MAX_LENGTH: OnceLock<usize> = OnceLock::new();
+
+/// Sets the most characters an expression may have, so overly long ones are
+/// rejected before they're parsed at all. Only the first call has any effect.
+pub fn set_max_length(len: usize) {
+    let _ = MAX_LENGTH.set(len);
+}
+
+/// The limit set by `set_max_length`, or the default.
+pub fn max_length() -> usize {
+    MAX_LENGTH.get().copied().unwrap_or(DEFAULT_MAX_LENGTH)
+}
+
+/// Restores the nesting depth when a nested parser returns.
+struct DepthGuard(usize);
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        DEPTH.with(|d| d.set(self.0));
+    }
+}
+
+/// Runs `inner` one level deeper, failing outright past the maximum depth so
+/// that pathological input can't overflow the stack while parsing or rolling.
+fn nested<'a, O>(mut inner: impl FnMut(&'a str) -> IResult<&'a str, O>) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    move |input| {
+        let depth = DEPTH.with(Cell::get);
+        if depth >= max_depth() {
+            return Err(Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+        }
+        let _guard = DepthGuard(depth);
+        DEPTH.with(|d| d.set(depth + 1));
+        inner(input)
+    }
+}
+
 fn group(input: &str) -> IResult<&str, Expression> {
     map(
-        delimited(char('('), expr, char(')')),
+        delimited(char('('), nested(expr), char(')')),
         |e| Expression::Grp(e.boxed()),
     )(input)
 }
 
-fn primary(input: &str) -> IResult<&str, Expression> { ws(alt((dice, number, group)))(input) }
+/// A group on its own, or a dice term rolling as many dice as the group's
+/// result (`(1+2)d6`). Counts without any dice are resolved immediately.
+fn grouped(input: &str) -> IResult<&str, Expression> {
+    map(
+        pair(group, opt(die)),
+        |(grp, die)| match die {
+            None => grp,
+            Some((sides, mods)) => match grp.constant() {
+                Some(times) if (0..=dice_limits().0).contains(&times) => Expression::dice_with(times, sides, mods),
+                _ => Expression::Counted { times: grp.boxed(), sides, mods },
+            },
+        },
+    )(input)
+}
+
+/// A dice term rolling dice with as many sides as the group's result
+/// (`3d(2d6)`). Sides without any dice are resolved immediately. Modifiers
+/// naming specific faces can't be checked against rolled sides, so they're
+/// not allowed.
+fn sided(input: &str) -> IResult<&str, Expression> {
+    let (rest, (times, kind, sides)) = tuple((
+        opt(times),
+        preceded(multispace0, one_of("dDzZ")),
+        preceded(multispace0, group),
+    ))(input)?;
+    let times = times.unwrap_or(1);
+    if times > dice_limits().0 {
+        return Err(Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+    }
+
+    let (rest, mods) = verify(
+        modifiers,
+        |mods: &Modifiers| mods.weights.is_empty() && mods.minimum.is_none() && mods.explode_on.is_none()
+            && (mods.failures.is_none() || mods.successes.is_some()),
+    )(rest)?;
+    let mods = Modifiers { zero_based: matches!(kind, 'z' | 'Z'), ..mods };
+    let expr = match sides.constant() {
+        Some(s) if (1..=dice_limits().1).contains(&s) => Expression::dice_with(times, s, mods.clamp_rerolls(s)),
+        _ => Expression::Sided { times, sides: sides.boxed(), mods },
+    };
+    Ok((rest, expr))
+}
+
+fn selection(input: &str) -> IResult<&str, Selection> {
+    alt((
+        map(preceded(tag_no_case("kh"), opt(int)), |n| Selection::KeepHigh(n.unwrap_or(1))),
+        map(preceded(tag_no_case("kl"), opt(int)), |n| Selection::KeepLow(n.unwrap_or(1))),
+        map(preceded(tag_no_case("dh"), opt(int)), |n| Selection::DropHigh(n.unwrap_or(1))),
+        map(preceded(tag_no_case("dl"), opt(int)), |n| Selection::DropLow(n.unwrap_or(1))),
+    ))(input)
+}
+
+/// Rolling a single die with advantage (`d20 adv`) rolls it twice and keeps
+/// the higher, while disadvantage (`d20 dis`) keeps the lower.
+fn advantage(input: &str) -> IResult<&str, Expression> {
+    map_opt(
+        pair(dice, preceded(multispace0, alt((
+            value(Selection::KeepHigh(1), tag_no_case("adv")),
+            value(Selection::KeepLow(1), tag_no_case("dis")),
+        )))),
+        |(die, sel)| match die {
+            Expression::Dice { times: 1, sides, mods } => {
+                Some(Expression::Keep(Expression::Dice { times: 2, sides, mods }.boxed(), sel))
+            }
+            _ => None,
+        },
+    )(input)
+}
+
+fn pool(input: &str) -> IResult<&str, Expression> {
+    alt((
+        advantage,
+        map(
+            pair(alt((dice, sided, fudge, grouped)), opt(selection)),
+            |(e, sel)| match sel {
+                Some(sel) => Expression::Keep(e.boxed(), sel),
+                None => e,
+            },
+        ),
+    ))(input)
+}
+
+/// A function applied to its arguments. `abs` takes exactly one, and `clamp`
+/// exactly three.
+fn call(input: &str) -> IResult<&str, Expression> {
+    map_opt(
+        pair(
+            alt((tag_no_case("min"), tag_no_case("max"), tag_no_case("abs"), tag_no_case("clamp"))),
+            delimited(char('('), nested(separated_list1(char(','), expr)), char(')')),
+        ),
+        |(name, mut args)| match name.to_ascii_lowercase().as_str() {
+            "min" => Some(Expression::Min(args)),
+            "max" => Some(Expression::Max(args)),
+            "abs" if args.len() == 1 => args.pop().map(|arg| Expression::Abs(arg.boxed())),
+            "clamp" if args.len() == 3 => {
+                let (hi, lo, expr) = (args.pop()?, args.pop()?, args.pop()?);
+                Some(Expression::Clamp(expr.boxed(), lo.boxed(), hi.boxed()))
+            }
+            _ => None,
+        },
+    )(input)
+}
+
+fn primary(input: &str) -> IResult<&str, Expression> { ws(alt((call, pool, neg, number)))(input) }
+
+/// Negates the term that follows, binding tighter than any operator, so
+/// `-d6^2` is `(-d6)^2` just like `-2^2` is `(-2)^2`. Negated numbers are
+/// folded into a single number.
+fn neg(input: &str) -> IResult<&str, Expression> {
+    map(
+        preceded(char('-'), nested(primary)),
+        |e| match e {
+            Expression::Num(i) if i != i64::MIN => Expression::Num(-i),
+            e => Expression::Neg(e.boxed()),
+        },
+    )(input)
+}
+
+/// Exponents are right-associative, so `2^3^2` is `2^(3^2)`.
+fn power(input: &str) -> IResult<&str, Expression> {
+    map(
+        pair(primary, opt(preceded(char('^'), nested(power)))),
+        |(lhs, rhs)| match rhs {
+            Some(rhs) => Expression::Pow(lhs.boxed(), rhs.boxed()),
+            None => lhs,
+        },
+    )(input)
+}
+
+fn div_mode(input: &str) -> IResult<&str, DivMode> {
+    alt((
+        value(DivMode::Trunc, one_of("tT")),
+        value(DivMode::Floor, one_of("fF")),
+        value(DivMode::Ceil, one_of("cC")),
+        value(DivMode::Round, one_of("rR")),
+    ))(input)
+}
 
 fn factor(input: &str) -> IResult<&str, Expression> {
     let (rem, (lhs, rhss)) = pair(
-        primary,
+        power,
         many0(pair(
-            one_of("/*"),
-            primary,
+            alt((
+                // floor division is shorthand for `/f`
+                value(('/', Some(DivMode::Floor)), tag("//")),
+                map(preceded(char('/'), opt(div_mode)), |mode| ('/', mode)),
+                map(one_of("*%"), |o| (o, None)),
+            )),
+            power,
         )),
     )(input)?;
 
-    let out = rhss.into_iter().fold(lhs, |out, (o, rhs)| match o {
-        '/' => Expression::Div(out.boxed(), rhs.boxed()),
+    let out = rhss.into_iter().fold(lhs, |out, ((o, mode), rhs)| match o {
+        '/' => Expression::Div(out.boxed(), rhs.boxed(), mode),
         '*' => Expression::Mul(out.boxed(), rhs.boxed()),
+        '%' => Expression::Mod(out.boxed(), rhs.boxed()),
         _ => unreachable!(),
     });
 
@@ -121,6 +512,7 @@ mod tests {
     use super::*;
     use nom::Err;
     use nom::error::{Error, ErrorKind};
+    use super::super::roll::{DEFAULT_MAX_SIDES, MAX_TIMES};
 
     #[test]
     fn test_int() {
@@ -136,6 +528,162 @@ mod tests {
     fn test_dice() {
         assert_eq!(Ok(("", Expression::dice(123, 456))), dice("123d456"));
         assert_eq!(Ok(("", Expression::dice(123, 456))), dice("123D456"));
+        assert_eq!(
+            Ok(("", Expression::dice_with(2, 6, Modifiers { reroll_lowest: true, ..Default::default() }))),
+            dice("2d6rl"));
+        assert_eq!(
+            Ok(("", Expression::dice_with(1, 10, Modifiers { zero_based: true, ..Default::default() }))),
+            dice("z10"));
+        for (input, threshold) in [("2d6ro2", 2), ("2d6RO", 1), ("2d6ro0", 1), ("2d6ro9", 6)] {
+            assert_eq!(
+                Ok(("", Expression::dice_with(2, 6, Modifiers { reroll_once: Some(threshold), ..Default::default() }))),
+                dice(input),
+                "{}", input);
+        }
+        for (input, ex) in [
+            ("4d6r1", Modifiers { reroll: Some(1), ..Default::default() }),
+            ("4d6r", Modifiers { reroll: Some(1), ..Default::default() }),
+            ("4d6r2rl", Modifiers { reroll: Some(2), reroll_lowest: true, ..Default::default() }),
+            ("4d6ro1", Modifiers { reroll_once: Some(1), ..Default::default() }),
+        ] {
+            assert_eq!(Ok(("", Expression::dice_with(4, 6, ex))), dice(input), "{}", input);
+        }
+        assert_eq!(
+            Ok(("", Expression::dice_with(3, 6, Modifiers { explode: Some(Explode::Each), ..Default::default() }))),
+            dice("3d6!"));
+        assert_eq!(
+            Ok(("", Expression::dice_with(3, 6, Modifiers { explode: Some(Explode::Compound), ..Default::default() }))),
+            dice("3d6!!"));
+        assert_eq!(
+            Ok(("", Expression::dice_with(3, 10, Modifiers {
+                explode: Some(Explode::Each),
+                explode_on: Some(Condition { cmp: Compare::Ge, target: 9 }),
+                ..Default::default()
+            }))),
+            dice("3d10!>=9"));
+        assert_eq!(
+            Ok(("", Expression::dice_with(2, 6, Modifiers {
+                explode: Some(Explode::Compound),
+                explode_on: Some(Condition { cmp: Compare::Eq, target: 1 }),
+                successes: Some(Condition { cmp: Compare::Gt, target: 4 }),
+                ..Default::default()
+            }))),
+            dice("2d6!!=1>4"));
+        assert_eq!(
+            Ok(("", Expression::dice_with(8, 10, Modifiers {
+                successes: Some(Condition { cmp: Compare::Ge, target: 7 }),
+                ..Default::default()
+            }))),
+            dice("8d10>=7"));
+        assert_eq!(
+            Ok(("", Expression::dice_with(4, 6, Modifiers {
+                successes: Some(Condition { cmp: Compare::Eq, target: 6 }),
+                ..Default::default()
+            }))),
+            dice("4d6=6"));
+        assert_eq!(
+            Ok(("", Expression::dice_with(10, 10, Modifiers {
+                successes: Some(Condition { cmp: Compare::Ge, target: 8 }),
+                failures: Some(Condition { cmp: Compare::Le, target: 1 }),
+                ..Default::default()
+            }))),
+            dice("10d10>=8f<=1"));
+        assert_eq!(
+            Ok(("", Expression::dice_with(1, 6, Modifiers { weights: vec![(1, 3), (6, 3)], ..Default::default() }))),
+            dice("d6w[1:3, 6:3]"));
+        assert_eq!(
+            Ok(("", Expression::dice_with(6, 6, Modifiers { sort: Some(Sort::Ascending), ..Default::default() }))),
+            dice("6d6s"));
+        assert_eq!(
+            Ok(("", Expression::dice_with(4, 6, Modifiers { minimum: Some(2), ..Default::default() }))),
+            dice("4d6MI2"));
+        assert_eq!(
+            Ok(("", Expression::dice_with(1, 6, Modifiers { zero_based: true, minimum: Some(0), ..Default::default() }))),
+            dice("z6mi0"));
+        assert_eq!(
+            Ok(("", Expression::dice_with(6, 6, Modifiers { sort: Some(Sort::Descending), explode: Some(Explode::Each), ..Default::default() }))),
+            dice("6d6!SD"));
+
+        for invalid in ["d6w[7:2]", "d6w[0:2]", "z6w[6:2]", "d6w[1:0]", "d6w[]", "d5000w[1:2]", "d6f<=1", "d6>=5f", "d6mi7", "d6mi0", "z6mi6", "d6mi", "d6!>=7", "d6!>6", "d6!<1", "z6!=6", "d6!=0"] {
+            assert!(!matches!(dice(invalid), Ok(("", _))), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let ok = format!("{}1{}", "(".repeat(DEFAULT_MAX_DEPTH), ")".repeat(DEFAULT_MAX_DEPTH));
+        assert_eq!(Ok(1), Expression::try_from(ok.as_str()).map(|e| e.constant()).map(Option::unwrap));
+
+        let deep = format!("{}1{}", "(".repeat(5000), ")".repeat(5000));
+        assert!(matches!(expr(deep.as_str()), Err(Err::Failure(e)) if e.code == ErrorKind::TooLarge));
+
+        let unclosed = "(".repeat(5000);
+        assert!(Expression::try_from(unclosed.as_str()).is_err());
+
+        for deep in [format!("{}1{}", "max(".repeat(5000), ")".repeat(5000)), format!("2{}", "^2".repeat(5000))] {
+            assert!(expr(deep.as_str()).is_err());
+        }
+
+        // a failed parse leaves the depth where it started
+        assert_eq!(0, DEPTH.with(Cell::get));
+    }
+
+    #[test]
+    fn test_zero_sides() {
+        for invalid in ["d0", "3d0", "z0", "(2)d0", "d%0", "2d00"] {
+            assert!(Expression::try_from(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_number_overflow() {
+        let max = i64::MAX.to_string();
+        assert_eq!(Ok(i64::MAX), Expression::try_from(max.as_str()).map(|e| e.constant()).map(Option::unwrap));
+
+        // one past the largest integer is rejected rather than wrapping or panicking
+        for invalid in ["9223372036854775808", "d9223372036854775808", "9223372036854775808d6", "2 + 9223372036854775808"] {
+            assert!(Expression::try_from(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_dice_max_times() {
+        let max = format!("{}d6", MAX_TIMES);
+        assert_eq!(Ok(("", Expression::dice(MAX_TIMES, 6))), dice(&max));
+
+        let over = format!("{}d6", MAX_TIMES + 1);
+        assert!(dice(&over).is_err());
+
+        // counts around the 32-bit boundary are rejected identically on every target
+        for times in [u32::MAX as i64, u32::MAX as i64 + 1, u32::MAX as i64 * 2] {
+            assert!(dice(&format!("{}d6", times)).is_err(), "{}", times);
+        }
+    }
+
+    #[test]
+    fn test_dice_limits() {
+        let max = format!("d{}", DEFAULT_MAX_SIDES);
+        assert_eq!(Ok(("", Expression::dice(1, DEFAULT_MAX_SIDES))), dice(&max));
+
+        // oversized terms fail where they start instead of parsing as something else
+        for (input, near) in [
+            (format!("{}d6", MAX_TIMES + 1), format!("{}d6", MAX_TIMES + 1)),
+            ("99999999999999999999d6".to_string(), "99999999999999999999d6".to_string()),
+            (format!("5d{}", DEFAULT_MAX_SIDES + 1), format!("d{}", DEFAULT_MAX_SIDES + 1)),
+            ("2 + (3)d99999999999999999999".to_string(), "d99999999999999999999".to_string()),
+            ("1 + 99999999999dF".to_string(), "99999999999dF".to_string()),
+            (format!("{}dF", MAX_TIMES + 1), format!("{}dF", MAX_TIMES + 1)),
+        ] {
+            assert!(matches!(expr(input.as_str()), Err(Err::Failure(e)) if e.code == ErrorKind::TooLarge && e.input == near), "{}", input);
+        }
+
+        assert_eq!(Some(Limit::Dice), exceeded_limit(&format!("{}d6 + 2", MAX_TIMES + 1)));
+        assert_eq!(Some(Limit::Sides), exceeded_limit(&format!(" d{}", DEFAULT_MAX_SIDES + 1)));
+        assert_eq!(Some(Limit::Sides), exceeded_limit("99999999999999999999D99999999999999999999"));
+        assert_eq!(Some(Limit::Dice), exceeded_limit(&format!("{}dF - 1", MAX_TIMES + 1)));
+        for near in ["d6", "3d6", "dd6", "fireball", "", "9999999999"] {
+            assert_eq!(None, exceeded_limit(near), "{}", near);
+        }
     }
 
     #[test]
@@ -153,13 +701,133 @@ mod tests {
             ("2D4", Expression::dice(2, 4)),
             ("d20", Expression::dice(1, 20)),
             ("D8", Expression::dice(1, 8)),
+            ("d%", Expression::dice(1, 100)),
+            ("2D% + 5", Expression::Add(Expression::dice(2, 100).boxed(), 5.into())),
+            ("4dF", Expression::Fudge { times: 4 }),
+            ("df - 1", Expression::Sub(Expression::Fudge { times: 1 }.boxed(), 1.into())),
             ("(123)", Expression::Grp(123.into())),
             ("    (    -456)", Expression::Grp((-456).into())),
             ("3 * -4", Expression::Mul(3.into(), (-4).into())),
+            ("7 / 2", Expression::Div(7.into(), 2.into(), None)),
+            ("7 /f 2", Expression::Div(7.into(), 2.into(), Some(DivMode::Floor))),
+            ("7 // 2", Expression::Div(7.into(), 2.into(), Some(DivMode::Floor))),
+            ("7//-2", Expression::Div(7.into(), (-2).into(), Some(DivMode::Floor))),
+            ("7/R-2", Expression::Div(7.into(), (-2).into(), Some(DivMode::Round))),
+            ("max(2d6, 1d12)", Expression::Max(vec![Expression::dice(2, 6), Expression::dice(1, 12)])),
+            ("MIN( d20 + 5 , 25 )", Expression::Min(vec![
+                Expression::Add(Expression::dice(1, 20).boxed(), 5.into()),
+                25.into(),
+            ])),
+            ("max(3)", Expression::Max(vec![3.into()])),
+            ("(max(1, 2))", Expression::Grp(Expression::Max(vec![1.into(), 2.into()]).boxed())),
+            ("abs(d20 - d20)", Expression::Abs(Expression::Sub(Expression::dice(1, 20).boxed(), Expression::dice(1, 20).boxed()).boxed())),
+            ("ABS( -3 )", Expression::Abs((-3).into())),
+            ("clamp(d20 + 5, 1, 20)", Expression::Clamp(
+                Expression::Add(Expression::dice(1, 20).boxed(), 5.into()).boxed(),
+                1.into(),
+                20.into(),
+            )),
+            ("CLAMP( 2d6 , d4 , 8 )", Expression::Clamp(Expression::dice(2, 6).boxed(), Expression::dice(1, 4).boxed(), 8.into())),
+            ("(1+2)d6", Expression::dice(3, 6)),
+            ("(d4)d6kh1", Expression::Keep(
+                Expression::Counted {
+                    times: Expression::Grp(Expression::dice(1, 4).boxed()).boxed(),
+                    sides: 6,
+                    mods: Modifiers::default(),
+                }.boxed(),
+                Selection::KeepHigh(1),
+            )),
+            ("3d(2 + 4)", Expression::dice(3, 6)),
+            ("3d(2d6)r1", Expression::Sided {
+                times: 3,
+                sides: Expression::Grp(Expression::dice(2, 6).boxed()).boxed(),
+                mods: Modifiers { reroll: Some(1), ..Default::default() },
+            }),
+            ("z (d4)", Expression::Sided {
+                times: 1,
+                sides: Expression::Grp(Expression::dice(1, 4).boxed()).boxed(),
+                mods: Modifiers { zero_based: true, ..Default::default() },
+            }),
+            ("3d(0)", Expression::Sided { times: 3, sides: Expression::Grp(0.into()).boxed(), mods: Modifiers::default() }),
+            ("d20 % 6", Expression::Mod(Expression::dice(1, 20).boxed(), 6.into())),
+            ("d%%2", Expression::Mod(Expression::dice(1, 100).boxed(), 2.into())),
+            ("2 ^ 3 ^ 2", Expression::Pow(2.into(), Expression::Pow(3.into(), 2.into()).boxed())),
+            ("2 * 3^2", Expression::Mul(2.into(), Expression::Pow(3.into(), 2.into()).boxed())),
+            ("(1 + 1)^d4", Expression::Pow(
+                Expression::Grp(Expression::Add(1.into(), 1.into()).boxed()).boxed(),
+                Expression::dice(1, 4).boxed(),
+            )),
+            ("2d20kh", Expression::Keep(Expression::dice(2, 20).boxed(), Selection::KeepHigh(1))),
+            ("2d20kh0", Expression::Keep(Expression::dice(2, 20).boxed(), Selection::KeepHigh(0))),
+            ("2d20kh5", Expression::Keep(Expression::dice(2, 20).boxed(), Selection::KeepHigh(5))),
+            ("4d6KL3", Expression::Keep(Expression::dice(4, 6).boxed(), Selection::KeepLow(3))),
+            ("4d6dl1", Expression::Keep(Expression::dice(4, 6).boxed(), Selection::DropLow(1))),
+            ("2d20DH", Expression::Keep(Expression::dice(2, 20).boxed(), Selection::DropHigh(1))),
+            ("(d6 + 3)kh1", Expression::Keep(
+                Expression::Grp(Expression::Add(Expression::dice(1, 6).boxed(), 3.into()).boxed()).boxed(),
+                Selection::KeepHigh(1),
+            )),
         ];
 
         for (input, ex) in tests {
             assert_eq!(Ok(("", ex)), expr(input));
         }
     }
+
+    #[test]
+    fn test_spaced_dice() {
+        let tests = [
+            ("2 d 6", Expression::dice(2, 6)),
+            ("2 D6", Expression::dice(2, 6)),
+            ("d 20", Expression::dice(1, 20)),
+            ("3 z 10", Expression::dice_with(3, 10, Modifiers { zero_based: true, ..Default::default() })),
+            ("2 d %", Expression::dice(2, 100)),
+            ("4 d F", Expression::Fudge { times: 4 }),
+            ("(1 + 1) d 6", Expression::dice(2, 6)),
+            ("2 d 6kh1", Expression::Keep(Expression::dice(2, 6).boxed(), Selection::KeepHigh(1))),
+            ("2 - d6", Expression::Sub(2.into(), Expression::dice(1, 6).boxed())),
+            ("2 -d 6", Expression::Sub(2.into(), Expression::dice(1, 6).boxed())),
+            ("d6 % 4", Expression::Mod(Expression::dice(1, 6).boxed(), 4.into())),
+            ("d20 adv", Expression::Keep(Expression::dice(2, 20).boxed(), Selection::KeepHigh(1))),
+            ("1d20DIS + 5", Expression::Add(
+                Expression::Keep(Expression::dice(2, 20).boxed(), Selection::KeepLow(1)).boxed(),
+                5.into(),
+            )),
+            ("d20 + d4 adv", Expression::Add(
+                Expression::dice(1, 20).boxed(),
+                Expression::Keep(Expression::dice(2, 4).boxed(), Selection::KeepHigh(1)).boxed(),
+            )),
+        ];
+        for (input, ex) in tests {
+            assert_eq!(Ok(("", ex)), expr(input), "{}", input);
+        }
+
+        for invalid in ["2 d", "2 d ", "d", "2 d - 6", "7 //f 2", "7 / / 2", "7 /// 2", "abs(1, 2)", "abs()", "clamp(d20, 1)", "clamp(d20, 1, 2, 3)", "abs 3", "2d20 adv", "(d20) adv", "d20 + 5 adv", "d20kh1 adv", "d20 adv adv", "dF adv", "3d(d6)mi2", "d(2d6)w[1:2]", "d(d6)!>5", "3d()"] {
+            assert!(!matches!(expr(invalid), Ok(("", _))), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_neg() {
+        let neg = |e: Expression| Expression::Neg(e.boxed());
+        let tests = [
+            ("-(d6)", neg(Expression::Grp(Expression::dice(1, 6).boxed()))),
+            ("-2d6", neg(Expression::dice(2, 6))),
+            ("- 4d6kh3", neg(Expression::Keep(Expression::dice(4, 6).boxed(), Selection::KeepHigh(3)))),
+            ("--d6", neg(neg(Expression::dice(1, 6)))),
+            ("-(2)", neg(Expression::Grp(2.into()))),
+            ("--2", 2.into()),
+            ("3 - -d4", Expression::Sub(3.into(), neg(Expression::dice(1, 4)).boxed())),
+            ("-d6^2", Expression::Pow(neg(Expression::dice(1, 6)).boxed(), 2.into())),
+            ("2^-d6", Expression::Pow(2.into(), neg(Expression::dice(1, 6)).boxed())),
+            ("-max(d4, 2)", neg(Expression::Max(vec![Expression::dice(1, 4), 2.into()]))),
+        ];
+        for (input, ex) in tests {
+            assert_eq!(Ok(("", ex)), expr(input), "{}", input);
+        }
+
+        for (input, ex) in [("3 - -2", 5), ("-(2+3)*4", -20), ("4 * -(2+3)", -20), ("-(2+3)^2", 25), ("-(1-3) - -(4)", 6), ("10 / -(3)", -3)] {
+            assert_eq!(Some(ex), expr(input).unwrap().1.constant(), "{}", input);
+        }
+    }
 }
\ No newline at end of file
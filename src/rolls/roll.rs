@@ -1,120 +1,775 @@
 use std::collections::BTreeMap;
 use std::fmt::{Debug, Display, Formatter};
+use std::ops::RangeInclusive;
+use std::sync::OnceLock;
 use rand::prelude::*;
-use rand::distributions::Uniform;
-use super::Expression;
-use nom::Err;
-use nom::error::Error;
+use rand::distributions::{Uniform, WeightedIndex};
+use super::expression::face_average;
+use super::{clamp, default_div_mode, pow, rem, strict_division, Compare, Condition, DivMode, Explode, Expression, Modifiers, ParseError, Selection, Sort};
 
-#[derive(Debug)]
+/// The most dice a single term may roll, whatever limit is set. The parser
+/// rejects larger counts, which also guarantees every count fits in a `usize`
+/// regardless of pointer width.
+pub const MAX_TIMES: i64 = 1_000_000;
+
+/// The most sides a die may have when no limit is set.
+pub const DEFAULT_MAX_SIDES: i64 = 1_000_000;
+
+const _: () = assert!(MAX_TIMES <= u32::MAX as i64);
+
+/// The most extra rolls explosions may add to a single dice term, so that dice
+/// which always explode (like `1000d1!`) still finish rolling.
+pub const MAX_EXPLOSIONS: usize = 1000;
+
+/// The most rerolls the `rN` modifier may make for a single dice term, so that
+/// dice which can never escape the threshold (like `d1r1`) still finish.
+pub const MAX_REROLLS: usize = 1000;
+
+/// The most sides a weighted die may have, since every face needs a weight.
+pub const MAX_WEIGHTED_SIDES: i64 = 1000;
+
+/// How many dice a pool may roll before its faces are tallied, when no
+/// threshold is set.
+pub const DEFAULT_MANY_DICE: i64 = 20;
+
+/// How many sides a tallied pool's dice may have before only the pool's sum is
+/// kept, when no threshold is set.
+pub const DEFAULT_TOO_MANY_DICE: i64 = 20;
+
+static DICE_THRESHOLDS: OnceLock<(i64, i64)> = OnceLock::new();
+
+/// Sets when pools are shown in less detail: pools of more than `many` dice
+/// are tallied by face, and tallied pools of dice with more than `too_many`
+/// sides are only summarized. Only the first call has any effect.
+pub fn set_dice_thresholds(many: i64, too_many: i64) {
+    let _ = DICE_THRESHOLDS.set((many, too_many));
+}
+
+/// The thresholds set by `set_dice_thresholds`, or the defaults.
+fn dice_thresholds() -> (i64, i64) {
+    DICE_THRESHOLDS.get().copied().unwrap_or((DEFAULT_MANY_DICE, DEFAULT_TOO_MANY_DICE))
+}
+
+static DICE_LIMITS: OnceLock<(i64, i64)> = OnceLock::new();
+
+/// Sets the most dice a single term may roll and the most sides each of them
+/// may have, so the parser rejects terms that would take too long to roll.
+/// Counts are held to `MAX_TIMES` regardless. Only the first call has any effect.
+pub fn set_dice_limits(times: i64, sides: i64) {
+    let _ = DICE_LIMITS.set((times.min(MAX_TIMES), sides));
+}
+
+/// The limits set by `set_dice_limits`, or the defaults.
+pub fn dice_limits() -> (i64, i64) {
+    DICE_LIMITS.get().copied().unwrap_or((MAX_TIMES, DEFAULT_MAX_SIDES))
+}
+
+/// The outcome of rolling an expression. Equality compares the faces the dice
+/// landed on along with how the terms are arranged, so two rolls of the same
+/// expression usually differ.
+#[derive(Debug, Clone, PartialEq)]
 pub enum Roll {
     Num(i64),
-    Dice(Vec<i64>),
+    /// Individually rolled dice, along with the lowest and highest faces they can show.
+    Dice(Vec<Die>, RangeInclusive<i64>),
     ManyDice(BTreeMap<i64, i64>),
-    TooManyDice(i64),
+    /// A pool too large to show, summarized by its sum and spread.
+    TooManyDice(PoolSummary),
+    Loaded(Box<Roll>),
+    /// Dice whose count was rolled first: the count, then the dice.
+    Counted(Box<Roll>, Box<Roll>),
+    /// Dice whose sides were rolled first: the sides, then the dice.
+    Sided(Box<Roll>, Box<Roll>),
+    /// Fate dice, whose faces are -1, 0, and +1.
+    Fudge(Box<Roll>),
+    /// A pool whose value is the number of kept dice meeting the first
+    /// condition, less the number meeting the second if there is one.
+    Successes(Box<Roll>, Condition, Option<Condition>),
     Grp(Box<Roll>),
+    Neg(Box<Roll>),
+    Abs(Box<Roll>),
     Add(Box<Roll>, Box<Roll>),
     Sub(Box<Roll>, Box<Roll>),
     Mul(Box<Roll>, Box<Roll>),
-    Div(Box<Roll>, Box<Roll>),
+    Div(Box<Roll>, Box<Roll>, DivMode),
+    Mod(Box<Roll>, Box<Roll>),
+    Pow(Box<Roll>, Box<Roll>),
+    Min(Vec<Roll>),
+    Max(Vec<Roll>),
+    Clamp(Box<Roll>, Box<Roll>, Box<Roll>),
+}
+
+/// An error calculating the total of a roll.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvalError {
+    /// The total, or some part of it, doesn't fit in an `i64`.
+    Overflow,
+    /// Part of the roll divides by zero, with strict division set.
+    DivisionByZero,
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::Overflow => write!(f, "roll total is too large"),
+            EvalError::DivisionByZero => write!(f, "division by zero"),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+impl Roll {
+    /// The total of the roll. Arithmetic is checked, so totals too large for an
+    /// `i64` are reported as an overflow rather than wrapping. Dividing by zero
+    /// equals zero unless strict division is set by `set_strict_division`.
+    pub fn value(&self) -> Result<i64, EvalError> {
+        self.total(strict_division())
+    }
+
+    /// The total of the roll, where dividing by zero is an error if `strict`
+    /// is set and equals zero otherwise.
+    pub(super) fn total(&self, strict: bool) -> Result<i64, EvalError> {
+        use Roll::*;
+
+        match self {
+            Num(i) => Ok(*i),
+            Dice(v, _) => v.iter()
+                .filter(|d| !d.dropped)
+                .try_fold(0i64, |s, d| s.checked_add(d.value))
+                .ok_or(EvalError::Overflow),
+            ManyDice(m) => m.iter()
+                .try_fold(0i64, |s, (val, times)| val.checked_mul(*times).and_then(|n| s.checked_add(n)))
+                .ok_or(EvalError::Overflow),
+            TooManyDice(s) => i64::try_from(s.sum).map_err(|_| EvalError::Overflow),
+            Loaded(expr) | Fudge(expr) | Grp(expr) => expr.total(strict),
+            Neg(expr) => expr.total(strict)?.checked_neg().ok_or(EvalError::Overflow),
+            Abs(expr) => expr.total(strict)?.checked_abs().ok_or(EvalError::Overflow),
+            Counted(count, expr) | Sided(count, expr) => count.total(strict).and_then(|_| expr.total(strict)),
+            Successes(expr, hit, miss) => Ok(expr.count_successes(hit) - miss.map_or(0, |c| expr.count_successes(&c))),
+            Add(lhs, rhs) => lhs.total(strict)?.checked_add(rhs.total(strict)?).ok_or(EvalError::Overflow),
+            Sub(lhs, rhs) => lhs.total(strict)?.checked_sub(rhs.total(strict)?).ok_or(EvalError::Overflow),
+            Mul(lhs, rhs) => lhs.total(strict)?.checked_mul(rhs.total(strict)?).ok_or(EvalError::Overflow),
+            Div(lhs, rhs, mode) => match (lhs.total(strict)?, rhs.total(strict)?) {
+                (_, 0) if strict => Err(EvalError::DivisionByZero),
+                (i64::MIN, -1) => Err(EvalError::Overflow),
+                (l, r) => Ok(mode.divide(l, r)),
+            },
+            Mod(lhs, rhs) => match (lhs.total(strict)?, rhs.total(strict)?) {
+                (_, 0) if strict => Err(EvalError::DivisionByZero),
+                (l, r) => Ok(rem(l, r)),
+            },
+            // negative powers of zero divide by zero
+            Pow(lhs, rhs) => match (lhs.total(strict)?, rhs.total(strict)?) {
+                (0, exp) if exp < 0 && strict => Err(EvalError::DivisionByZero),
+                (base, exp) => Ok(pow(base, exp)),
+            },
+            Min(args) => args.iter().map(|r| r.total(strict)).collect::<Result<Vec<_>, _>>().map(|v| v.into_iter().min().unwrap_or(0)),
+            Max(args) => args.iter().map(|r| r.total(strict)).collect::<Result<Vec<_>, _>>().map(|v| v.into_iter().max().unwrap_or(0)),
+            Clamp(expr, lo, hi) => Ok(clamp(expr.total(strict)?, lo.total(strict)?, hi.total(strict)?)),
+        }
+    }
+
+    /// The face a lone d20 landed on, before any minimum raised it, or nothing
+    /// if the roll is anything other than a single d20.
+    pub fn natural_d20(&self) -> Option<i64> {
+        match self {
+            Roll::Dice(dice, faces) if *faces == (1..=20) => match dice.as_slice() {
+                [d] => Some(d.raised.unwrap_or(d.value)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
 }
 
 impl Roll {
-    pub fn value(&self) -> i64 {
+    /// Counts the kept dice in a pool meeting `cond`.
+    fn count_successes(&self, cond: &Condition) -> i64 {
         use Roll::*;
 
         match self {
-            Num(i) => *i,
-            Dice(v) => v.iter().sum(),
-            ManyDice(m) => m.iter().fold(0, |s, (val, times)| s + (*val) * (*times)),
-            TooManyDice(i) => *i,
-            Grp(expr) => expr.value(),
-            Add(lhs, rhs) => lhs.value() + rhs.value(),
-            Sub(lhs, rhs) => lhs.value() - rhs.value(),
-            Mul(lhs, rhs) => lhs.value() * rhs.value(),
-            Div(lhs, rhs) => {
-                let r = rhs.value();
-                if r == 0 {
-                    return 0;
+            Dice(v, _) => v.iter().filter(|d| !d.dropped && cond.matches(d.value)).count() as i64,
+            ManyDice(m) => m.iter().filter(|(face, _)| cond.matches(**face)).map(|(_, times)| times).sum(),
+            Loaded(expr) => expr.count_successes(cond),
+            _ => 0,
+        }
+    }
+}
+
+/// A single die within a rolled dice term.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Die {
+    pub value: i64,
+    /// The original face if this die was rerolled.
+    pub rerolled: Option<i64>,
+    /// Whether a keep modifier excluded this die from the total.
+    pub dropped: bool,
+    /// Whether this die showed its highest face and added another die.
+    pub exploded: bool,
+    /// The face this die showed before a minimum raised it.
+    pub raised: Option<i64>,
+}
+
+/// What's kept of a pool too large to show.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolSummary {
+    pub count: i64,
+    /// The sum of every face, kept wide enough that it can't wrap.
+    pub sum: i128,
+    /// The lowest face rolled.
+    pub min: i64,
+    /// The highest face rolled.
+    pub max: i64,
+}
+
+impl PoolSummary {
+    /// Summarizes the faces of a pool in a single pass.
+    fn new(values: impl Iterator<Item=i64>) -> Self {
+        let empty = Self { count: 0, sum: 0, min: i64::MAX, max: i64::MIN };
+        values.fold(empty, |s, v| Self {
+            count: s.count + 1,
+            sum: s.sum + i128::from(v),
+            min: s.min.min(v),
+            max: s.max.max(v),
+        })
+    }
+
+    /// The average face, or zero for a pool without any dice.
+    pub fn mean(&self) -> f64 {
+        match self.count {
+            0 => 0.0,
+            n => self.sum as f64 / n as f64,
+        }
+    }
+}
+
+impl Display for PoolSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mean = format!("{:.2}", self.mean());
+        let mean = mean.trim_end_matches('0').trim_end_matches('.');
+        write!(f, "[{} dice: sum {}, min {}, max {}, avg {}]", self.count, self.sum, self.min, self.max, mean)
+    }
+}
+
+impl Die {
+    fn new(value: i64) -> Self {
+        Self { value, rerolled: None, dropped: false, exploded: false, raised: None }
+    }
+
+    /// Rerolls the die, remembering the face it first landed on.
+    fn reroll<R: Rng + ?Sized>(&mut self, faces: &Faces, rng: &mut R) {
+        self.rerolled.get_or_insert(self.value);
+        self.value = faces.sample(rng);
+    }
+}
+
+impl Roll {
+    /// The terms added or subtracted at the top level of the roll, in order,
+    /// each with whether it's subtracted. Anything other than a sum is a
+    /// single term.
+    pub fn terms(&self) -> Vec<(bool, &Roll)> {
+        match self {
+            Roll::Add(lhs, rhs) | Roll::Sub(lhs, rhs) => {
+                let mut terms = lhs.terms();
+                terms.push((matches!(self, Roll::Sub(..)), rhs));
+                terms
+            }
+            term => vec![(false, term)],
+        }
+    }
+
+    /// Returns the face of every die rolled across the whole tree, in traversal
+    /// order and ignoring the arithmetic structure. Dropped dice are included,
+    /// and rerolled dice report the face they finally landed on. Pools large
+    /// enough to be aggregated are expanded in ascending face order, while the
+    /// very largest pools only retain their sum and contribute no faces.
+    pub fn evaluate_all(&self) -> Vec<i64> {
+        let mut out = Vec::new();
+        self.collect_faces(&mut out);
+        out
+    }
+
+    fn collect_faces(&self, out: &mut Vec<i64>) {
+        use Roll::*;
+
+        match self {
+            Num(_) | TooManyDice(_) => {}
+            Dice(v, _) => out.extend(v.iter().map(|d| d.value)),
+            ManyDice(m) => {
+                for (face, times) in m {
+                    out.extend(std::iter::repeat_n(*face, *times as usize));
                 }
-                lhs.value() / r
+            }
+            Loaded(expr) | Fudge(expr) | Successes(expr, ..) | Grp(expr) | Neg(expr) | Abs(expr) => expr.collect_faces(out),
+            Counted(times, expr) | Sided(times, expr) => {
+                times.collect_faces(out);
+                expr.collect_faces(out);
+            }
+            Add(lhs, rhs) | Sub(lhs, rhs) | Mul(lhs, rhs) | Div(lhs, rhs, _) | Mod(lhs, rhs) | Pow(lhs, rhs) => {
+                lhs.collect_faces(out);
+                rhs.collect_faces(out);
+            }
+            Min(args) | Max(args) => {
+                for arg in args {
+                    arg.collect_faces(out);
+                }
+            }
+            Clamp(expr, lo, hi) => {
+                expr.collect_faces(out);
+                lo.collect_faces(out);
+                hi.collect_faces(out);
             }
         }
     }
 }
 
 impl Roll {
-    fn roll_iter(times: i64, sides: i64) -> impl IntoIterator<Item=i64> {
-        Uniform::from(1..=sides)
-            .sample_iter(thread_rng())
-            .take(times as usize)
+    /// Rolls every die in `expr` using the provided RNG. Expressions without any
+    /// dice are evaluated directly and never touch the RNG.
+    pub fn from_expr_with_rng<R: Rng + ?Sized>(expr: &Expression, rng: &mut R) -> Self {
+        match expr.constant() {
+            Some(i) => Self::Num(i),
+            None => Self::sample(expr, rng, false),
+        }
+    }
+
+    /// Evaluates `expr` as if every die landed on its average face, rounded
+    /// down, so the result is representative without being random. Rerolls
+    /// and minimums count towards the average, but no die explodes.
+    pub fn preview(expr: &Expression) -> Self {
+        match expr.constant() {
+            Some(i) => Self::Num(i),
+            None => Self::sample(expr, &mut Preview, false),
+        }
+    }
+
+    /// Rolls the dice in `expr`. When `individual` is set, every die is kept as
+    /// its own `Die` rather than aggregated, so that keep modifiers can select
+    /// from them.
+    fn sample<R: Roller + ?Sized>(expr: &Expression, rng: &mut R, individual: bool) -> Self {
+        use Expression::*;
+
+        match expr {
+            Num(i) => Self::Num(*i),
+            Dice { times, sides, mods } => rng.dice(*times, *sides, mods, individual),
+            Counted { times, sides, mods } => {
+                let count = Self::sample_boxed(times, rng, false);
+                // negative counts roll nothing, and huge ones are held to the parser's limit;
+                // a count that overflows rolls nothing, and the total reports the overflow
+                let times = count.value().unwrap_or(0).clamp(0, dice_limits().0);
+                Self::Counted(count, Box::new(rng.dice(times, *sides, mods, individual)))
+            }
+            Sided { times, sides, mods } => {
+                let resolved = Self::sample_boxed(sides, rng, false);
+                // sides below one roll nothing, and huge ones are held to the parser's limit;
+                // sides that overflow roll nothing, and the total reports the overflow
+                let (times, sides) = match resolved.value() {
+                    Ok(s) if s >= 1 => (*times, s.min(dice_limits().1)),
+                    _ => (0, 1),
+                };
+                Self::Sided(resolved, Box::new(rng.dice(times, sides, &mods.clone().clamp_rerolls(sides), individual)))
+            }
+            Fudge { times } => rng.fudge(*times, individual),
+            Keep(e, sel) => {
+                let mut roll = Self::sample(e, rng, true);
+                roll.select(*sel);
+                // an enclosing keep still needs every die to select from
+                if !individual {
+                    roll.compact();
+                }
+                roll
+            }
+            Grp(e) => Self::Grp(Self::sample_boxed(e, rng, individual)),
+            Neg(e) => Self::Neg(Self::sample_boxed(e, rng, individual)),
+            Abs(e) => Self::Abs(Self::sample_boxed(e, rng, individual)),
+            Add(lhs, rhs) => Self::Add(Self::sample_boxed(lhs, rng, individual), Self::sample_boxed(rhs, rng, individual)),
+            Sub(lhs, rhs) => Self::Sub(Self::sample_boxed(lhs, rng, individual), Self::sample_boxed(rhs, rng, individual)),
+            Mul(lhs, rhs) => Self::Mul(Self::sample_boxed(lhs, rng, individual), Self::sample_boxed(rhs, rng, individual)),
+            Div(lhs, rhs, mode) => Self::Div(
+                Self::sample_boxed(lhs, rng, individual),
+                Self::sample_boxed(rhs, rng, individual),
+                mode.unwrap_or_else(default_div_mode),
+            ),
+            Mod(lhs, rhs) => Self::Mod(Self::sample_boxed(lhs, rng, individual), Self::sample_boxed(rhs, rng, individual)),
+            Pow(lhs, rhs) => Self::Pow(Self::sample_boxed(lhs, rng, individual), Self::sample_boxed(rhs, rng, individual)),
+            Min(args) => Self::Min(args.iter().map(|e| Self::sample(e, rng, individual)).collect()),
+            Max(args) => Self::Max(args.iter().map(|e| Self::sample(e, rng, individual)).collect()),
+            Clamp(e, lo, hi) => Self::Clamp(
+                Self::sample_boxed(e, rng, individual),
+                Self::sample_boxed(lo, rng, individual),
+                Self::sample_boxed(hi, rng, individual),
+            ),
+        }
+    }
+
+    fn sample_dice<R: Rng + ?Sized>(times: i64, sides: i64, mods: &Modifiers, rng: &mut R, individual: bool) -> Self {
+        let faces = Faces::new(sides, mods);
+        // counting successes needs every face, so large pools are tallied rather than summed
+        let (many, too_many) = dice_thresholds();
+        let summed = sides > too_many && mods.successes.is_none();
+        let roll = match times {
+            t if !individual && !mods.per_die() && t > many && summed => Self::roll_too_many(t, &faces, rng),
+            t if !individual && !mods.per_die() && t > many => Self::roll_many(t, &faces, rng),
+            t => Self::new_roll(t, sides, &faces, mods, rng, individual),
+        };
+        Self::wrap_dice(roll, mods)
+    }
+
+    /// Wraps rolled dice in the terms their modifiers call for: loaded if
+    /// they're weighted, and counting successes if there's a target.
+    fn wrap_dice(roll: Self, mods: &Modifiers) -> Self {
+        let roll = match mods.weights.is_empty() {
+            true => roll,
+            false => Self::Loaded(Box::new(roll)),
+        };
+        match mods.successes {
+            Some(cond) => Self::Successes(Box::new(roll), cond, mods.failures),
+            None => roll,
+        }
+    }
+
+    /// `times` dice that all show `face`, aggregated like rolled dice unless
+    /// `individual` is set.
+    fn fixed(times: i64, face: i64, faces: RangeInclusive<i64>, individual: bool, summed: bool) -> Self {
+        let dice = (0..times).map(|_| Die::new(face)).collect();
+        match individual {
+            true => Self::Dice(dice, faces),
+            false => Self::collapse(dice, faces, summed),
+        }
+    }
+
+    fn sample_boxed<R: Roller + ?Sized>(expr: &Expression, rng: &mut R, individual: bool) -> Box<Self> {
+        Box::new(Self::sample(expr, rng, individual))
+    }
+
+    /// Marks dice across the whole subtree as dropped according to `sel`. Only
+    /// dice take part in the selection; constants are left untouched and still
+    /// contribute to the total. Ties are broken by roll order.
+    fn select(&mut self, sel: Selection) {
+        let mut dice = self.dice_mut();
+        dice.retain(|d| !d.dropped);
+
+        let len = dice.len();
+        let count = |n: i64| n.clamp(0, len as i64) as usize;
+        let (keep, highest) = match sel {
+            Selection::KeepHigh(n) => (count(n), true),
+            Selection::KeepLow(n) => (count(n), false),
+            Selection::DropHigh(n) => (len - count(n), false),
+            Selection::DropLow(n) => (len - count(n), true),
+        };
+        match highest {
+            true => dice.sort_by_key(|d| std::cmp::Reverse(d.value)),
+            false => dice.sort_by_key(|d| d.value),
+        }
+
+        for d in dice.into_iter().skip(keep) {
+            d.dropped = true;
+        }
+    }
+
+    /// Shrinks pools that were only rolled die by die so a keep could select
+    /// from them. Once a pool is too large to list, its dropped dice are
+    /// discarded, and the dice it kept are tallied if there are still too many.
+    fn compact(&mut self) {
+        use Roll::*;
+
+        match self {
+            Dice(v, faces) if v.len() as i64 > dice_thresholds().0 => {
+                v.retain(|d| !d.dropped);
+                // tallies keep every face, so successes can still be counted from them
+                *self = Self::collapse(std::mem::take(v), faces.clone(), false);
+            }
+            Num(_) | Dice(..) | ManyDice(_) | TooManyDice(_) => {}
+            Loaded(expr) | Fudge(expr) | Successes(expr, ..) | Grp(expr) | Neg(expr) | Abs(expr) | Counted(_, expr) | Sided(_, expr) => expr.compact(),
+            Add(lhs, rhs) | Sub(lhs, rhs) | Mul(lhs, rhs) | Div(lhs, rhs, _) | Mod(lhs, rhs) | Pow(lhs, rhs) => {
+                lhs.compact();
+                rhs.compact();
+            }
+            Min(args) | Max(args) => args.iter_mut().for_each(Roll::compact),
+            Clamp(expr, lo, hi) => {
+                expr.compact();
+                lo.compact();
+                hi.compact();
+            }
+        }
+    }
+
+    /// Collects every individually rolled die in the tree, in roll order.
+    fn dice_mut(&mut self) -> Vec<&mut Die> {
+        let mut out = Vec::new();
+        self.collect_dice_mut(&mut out);
+        out
     }
 
-    fn new_roll(times: i64, sides: i64) -> Self {
+    fn collect_dice_mut<'a>(&'a mut self, out: &mut Vec<&'a mut Die>) {
+        use Roll::*;
+
+        match self {
+            Num(_) | ManyDice(_) | TooManyDice(_) => {}
+            Dice(v, _) => out.extend(v.iter_mut()),
+            // only the counted dice take part in keep selections, not the dice rolled for the count
+            Loaded(expr) | Fudge(expr) | Successes(expr, ..) | Grp(expr) | Neg(expr) | Abs(expr) | Counted(_, expr) | Sided(_, expr) => expr.collect_dice_mut(out),
+            Add(lhs, rhs) | Sub(lhs, rhs) | Mul(lhs, rhs) | Div(lhs, rhs, _) | Mod(lhs, rhs) | Pow(lhs, rhs) => {
+                lhs.collect_dice_mut(out);
+                rhs.collect_dice_mut(out);
+            }
+            Min(args) | Max(args) => {
+                for arg in args {
+                    arg.collect_dice_mut(out);
+                }
+            }
+            Clamp(expr, lo, hi) => {
+                expr.collect_dice_mut(out);
+                lo.collect_dice_mut(out);
+                hi.collect_dice_mut(out);
+            }
+        }
+    }
+
+    fn roll_iter<'a, R: Rng + ?Sized>(times: i64, faces: &'a Faces, rng: &'a mut R) -> impl Iterator<Item=i64> + 'a {
+        (0..times).map(move |_| faces.sample(rng))
+    }
+
+    fn new_roll<R: Rng + ?Sized>(times: i64, sides: i64, faces: &Faces, mods: &Modifiers, rng: &mut R, individual: bool) -> Self {
         let mut v = Vec::with_capacity(times as usize);
 
-        for n in Roll::roll_iter(times, sides) {
-            v.push(n);
+        for n in Roll::roll_iter(times, faces, rng) {
+            v.push(Die::new(n));
         }
 
-        Self::Dice(v)
+        if let Some(threshold) = mods.reroll_once {
+            for d in v.iter_mut().filter(|d| d.value <= threshold) {
+                d.reroll(faces, rng);
+            }
+        }
+
+        if let Some(threshold) = mods.reroll {
+            let mut rerolls = 0;
+            for d in v.iter_mut() {
+                while d.value <= threshold && rerolls < MAX_REROLLS {
+                    d.reroll(faces, rng);
+                    rerolls += 1;
+                }
+            }
+        }
+
+        if mods.reroll_lowest {
+            Self::reroll_lowest(&mut v, faces, rng);
+        }
+
+        let low = if mods.zero_based { 0 } else { 1 };
+        let max = sides - 1 + low;
+        let explode_on = mods.explode_on.unwrap_or(Condition { cmp: Compare::Eq, target: max });
+        match mods.explode {
+            Some(Explode::Each) => Self::explode(&mut v, explode_on, faces, rng),
+            Some(Explode::Compound) => Self::compound(&mut v, explode_on, faces, rng),
+            None => {}
+        }
+
+        // dice explode on the face they actually showed, and extra dice are raised as well
+        if let Some(m) = mods.minimum {
+            for d in v.iter_mut().filter(|d| d.value < m) {
+                d.raised = Some(d.value);
+                d.value = m;
+            }
+        }
+
+        // the total doesn't depend on the order, so sorting only changes how the dice are listed
+        match mods.sort {
+            Some(Sort::Ascending) => v.sort_by_key(|d| d.value),
+            Some(Sort::Descending) => v.sort_by_key(|d| std::cmp::Reverse(d.value)),
+            None => {}
+        }
+
+        if individual {
+            return Self::Dice(v, low..=max);
+        }
+        Self::collapse(v, low..=max, sides > dice_thresholds().1 && mods.successes.is_none())
     }
 
-    fn roll_many(times: i64, sides: i64) -> Self {
+    /// Rerolls the lowest die once. Ties go to the earliest die rolled.
+    fn reroll_lowest<R: Rng + ?Sized>(dice: &mut [Die], faces: &Faces, rng: &mut R) {
+        if let Some(lowest) = dice.iter_mut().min_by_key(|d| d.value) {
+            lowest.reroll(faces, rng);
+        }
+    }
+
+    /// Appends another die for every die meeting `on`, including the added
+    /// dice themselves, until none explode or `MAX_EXPLOSIONS` is reached.
+    fn explode<R: Rng + ?Sized>(dice: &mut Vec<Die>, on: Condition, faces: &Faces, rng: &mut R) {
+        let mut i = 0;
+        let mut extra = 0;
+        while i < dice.len() && extra < MAX_EXPLOSIONS {
+            if on.matches(dice[i].value) {
+                dice[i].exploded = true;
+                dice.push(Die::new(faces.sample(rng)));
+                extra += 1;
+            }
+            i += 1;
+        }
+    }
+
+    /// Adds another roll into every die meeting `on`, repeating while the new
+    /// roll also meets it, so each die remains a single total.
+    fn compound<R: Rng + ?Sized>(dice: &mut [Die], on: Condition, faces: &Faces, rng: &mut R) {
+        let mut extra = 0;
+        for d in dice.iter_mut() {
+            let mut last = d.value;
+            while on.matches(last) && extra < MAX_EXPLOSIONS {
+                last = faces.sample(rng);
+                d.value += last;
+                extra += 1;
+            }
+        }
+    }
+
+    /// Aggregates large pools after their modifiers are applied, mirroring the
+    /// representation used for unmodified pools. Pools are only reduced to their
+    /// sum when `summed` is set.
+    fn collapse(dice: Vec<Die>, faces: RangeInclusive<i64>, summed: bool) -> Self {
+        let (many, _) = dice_thresholds();
+        match dice.len() as i64 {
+            n if n > many && summed => Self::TooManyDice(PoolSummary::new(dice.iter().map(|d| d.value))),
+            n if n > many => Self::ManyDice(Self::tally(dice.iter().map(|d| d.value))),
+            _ => Self::Dice(dice, faces),
+        }
+    }
+
+    fn tally(values: impl Iterator<Item=i64>) -> BTreeMap<i64, i64> {
         let mut m = BTreeMap::new();
 
-        for n in Roll::roll_iter(times, sides) {
+        for n in values {
             *m.entry(n).or_insert(0) += 1;
         }
 
-        Self::ManyDice(m)
+        m
     }
 
-    fn roll_too_many(times: i64, sides: i64) -> Self {
-        let n = Roll::roll_iter(times, sides).into_iter().sum();
-        Self::TooManyDice(n)
+    fn roll_many<R: Rng + ?Sized>(times: i64, faces: &Faces, rng: &mut R) -> Self {
+        Self::ManyDice(Self::tally(Roll::roll_iter(times, faces, rng)))
+    }
+
+    fn roll_too_many<R: Rng + ?Sized>(times: i64, faces: &Faces, rng: &mut R) -> Self {
+        Self::TooManyDice(PoolSummary::new(Roll::roll_iter(times, faces, rng)))
     }
 }
 
-impl From<&Expression> for Roll {
-    fn from(expr: &Expression) -> Self {
-        use Expression::*;
+/// Decides the faces of the dice in an expression as it's evaluated.
+trait Roller {
+    fn dice(&mut self, times: i64, sides: i64, mods: &Modifiers, individual: bool) -> Roll;
+    fn fudge(&mut self, times: i64, individual: bool) -> Roll;
+}
 
-        match expr {
-            Num(i) => Self::Num(*i),
-            Dice { times, sides } if *times > 20 && *sides > 20 => Self::roll_too_many(*times, *sides),
-            Dice { times, sides } if *times > 20 => Self::roll_many(*times, *sides),
-            Dice { times, sides } => Self::new_roll(*times, *sides),
-            Grp(e) => Self::Grp(e.into()),
-            Add(lhs, rhs) => Self::Add(lhs.into(), rhs.into()),
-            Sub(lhs, rhs) => Self::Sub(lhs.into(), rhs.into()),
-            Mul(lhs, rhs) => Self::Mul(lhs.into(), rhs.into()),
-            Div(lhs, rhs) => Self::Div(lhs.into(), rhs.into()),
+impl<R: Rng + ?Sized> Roller for R {
+    fn dice(&mut self, times: i64, sides: i64, mods: &Modifiers, individual: bool) -> Roll {
+        Roll::sample_dice(times, sides, mods, self, individual)
+    }
+
+    fn fudge(&mut self, times: i64, individual: bool) -> Roll {
+        let faces = Faces::Fair(Uniform::from(-1..=1));
+        let (many, _) = dice_thresholds();
+        Roll::Fudge(Box::new(match times {
+            t if !individual && t > many => Roll::roll_many(t, &faces, self),
+            t => Roll::new_roll(t, 3, &faces, &Modifiers::default(), self, individual),
+        }))
+    }
+}
+
+/// Lands every die on its average face, rounded down, for `Roll::preview`.
+struct Preview;
+
+impl Roller for Preview {
+    fn dice(&mut self, times: i64, sides: i64, mods: &Modifiers, individual: bool) -> Roll {
+        let low = if mods.zero_based { 0 } else { 1 };
+        let high = sides - 1 + low;
+        let face = (face_average(sides, mods).floor() as i64).clamp(low, high);
+        let summed = sides > dice_thresholds().1 && mods.successes.is_none();
+        Roll::wrap_dice(Roll::fixed(times, face, low..=high, individual, summed), mods)
+    }
+
+    fn fudge(&mut self, times: i64, individual: bool) -> Roll {
+        Roll::Fudge(Box::new(Roll::fixed(times, 0, -1..=1, individual, false)))
+    }
+}
+
+/// The distribution of faces for a single die: fair unless weights were given.
+enum Faces {
+    Fair(Uniform<i64>),
+    Weighted { low: i64, index: WeightedIndex<u32> },
+}
+
+impl Faces {
+    fn new(sides: i64, mods: &Modifiers) -> Self {
+        let low = if mods.zero_based { 0 } else { 1 };
+        if mods.weights.is_empty() {
+            return Self::Fair(Uniform::from(low..=sides - 1 + low));
+        }
+
+        let mut weights = vec![1; sides as usize];
+        for (face, weight) in &mods.weights {
+            weights[(face - low) as usize] = *weight;
+        }
+        let index = WeightedIndex::new(weights)
+            .expect("weights are validated by the parser");
+        Self::Weighted { low, index }
+    }
+}
+
+impl Distribution<i64> for Faces {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> i64 {
+        match self {
+            Self::Fair(faces) => faces.sample(rng),
+            Self::Weighted { low, index } => low + index.sample(rng) as i64,
         }
     }
 }
 
-impl From<&Box<Expression>> for Box<Roll> {
-    fn from(be: &Box<Expression>) -> Self {
-        Box::new(be.as_ref().into())
+impl From<&Expression> for Roll {
+    fn from(expr: &Expression) -> Self {
+        Self::from_expr_with_rng(expr, &mut thread_rng())
     }
 }
 
-impl<'a> TryFrom<&'a str> for Roll {
-    type Error = Err<Error<&'a str>>;
+impl TryFrom<&str> for Roll {
+    type Error = ParseError;
 
-    fn try_from(value: &'a str) -> Result<Self, Self::Error> {
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
         Expression::try_from(value).
             map(|e| (&e).into())
     }
 }
 
+impl Roll {
+    /// Whether the roll renders as a single term, such that wrapping it in
+    /// parentheses would add nothing. Negative numbers and negated terms keep
+    /// their parentheses so `3 - (-2)` doesn't read as `3 - -2`.
+    fn is_atomic(&self) -> bool {
+        use Roll::*;
+
+        match self {
+            Num(i) => *i >= 0,
+            Dice(..) | ManyDice(_) | TooManyDice(_) | Loaded(_) | Fudge(_) | Successes(..) | Counted(..) | Sided(..) | Grp(_) | Abs(_) | Min(_) | Max(_) | Clamp(..) => true,
+            Neg(_) | Add(..) | Sub(..) | Mul(..) | Div(..) | Mod(..) | Pow(..) => false,
+        }
+    }
+}
+
 impl Display for Roll {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         use Roll::*;
         match self {
             Num(i) => write!(f, "{}", i),
-            Dice(v) => write!(f, "{:?}", v),
+            Dice(v, faces) => {
+                write!(f, "[")?;
+                for (i, d) in v.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    d.fmt_marked(faces, f)?;
+                }
+                write!(f, "]")
+            }
             ManyDice(m) => {
                 write!(f, "[")?;
                 let mut first = true;
@@ -127,12 +782,1094 @@ impl Display for Roll {
                 }
                 write!(f, "]")
             }
-            TooManyDice(i) => write!(f, "[{}]", i),
+            TooManyDice(s) => write!(f, "{}", s),
+            Loaded(expr) => write!(f, "loaded{}", expr),
+            Counted(times, expr) if times.is_atomic() => write!(f, "{}d{}", times, expr),
+            Counted(times, expr) => write!(f, "({})d{}", times, expr),
+            Sided(sides, expr) => write!(f, "d{}{}", sides, expr),
+            Fudge(expr) => match expr.as_ref() {
+                Dice(v, _) => {
+                    write!(f, "[")?;
+                    for (i, d) in v.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        let face = match d.value {
+                            v if v > 0 => "+",
+                            v if v < 0 => "-",
+                            _ => "0",
+                        };
+                        match d.dropped {
+                            true => write!(f, "~~{}~~", face)?,
+                            false => write!(f, "{}", face)?,
+                        }
+                    }
+                    write!(f, "]")
+                }
+                expr => write!(f, "{}", expr),
+            },
+            Successes(expr, hit, miss) => {
+                Self::fmt_successes(expr, hit, miss.as_ref(), f)?;
+                let hits = match expr.count_successes(hit) {
+                    1 => "1 success".to_string(),
+                    n => format!("{} successes", n),
+                };
+                match miss.map(|c| expr.count_successes(&c)) {
+                    None => write!(f, " ({})", hits),
+                    Some(1) => write!(f, " ({} - 1 failure)", hits),
+                    Some(n) => write!(f, " ({} - {} failures)", hits, n),
+                }
+            }
+            Grp(expr) if expr.is_atomic() => write!(f, "{}", expr),
             Grp(expr) => write!(f, "({})", expr),
+            Neg(expr) if expr.is_atomic() => write!(f, "-{}", expr),
+            Neg(expr) => write!(f, "-({})", expr),
+            Abs(expr) => write!(f, "abs({})", expr),
             Add(lhs, rhs) => write!(f, "{} + {}", lhs, rhs),
             Sub(lhs, rhs) => write!(f, "{} - {}", lhs, rhs),
-            Mul(lhs, rhs) => write!(f, "{} * {}", lhs, rhs),
-            Div(lhs, rhs) => write!(f, "{} / {}", lhs, rhs),
+            // Escaped so the operator isn't read as the start of bold text.
+            Mul(lhs, rhs) => write!(f, "{} \\* {}", lhs, rhs),
+            Div(lhs, rhs, DivMode::Trunc) => write!(f, "{} / {}", lhs, rhs),
+            Div(lhs, rhs, DivMode::Floor) => write!(f, "{} /f {}", lhs, rhs),
+            Div(lhs, rhs, DivMode::Ceil) => write!(f, "{} /c {}", lhs, rhs),
+            Div(lhs, rhs, DivMode::Round) => write!(f, "{} /r {}", lhs, rhs),
+            Mod(lhs, rhs) => write!(f, "{} % {}", lhs, rhs),
+            Pow(lhs, rhs) => write!(f, "{} ^ {}", lhs, rhs),
+            Min(args) => Self::fmt_call("min", args, f),
+            Max(args) => Self::fmt_call("max", args, f),
+            Clamp(expr, lo, hi) => write!(f, "clamp({}, {}, {})", expr, lo, hi),
+        }
+    }
+}
+impl Roll {
+    fn fmt_call(name: &str, args: &[Roll], f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}(", name)?;
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", arg)?;
+        }
+        write!(f, ")")
+    }
+
+    /// Writes a pool with the faces meeting `hit` in bold and the rest of
+    /// those meeting `miss` in italics.
+    fn fmt_successes(&self, hit: &Condition, miss: Option<&Condition>, f: &mut Formatter<'_>) -> std::fmt::Result {
+        use Roll::*;
+
+        match self {
+            Dice(v, _) => {
+                write!(f, "[")?;
+                for (i, d) in v.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match d.dropped {
+                        false if hit.matches(d.value) => write!(f, "*{}*", d)?,
+                        false if miss.is_some_and(|c| c.matches(d.value)) => write!(f, "_{}_", d)?,
+                        _ => write!(f, "{}", d)?,
+                    }
+                }
+                write!(f, "]")
+            }
+            ManyDice(m) => {
+                write!(f, "[")?;
+                for (i, (face, times)) in m.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match face {
+                        face if hit.matches(*face) => write!(f, "*{}:{}*", face, times)?,
+                        face if miss.is_some_and(|c| c.matches(*face)) => write!(f, "_{}:{}_", face, times)?,
+                        face => write!(f, "{}:{}", face, times)?,
+                    }
+                }
+                write!(f, "]")
+            }
+            Loaded(expr) => {
+                write!(f, "loaded")?;
+                expr.fmt_successes(hit, miss, f)
+            }
+            roll => write!(f, "{}", roll),
+        }
+    }
+}
+
+impl Die {
+    /// Writes the die with its highest face in bold and its lowest in italics,
+    /// so that criticals and fumbles stand out. Dropped dice aren't marked.
+    fn fmt_marked(&self, faces: &RangeInclusive<i64>, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.value {
+            _ if self.dropped || faces.start() == faces.end() => write!(f, "{}", self),
+            v if v >= *faces.end() => write!(f, "*{}*", self),
+            v if v == *faces.start() => write!(f, "_{}_", self),
+            _ => write!(f, "{}", self),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Display for Die {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.dropped {
+            write!(f, "~~")?;
+        }
+        if let Some(original) = self.rerolled {
+            write!(f, "{}→", original)?;
+        }
+        if let Some(face) = self.raised {
+            write!(f, "{}↑", face)?;
+        }
+        write!(f, "{}", self.value)?;
+        if self.exploded {
+            write!(f, "!")?;
+        }
+        if self.dropped {
+            write!(f, "~~")?;
+        }
+        Ok(())
+    }
+}
+
+/// Serializes each node as a map tagged with its `kind` and `total`, followed
+/// by its operands or dice. Totals that overflow are `null`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Roll {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        use Roll::*;
+
+        let kind = match self {
+            Num(_) => "num",
+            Dice(..) => "dice",
+            ManyDice(_) => "many_dice",
+            TooManyDice(_) => "too_many_dice",
+            Loaded(_) => "loaded",
+            Counted(..) => "counted",
+            Sided(..) => "sided",
+            Fudge(_) => "fudge",
+            Successes(..) => "successes",
+            Grp(_) => "group",
+            Neg(_) => "neg",
+            Abs(_) => "abs",
+            Add(..) => "add",
+            Sub(..) => "sub",
+            Mul(..) => "mul",
+            Div(..) => "div",
+            Mod(..) => "mod",
+            Pow(..) => "pow",
+            Min(_) => "min",
+            Max(_) => "max",
+            Clamp(..) => "clamp",
+        };
+
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("kind", kind)?;
+        map.serialize_entry("total", &self.value().ok())?;
+        match self {
+            Num(_) => {}
+            TooManyDice(s) => {
+                map.serialize_entry("count", &s.count)?;
+                map.serialize_entry("min", &s.min)?;
+                map.serialize_entry("max", &s.max)?;
+                map.serialize_entry("mean", &s.mean())?;
+            }
+            Dice(dice, faces) => {
+                map.serialize_entry("dice", dice)?;
+                map.serialize_entry("faces", &[faces.start(), faces.end()])?;
+            }
+            ManyDice(m) => map.serialize_entry("counts", &m.iter().collect::<Vec<_>>())?,
+            Loaded(expr) | Fudge(expr) | Successes(expr, ..) | Grp(expr) | Neg(expr) | Abs(expr) => map.serialize_entry("roll", expr)?,
+            Counted(count, expr) => {
+                map.serialize_entry("count", count)?;
+                map.serialize_entry("roll", expr)?;
+            }
+            Sided(sides, expr) => {
+                map.serialize_entry("sides", sides)?;
+                map.serialize_entry("roll", expr)?;
+            }
+            Add(lhs, rhs) | Sub(lhs, rhs) | Mul(lhs, rhs) | Mod(lhs, rhs) | Pow(lhs, rhs) => {
+                map.serialize_entry("lhs", lhs)?;
+                map.serialize_entry("rhs", rhs)?;
+            }
+            Div(lhs, rhs, mode) => {
+                map.serialize_entry("lhs", lhs)?;
+                map.serialize_entry("rhs", rhs)?;
+                map.serialize_entry("rounding", &format!("{:?}", mode).to_lowercase())?;
+            }
+            Min(args) | Max(args) => map.serialize_entry("args", args)?,
+            Clamp(expr, lo, hi) => {
+                map.serialize_entry("roll", expr)?;
+                map.serialize_entry("lo", lo)?;
+                map.serialize_entry("hi", hi)?;
+            }
+        }
+        map.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Die {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(Some(5))?;
+        map.serialize_entry("value", &self.value)?;
+        map.serialize_entry("rerolled", &self.rerolled)?;
+        map.serialize_entry("dropped", &self.dropped)?;
+        map.serialize_entry("exploded", &self.exploded)?;
+        map.serialize_entry("raised", &self.raised)?;
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Compare;
+    use rand::rngs::StdRng;
+
+    /// An RNG that fails the test if anything attempts to sample from it.
+    struct UnusedRng;
+
+    impl RngCore for UnusedRng {
+        fn next_u32(&mut self) -> u32 { panic!("RNG should not be used") }
+        fn next_u64(&mut self) -> u64 { panic!("RNG should not be used") }
+        fn fill_bytes(&mut self, _: &mut [u8]) { panic!("RNG should not be used") }
+        fn try_fill_bytes(&mut self, _: &mut [u8]) -> Result<(), rand::Error> { panic!("RNG should not be used") }
+    }
+
+    fn roll(input: &str, rng: &mut impl Rng) -> Roll {
+        let expr = Expression::try_from(input).unwrap();
+        Roll::from_expr_with_rng(&expr, rng)
+    }
+
+    /// Formats a face of a fair die the way `Display` marks it.
+    fn marked(face: i64, sides: i64) -> String {
+        match face {
+            f if f == sides => format!("*{}*", f),
+            1 => "_1_".to_string(),
+            f => f.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_constant() {
+        let tests = [
+            ("3 + 4 * 2", 11),
+            ("(1 - 5) / 2", -2),
+            ("7 / 0", 0),
+            ("-7 /f 2", -4),
+            ("-7 / 2", -3),
+            ("-7 // 2", -4),
+            ("7 // 2", 3),
+            ("-7 // 0", 0),
+            ("-7 /c 2", -3),
+            ("7 /r 2", 4),
+            ("-7 /t 2", -3),
+            ("-7 /R 0", 0),
+            ("max(3, 9, -1)", 9),
+            ("min(3, 9 - 7) * 2", 4),
+            ("abs(3 - 10)", 7),
+            ("-abs(-4)", -4),
+            ("17 % 5", 2),
+            ("-17 % 5", -2),
+            ("7 % 0", 0),
+            ("2 ^ 3 ^ 2", 512),
+            ("2 * 3 ^ 2", 18),
+            ("2 ^ 64", i64::MAX),
+        ];
+
+        for (input, ex) in tests {
+            let r = roll(input, &mut UnusedRng);
+            assert_eq!(ex, r.value().unwrap(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_reroll_lowest() {
+        for seed in 0..100 {
+            let r = roll("4d6rl", &mut StdRng::seed_from_u64(seed));
+            let dice = match r {
+                Roll::Dice(dice, _) => dice,
+                r => panic!("unexpected roll: {:?}", r),
+            };
+
+            let rerolled: Vec<&Die> = dice.iter().filter(|d| d.rerolled.is_some()).collect();
+            assert_eq!(1, rerolled.len(), "seed {}", seed);
+
+            let original = rerolled[0].rerolled.unwrap();
+            let first_lowest = dice.iter()
+                .position(|d| d.rerolled.is_some() || d.value <= original)
+                .unwrap();
+            assert!(dice[first_lowest].rerolled.is_some(), "seed {}: {:?}", seed, dice);
+            assert!(dice.iter().all(|d| d.rerolled.is_some() || d.value >= original));
+        }
+    }
+
+    #[test]
+    fn test_reroll_ones() {
+        for seed in 0..100 {
+            let expr = Expression::try_from("8d6").unwrap().reroll_ones();
+            let dice = match Roll::from_expr_with_rng(&expr, &mut StdRng::seed_from_u64(seed)) {
+                Roll::Dice(dice, _) => dice,
+                r => panic!("unexpected roll: {:?}", r),
+            };
+
+            for d in &dice {
+                match d.rerolled {
+                    Some(original) => assert_eq!(1, original, "seed {}: {:?}", seed, dice),
+                    None => assert_ne!(1, d.value, "seed {}: {:?}", seed, dice),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_percentile() {
+        for seed in 0..100 {
+            let r = roll("d% + 5", &mut StdRng::seed_from_u64(seed));
+            let faces = r.evaluate_all();
+            assert!((1..=100).contains(&faces[0]), "seed {}: {}", seed, r);
+            assert_eq!(format!("[{}] + 5", marked(faces[0], 100)), r.to_string());
+            assert_eq!(faces[0] + 5, r.value().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_successes() {
+        for seed in 0..100 {
+            let r = roll("8d10>=7", &mut StdRng::seed_from_u64(seed));
+            let faces = r.evaluate_all();
+            assert_eq!(8, faces.len());
+            assert_eq!(faces.iter().filter(|f| **f >= 7).count() as i64, r.value().unwrap(), "seed {}: {}", seed, r);
+        }
+
+        // large pools with many sides would normally be reduced to a sum
+        for input in ["50d6>4", "50d100<=10", "30d100!>=90>=90"] {
+            let r = roll(input, &mut StdRng::seed_from_u64(5));
+            let cond = match &r {
+                Roll::Successes(_, cond, _) => *cond,
+                r => panic!("unexpected roll: {:?}", r),
+            };
+            let ex = r.evaluate_all().into_iter().filter(|f| cond.matches(*f)).count() as i64;
+            assert_eq!(ex, r.value().unwrap(), "{}: {}", input, r);
+        }
+
+        let mut r = roll("(6d6>=5)kh2", &mut StdRng::seed_from_u64(9));
+        let kept = kept(&mut r);
+        assert_eq!(kept.iter().filter(|f| **f >= 5).count() as i64, r.value().unwrap(), "{}", r);
+    }
+
+    #[test]
+    fn test_net_successes() {
+        for seed in 0..100 {
+            let r = roll("10d10>=8f<=1", &mut StdRng::seed_from_u64(seed));
+            let faces = r.evaluate_all();
+            let ex = faces.iter().filter(|f| **f >= 8).count() as i64 - faces.iter().filter(|f| **f <= 1).count() as i64;
+            assert_eq!(ex, r.value().unwrap(), "seed {}: {}", seed, r);
+        }
+
+        // all failures and no successes nets below zero, and the total carries through arithmetic
+        let r = roll("3d1>=2f=1 - 1", &mut StdRng::seed_from_u64(0));
+        assert_eq!(Ok(-4), r.value());
+        assert_eq!("[_1_, _1_, _1_] (0 successes - 3 failures) - 1", r.to_string());
+    }
+
+    #[test]
+    fn test_minimum() {
+        for seed in 0..50 {
+            let r = roll("4d6mi3", &mut StdRng::seed_from_u64(seed));
+            let raw = roll("4d6", &mut StdRng::seed_from_u64(seed)).evaluate_all();
+            assert_eq!(raw.iter().map(|f| (*f).max(3)).collect::<Vec<_>>(), r.evaluate_all(), "seed {}", seed);
+
+            let mut r = roll("4d6mi3kh2", &mut StdRng::seed_from_u64(seed));
+            let mut faces = r.evaluate_all();
+            faces.sort();
+            assert_eq!(faces[2] + faces[3], r.value().unwrap(), "seed {}: {}", seed, r);
+            let mut kept = kept(&mut r);
+            kept.sort();
+            assert_eq!(faces[2..], kept[..], "seed {}", seed);
+        }
+
+        // large pools are still raised before they're tallied
+        let r = roll("50d6mi6", &mut StdRng::seed_from_u64(1));
+        assert_eq!(Ok(300), r.value());
+
+        let d = Die { raised: Some(1), ..Die::new(2) };
+        assert_eq!("1↑2", d.to_string());
+        let d = Die { rerolled: Some(5), raised: Some(1), dropped: true, ..Die::new(2) };
+        assert_eq!("~~5→1↑2~~", d.to_string());
+    }
+
+    #[test]
+    fn test_preview() {
+        let preview = |input: &str| Roll::preview(&Expression::try_from(input).unwrap());
+        for (input, display, value) in [
+            ("d20", "[10]", 10),
+            ("z10", "[4]", 4),
+            ("4d6kh3", "[3, 3, 3, ~~3~~]", 9),
+            ("d6r1", "[4]", 4),
+            ("2d6mi3", "[4, 4]", 8),
+            ("d6!", "[3]", 3),
+            ("4dF", "[0, 0, 0, 0]", 0),
+            ("(2d4)d6", "[2, 2]d[3, 3, 3, 3]", 12),
+            ("8d10>=7", "[5, 5, 5, 5, 5, 5, 5, 5] (0 successes)", 0),
+            ("7 * 3", "21", 21),
+        ] {
+            let r = preview(input);
+            assert_eq!(display, r.to_string(), "{}", input);
+            assert_eq!(Ok(value), r.value(), "{}", input);
+        }
+
+        // large pools are aggregated just as they are when rolled
+        assert!(matches!(preview("1000d6"), Roll::ManyDice(_)));
+        assert_eq!(Ok(3000), preview("1000d6").value());
+    }
+
+    #[test]
+    fn test_natural_d20() {
+        assert_eq!(Some(20), Roll::Dice(vec![Die::new(20)], 1..=20).natural_d20());
+        assert_eq!(Some(1), Roll::Dice(vec![Die { raised: Some(1), ..Die::new(5) }], 1..=20).natural_d20());
+        assert_eq!(None, Roll::Dice(vec![Die::new(20), Die::new(3)], 1..=20).natural_d20());
+        assert_eq!(None, Roll::Dice(vec![Die::new(6)], 1..=6).natural_d20());
+        assert_eq!(None, Roll::Dice(vec![Die::new(19)], 0..=19).natural_d20());
+        for input in ["d20 + 5", "2d20kh1", "d20>=10"] {
+            assert_eq!(None, roll(input, &mut StdRng::seed_from_u64(0)).natural_d20(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_sort() {
+        for seed in 0..20 {
+            let unsorted = roll("6d6 + 4d6kh2", &mut StdRng::seed_from_u64(seed));
+            let ascending = roll("6d6s + 4d6skh2", &mut StdRng::seed_from_u64(seed));
+            let descending = roll("6d6sd + 4d6sdkh2", &mut StdRng::seed_from_u64(seed));
+            assert_eq!(unsorted.value(), ascending.value(), "seed {}", seed);
+            assert_eq!(unsorted.value(), descending.value(), "seed {}", seed);
+
+            let mut faces = unsorted.evaluate_all();
+            faces[..6].sort();
+            faces[6..].sort();
+            assert_eq!(faces, ascending.evaluate_all(), "seed {}", seed);
+            faces[..6].reverse();
+            faces[6..].reverse();
+            assert_eq!(faces, descending.evaluate_all(), "seed {}", seed);
+        }
+
+        let r = roll("3d1s", &mut StdRng::seed_from_u64(0));
+        assert_eq!("[1, 1, 1]", r.to_string());
+    }
+
+    #[test]
+    fn test_successes_display() {
+        let cond = Condition { cmp: Compare::Ge, target: 5 };
+        let r = Roll::Successes(Box::new(Roll::Dice(vec![Die::new(6), Die::new(2), Die::new(5)], 1..=6)), cond, None);
+        assert_eq!("[*6*, 2, *5*] (2 successes)", r.to_string());
+
+        let r = Roll::Successes(Box::new(Roll::Dice(vec![Die::new(1), Die::new(5)], 1..=6)), cond, None);
+        assert_eq!("[1, *5*] (1 success)", r.to_string());
+
+        let fail = Condition { cmp: Compare::Le, target: 1 };
+        let r = Roll::Successes(Box::new(Roll::Dice(vec![Die::new(1), Die::new(5), Die::new(3)], 1..=6)), cond, Some(fail));
+        assert_eq!("[_1_, *5*, 3] (1 success - 1 failure)", r.to_string());
+    }
+
+    #[test]
+    fn test_div_mode_display() {
+        let r = roll("d6 /f 2 + d6 / 2", &mut StdRng::seed_from_u64(0));
+        let faces = r.evaluate_all();
+        assert_eq!(format!("[{}] /f 2 + [{}] / 2", marked(faces[0], 6), marked(faces[1], 6)), r.to_string());
+    }
+
+    #[test]
+    fn test_min_max() {
+        for seed in 0..50 {
+            let r = roll("max(2d6, 1d12) + min(d20 + 5, 10)", &mut StdRng::seed_from_u64(seed));
+            let f = r.evaluate_all();
+            assert_eq!((f[0] + f[1]).max(f[2]) + (f[3] + 5).min(10), r.value().unwrap(), "seed {}: {}", seed, r);
+            assert_eq!(
+                format!(
+                    "max([{}, {}], [{}]) + min([{}] + 5, 10)",
+                    marked(f[0], 6), marked(f[1], 6), marked(f[2], 12), marked(f[3], 20),
+                ),
+                r.to_string());
+        }
+    }
+
+    #[test]
+    fn test_abs() {
+        for seed in 0..50 {
+            let r = roll("abs(d20 - d20)", &mut StdRng::seed_from_u64(seed));
+            let f = r.evaluate_all();
+            assert_eq!((f[0] - f[1]).abs(), r.value().unwrap(), "seed {}: {}", seed, r);
+            assert_eq!(format!("abs([{}] - [{}])", marked(f[0], 20), marked(f[1], 20)), r.to_string());
+        }
+    }
+
+    #[test]
+    fn test_clamp() {
+        for seed in 0..50 {
+            let r = roll("clamp(d20 + 5, 1, 20)", &mut StdRng::seed_from_u64(seed));
+            let f = r.evaluate_all();
+            assert_eq!((f[0] + 5).min(20), r.value().unwrap(), "seed {}: {}", seed, r);
+            assert_eq!(format!("clamp([{}] + 5, 1, 20)", marked(f[0], 20)), r.to_string());
+        }
+        assert_eq!(Ok(3), roll("clamp(9, 3, 1)", &mut StdRng::seed_from_u64(0)).value());
+    }
+
+    #[test]
+    fn test_counted() {
+        for seed in 0..50 {
+            let mut r = roll("(d4)d6kh2", &mut StdRng::seed_from_u64(seed));
+            let faces = r.evaluate_all();
+            let count = faces[0] as usize;
+            assert_eq!(count + 1, faces.len(), "seed {}: {}", seed, r);
+            assert_eq!(count.min(2), kept(&mut r).len(), "seed {}: {}", seed, r);
+        }
+
+        let r = roll("(d4 - 10)d6", &mut StdRng::seed_from_u64(0));
+        assert_eq!(0, r.value().unwrap());
+        assert_eq!(1, r.evaluate_all().len());
+
+        let r = Roll::Counted(Box::new(Roll::Num(2)), Box::new(Roll::Dice(vec![Die::new(3), Die::new(4)], 1..=6)));
+        assert_eq!("2d[3, 4]", r.to_string());
+        assert_eq!(7, r.value().unwrap());
+    }
+
+    #[test]
+    fn test_sided() {
+        for seed in 0..50 {
+            let r = roll("3d(2d6)", &mut StdRng::seed_from_u64(seed));
+            let faces = r.evaluate_all();
+            let sides = faces[0] + faces[1];
+            assert_eq!(5, faces.len(), "seed {}: {}", seed, r);
+            assert!(faces[2..].iter().all(|f| (1..=sides).contains(f)), "seed {}: {}", seed, r);
+        }
+
+        let r = roll("2d(d4 - 10)", &mut StdRng::seed_from_u64(0));
+        assert_eq!(0, r.value().unwrap());
+        assert_eq!(1, r.evaluate_all().len());
+
+        let r = roll("d(9223372036854775807 + d6)", &mut StdRng::seed_from_u64(0));
+        assert_eq!(Err(EvalError::Overflow), r.value());
+
+        let r = Roll::Sided(
+            Box::new(Roll::Grp(Box::new(Roll::Dice(vec![Die::new(3)], 1..=4)))),
+            Box::new(Roll::Dice(vec![Die::new(1), Die::new(2)], 1..=3)),
+        );
+        assert_eq!("d[3][_1_, 2]", r.to_string());
+        assert_eq!(3, r.value().unwrap());
+    }
+
+    #[test]
+    fn test_terms() {
+        let r = roll("2d6 - (d4 + 1) + 3 * d8", &mut StdRng::seed_from_u64(0));
+        let terms: Vec<_> = r.terms().into_iter().map(|(sub, t)| (sub, matches!(t, Roll::Grp(_) | Roll::Mul(..)))).collect();
+        assert_eq!(vec![(false, false), (true, true), (false, true)], terms);
+
+        let r = roll("max(d4, d6)", &mut StdRng::seed_from_u64(0));
+        assert_eq!(1, r.terms().len());
+    }
+
+    #[test]
+    fn test_mod() {
+        for seed in 0..50 {
+            let r = roll("d20 % 6", &mut StdRng::seed_from_u64(seed));
+            let face = r.evaluate_all()[0];
+            assert_eq!(face % 6, r.value().unwrap(), "seed {}: {}", seed, r);
+            assert_eq!(format!("[{}] % 6", marked(face, 20)), r.to_string());
+        }
+
+        let r = roll("d% % 0", &mut StdRng::seed_from_u64(0));
+        assert_eq!(0, r.value().unwrap());
+    }
+
+    #[test]
+    fn test_strict_division() {
+        for (input, lenient) in [("7 / 0", 0), ("d6 // (1 - 1)", 0), ("7 % 0", 0), ("2d20 % (d1 - 1) + 1", 1), ("0 ^ -1", 0)] {
+            let r = roll(input, &mut StdRng::seed_from_u64(0));
+            assert_eq!(Ok(lenient), r.total(false), "{}", input);
+            assert_eq!(Err(EvalError::DivisionByZero), r.total(true), "{}", input);
+        }
+
+        let r = roll("7 / 2 + 5 % 3 + 0 ^ 2", &mut UnusedRng);
+        assert_eq!(Ok(5), r.total(true));
+        assert_eq!("division by zero", EvalError::DivisionByZero.to_string());
+    }
+
+    #[test]
+    fn test_fudge() {
+        let mut negative = false;
+        for seed in 0..100 {
+            let r = roll("4dF", &mut StdRng::seed_from_u64(seed));
+            let faces = r.evaluate_all();
+            assert_eq!(4, faces.len());
+            assert!(faces.iter().all(|f| (-1..=1).contains(f)), "seed {}: {}", seed, r);
+            assert_eq!(faces.iter().sum::<i64>(), r.value().unwrap());
+            negative |= r.value().unwrap() < 0;
+        }
+        assert!(negative, "fudge totals should be able to go negative");
+
+        let r = roll("100dF", &mut StdRng::seed_from_u64(1));
+        assert_eq!(100, r.evaluate_all().len());
+        assert_eq!(r.evaluate_all().iter().sum::<i64>(), r.value().unwrap());
+    }
+
+    #[test]
+    fn test_fudge_display() {
+        let r = Roll::Fudge(Box::new(Roll::Dice(vec![Die::new(1), Die::new(0), Die::new(-1), Die::new(-1)], -1..=1)));
+        assert_eq!("[+, 0, -, -]", r.to_string());
+        assert_eq!(-1, r.value().unwrap());
+
+        let mut r = roll("4dFkh2", &mut StdRng::seed_from_u64(2));
+        assert_eq!(2, kept(&mut r).len());
+        assert_eq!(2, r.to_string().matches("~~").count() / 2, "{}", r);
+    }
+
+    #[test]
+    fn test_explode() {
+        for seed in 0..100 {
+            let dice = match roll("3d6!", &mut StdRng::seed_from_u64(seed)) {
+                Roll::Dice(dice, _) => dice,
+                r => panic!("unexpected roll: {:?}", r),
+            };
+
+            let exploded = dice.iter().filter(|d| d.exploded).count();
+            assert_eq!(3 + exploded, dice.len(), "seed {}: {:?}", seed, dice);
+            assert!(dice.iter().all(|d| d.exploded == (d.value == 6)), "seed {}: {:?}", seed, dice);
+        }
+    }
+
+    #[test]
+    fn test_explode_cap() {
+        let r = roll("1000d1!", &mut StdRng::seed_from_u64(0));
+        assert_eq!(1000 + MAX_EXPLOSIONS as i64, r.value().unwrap());
+
+        // every face explodes, so only the cap stops the dice
+        let r = roll("3d6!>=1", &mut StdRng::seed_from_u64(0));
+        assert_eq!(3 + MAX_EXPLOSIONS, r.evaluate_all().len());
+        let r = roll("3d6!!>=1", &mut StdRng::seed_from_u64(0));
+        assert_eq!(3, r.evaluate_all().len());
+        assert!(r.value().unwrap() >= 3 + MAX_EXPLOSIONS as i64, "{}", r);
+    }
+
+    #[test]
+    fn test_explode_on() {
+        for seed in 0..100 {
+            let dice = match roll("3d10!>=9", &mut StdRng::seed_from_u64(seed)) {
+                Roll::Dice(dice, _) => dice,
+                r => panic!("unexpected roll: {:?}", r),
+            };
+
+            let exploded = dice.iter().filter(|d| d.exploded).count();
+            assert_eq!(3 + exploded, dice.len(), "seed {}: {:?}", seed, dice);
+            assert!(dice.iter().all(|d| d.exploded == (d.value >= 9)), "seed {}: {:?}", seed, dice);
+        }
+    }
+
+    #[test]
+    fn test_compound() {
+        for seed in 0..100 {
+            let dice = match roll("3d6!!", &mut StdRng::seed_from_u64(seed)) {
+                Roll::Dice(dice, _) => dice,
+                r => panic!("unexpected roll: {:?}", r),
+            };
+
+            assert_eq!(3, dice.len());
+            assert!(dice.iter().all(|d| d.value % 6 != 0), "seed {}: {:?}", seed, dice);
+        }
+
+        let r = roll("1000d1!!", &mut StdRng::seed_from_u64(0));
+        assert_eq!(1000 + MAX_EXPLOSIONS as i64, r.value().unwrap());
+    }
+
+    #[test]
+    fn test_compound_display() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let r = roll("2d1!!", &mut rng);
+        assert_eq!(format!("[{}, 1]", 1 + MAX_EXPLOSIONS), r.to_string());
+    }
+
+    #[test]
+    fn test_explode_display() {
+        let r = Roll::Dice(vec![Die { exploded: true, ..Die::new(6) }, Die::new(2), Die::new(4)], 1..=6);
+        assert_eq!("[*6!*, 2, 4]", r.to_string());
+        assert_eq!(12, r.value().unwrap());
+    }
+
+    #[test]
+    fn test_reroll_once() {
+        for seed in 0..100 {
+            let dice = match roll("6d6ro2", &mut StdRng::seed_from_u64(seed)) {
+                Roll::Dice(dice, _) => dice,
+                r => panic!("unexpected roll: {:?}", r),
+            };
+
+            for d in &dice {
+                match d.rerolled {
+                    Some(original) => assert!(original <= 2, "seed {}: {:?}", seed, dice),
+                    None => assert!(d.value > 2, "seed {}: {:?}", seed, dice),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_reroll() {
+        for seed in 0..100 {
+            let r = roll("4d6r2", &mut StdRng::seed_from_u64(seed));
+            assert!(r.evaluate_all().iter().all(|f| *f > 2), "seed {}: {}", seed, r);
+        }
+
+        let r = roll("3d1r1", &mut StdRng::seed_from_u64(0));
+        assert_eq!(3, r.value().unwrap());
+        // the cap is shared by the whole term, so the first die uses it all up
+        assert_eq!("[1→1, 1, 1]", r.to_string());
+    }
+
+    #[test]
+    fn test_reroll_once_display() {
+        let r = Roll::Dice(vec![Die { rerolled: Some(2), ..Die::new(1) }, Die::new(5)], 1..=6);
+        assert_eq!("[_2→1_, 5]", r.to_string());
+        assert_eq!(6, r.value().unwrap());
+    }
+
+    #[test]
+    fn test_reroll_lowest_display() {
+        let r = Roll::Dice(vec![Die::new(4), Die { rerolled: Some(1), ..Die::new(6) }], 1..=6);
+        assert_eq!("[4, *1→6*]", r.to_string());
+        assert_eq!(10, r.value().unwrap());
+    }
+
+    fn kept(roll: &mut Roll) -> Vec<i64> {
+        roll.dice_mut().into_iter().filter(|d| !d.dropped).map(|d| d.value).collect()
+    }
+
+    #[test]
+    fn test_keep_across_group() {
+        for seed in 0..100 {
+            let mut r = roll("(2d6 + 1d4)kh2", &mut StdRng::seed_from_u64(seed));
+            let mut all: Vec<i64> = r.dice_mut().into_iter().map(|d| d.value).collect();
+            assert_eq!(3, all.len());
+
+            let kept = kept(&mut r);
+            assert_eq!(2, kept.len());
+
+            all.sort();
+            assert_eq!(all[1] + all[2], r.value().unwrap(), "seed {}: {}", seed, r);
+            assert_eq!(kept.iter().sum::<i64>(), r.value().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_keep_excludes_constants() {
+        for seed in 0..100 {
+            let mut r = roll("(3d6 + 10)kl1", &mut StdRng::seed_from_u64(seed));
+            let lowest = r.dice_mut().into_iter().map(|d| d.value).min().unwrap();
+            assert_eq!(1, kept(&mut r).len());
+            assert_eq!(lowest + 10, r.value().unwrap(), "seed {}: {}", seed, r);
+        }
+    }
+
+    /// Every face of the pool in `input` in descending order, rolled die by
+    /// die just as a keep rolls it.
+    fn pool_faces(input: &str, seed: u64) -> Vec<i64> {
+        let expr = Expression::try_from(input).unwrap();
+        let mut faces = Roll::sample(&expr, &mut StdRng::seed_from_u64(seed), true).evaluate_all();
+        faces.sort_by_key(|f| std::cmp::Reverse(*f));
+        faces
+    }
+
+    #[test]
+    fn test_keep_large_pool() {
+        for seed in 0..20 {
+            // the dropped dice are discarded, leaving few enough to list
+            let mut r = roll("50d6kh10", &mut StdRng::seed_from_u64(seed));
+            let highest = &pool_faces("50d6", seed)[..10];
+            assert!(matches!(r, Roll::Dice(..)), "seed {}: {:?}", seed, r);
+            assert_eq!(10, r.dice_mut().len());
+            assert_eq!(highest.iter().sum::<i64>(), r.value().unwrap(), "seed {}: {}", seed, r);
+
+            // too many are kept to list, so they're tallied
+            let r = roll("50d6kh30", &mut StdRng::seed_from_u64(seed));
+            let highest = &pool_faces("50d6", seed)[..30];
+            assert!(matches!(r, Roll::ManyDice(_)), "seed {}: {:?}", seed, r);
+            assert_eq!(30, r.evaluate_all().len());
+            assert_eq!(highest.iter().sum::<i64>(), r.value().unwrap(), "seed {}: {}", seed, r);
+        }
+
+        // small pools still show the dice they dropped
+        let mut r = roll("6d6kh3", &mut StdRng::seed_from_u64(0));
+        assert_eq!(6, r.dice_mut().len());
+
+        // nested keeps select from every die before any are discarded
+        let r = roll("(50d6kh30)kl5", &mut StdRng::seed_from_u64(1));
+        let faces = pool_faces("50d6", 1);
+        assert_eq!(faces[25..30].iter().sum::<i64>(), r.value().unwrap(), "{}", r);
+    }
+
+    #[test]
+    fn test_keep_low_large_pool() {
+        // pools this size are normally tallied or summed, losing the individual dice
+        for input in ["30d6kl1", "25d100kl1"] {
+            let r = roll(input, &mut StdRng::seed_from_u64(3));
+            let lowest = *pool_faces(&input[..input.len() - 3], 3).last().unwrap();
+            assert_eq!(vec![lowest], r.evaluate_all(), "{}", input);
+            assert_eq!(lowest, r.value().unwrap(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_keep_high_bounds() {
+        for seed in 0..20 {
+            let mut r = roll("2d20kh5", &mut StdRng::seed_from_u64(seed));
+            let all: i64 = r.dice_mut().into_iter().map(|d| d.value).sum();
+            assert_eq!(2, kept(&mut r).len(), "seed {}: {}", seed, r);
+            assert_eq!(all, r.value().unwrap());
+
+            let mut r = roll("3d6kh0 + 2", &mut StdRng::seed_from_u64(seed));
+            assert!(kept(&mut r).is_empty(), "seed {}: {}", seed, r);
+            assert_eq!(2, r.value().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_drop() {
+        for seed in 0..100 {
+            let mut r = roll("4d6dl1", &mut StdRng::seed_from_u64(seed));
+            let mut all: Vec<i64> = r.dice_mut().into_iter().map(|d| d.value).collect();
+            all.sort();
+            assert_eq!(3, kept(&mut r).len());
+            assert_eq!(all[1..].iter().sum::<i64>(), r.value().unwrap(), "seed {}: {}", seed, r);
+
+            let mut r = roll("3d8dh2", &mut StdRng::seed_from_u64(seed));
+            let lowest = r.dice_mut().into_iter().map(|d| d.value).min().unwrap();
+            assert_eq!(lowest, r.value().unwrap(), "seed {}: {}", seed, r);
+        }
+
+        let mut r = roll("2d6dl5", &mut StdRng::seed_from_u64(1));
+        assert!(kept(&mut r).is_empty());
+        assert_eq!(0, r.value().unwrap());
+    }
+
+    #[test]
+    fn test_drop_display() {
+        let mut r = Roll::Dice(vec![Die::new(4), Die::new(2), Die::new(6), Die::new(2)], 1..=6);
+        r.select(Selection::DropLow(1));
+        assert_eq!("[4, 2, *6*, ~~2~~]", r.to_string());
+        assert_eq!(12, r.value().unwrap());
+    }
+
+    #[test]
+    fn test_advantage() {
+        for seed in 0..50 {
+            let r = roll("d20 adv", &mut StdRng::seed_from_u64(seed));
+            let f = r.evaluate_all();
+            assert_eq!(f[0].max(f[1]), r.value().unwrap(), "seed {}: {}", seed, r);
+            // both dice are shown, with the one not used struck out
+            let (kept, dropped) = if f[0] >= f[1] { (0, 1) } else { (1, 0) };
+            assert!(r.to_string().contains(&marked(f[kept], 20)), "seed {}: {}", seed, r);
+            assert!(r.to_string().contains(&format!("~~{}~~", f[dropped])), "seed {}: {}", seed, r);
+
+            let r = roll("d20 dis", &mut StdRng::seed_from_u64(seed));
+            let f = r.evaluate_all();
+            assert_eq!(f[0].min(f[1]), r.value().unwrap(), "seed {}: {}", seed, r);
+        }
+    }
+
+    #[test]
+    fn test_keep_display() {
+        let mut r = Roll::Dice(vec![Die::new(3), Die::new(5), Die::new(1)], 1..=6);
+        r.select(Selection::KeepHigh(2));
+        assert_eq!("[3, 5, ~~1~~]", r.to_string());
+        assert_eq!(8, r.value().unwrap());
+    }
+
+    #[test]
+    fn test_zero_based() {
+        let mut rng = StdRng::seed_from_u64(99);
+        let mut seen = [false; 10];
+        for _ in 0..500 {
+            let mut r = roll("z10", &mut rng);
+            let face = r.dice_mut()[0].value;
+            assert!((0..10).contains(&face), "{}", face);
+            seen[face as usize] = true;
+        }
+        assert!(seen[0], "0 should be rollable");
+
+        // large pools are streamed rather than kept per die, but share the range
+        for input in ["100z10", "100z100"] {
+            let r = roll(input, &mut rng);
+            assert!((0..=100 * 99).contains(&r.value().unwrap()), "{}: {}", input, r);
+        }
+        assert_eq!(0, roll("50z1", &mut rng).value().unwrap());
+    }
+
+    #[test]
+    fn test_weighted() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut counts = [0; 7];
+        for _ in 0..800 {
+            let r = roll("10d6w[1:3,6:3]", &mut rng);
+            let mut dice = match r {
+                Roll::Loaded(inner) => *inner,
+                r => panic!("weighted dice should be marked loaded: {:?}", r),
+            };
+            for d in dice.dice_mut() {
+                counts[d.value as usize] += 1;
+            }
+        }
+
+        // faces 1 and 6 should each come up about three times as often as the others
+        for fair in 2..=5 {
+            for loaded in [1, 6] {
+                let ratio = counts[loaded] as f64 / counts[fair] as f64;
+                assert!((2.5..3.5).contains(&ratio), "{:?}", counts);
+            }
+        }
+    }
+
+    #[test]
+    fn test_weighted_display() {
+        let r = roll("2d6w[6:5]", &mut StdRng::seed_from_u64(3));
+        assert!(r.to_string().starts_with("loaded["), "{}", r);
+
+        let r = roll("100d100w[100:5]", &mut StdRng::seed_from_u64(3));
+        assert!(matches!(r, Roll::Loaded(ref inner) if matches!(**inner, Roll::TooManyDice(_))));
+    }
+
+    #[test]
+    fn test_too_many_dice() {
+        let r = roll("500d100", &mut StdRng::seed_from_u64(3));
+        let Roll::TooManyDice(summary) = r else {
+            panic!("expected a summary: {:?}", r);
+        };
+        assert_eq!(500, summary.count);
+        assert_eq!(Ok(summary.sum as i64), r.value());
+        assert!(1 <= summary.min && summary.min < summary.max && summary.max <= 100, "{:?}", summary);
+        assert!((summary.mean() - summary.sum as f64 / 500.0).abs() < 1e-9);
+
+        let summary = PoolSummary::new([3, 1, 6, 4].into_iter());
+        assert_eq!(PoolSummary { count: 4, sum: 14, min: 1, max: 6 }, summary);
+        assert_eq!("[4 dice: sum 14, min 1, max 6, avg 3.5]", summary.to_string());
+        assert_eq!("[3 dice: sum 5, min 1, max 2, avg 1.67]", PoolSummary::new([1, 2, 2].into_iter()).to_string());
+        assert_eq!("[2 dice: sum 4, min 2, max 2, avg 2]", PoolSummary::new([2, 2].into_iter()).to_string());
+    }
+
+    #[test]
+    fn test_evaluate_all() {
+        let r = roll("2d6 + 3 * (d4 + 30d6) - 4d8kh2", &mut StdRng::seed_from_u64(5));
+        let faces = r.evaluate_all();
+        assert_eq!(2 + 1 + 30 + 4, faces.len());
+
+        let r = roll("d20 + 5", &mut StdRng::seed_from_u64(5));
+        let faces = r.evaluate_all();
+        assert_eq!(1, faces.len());
+        assert_eq!(faces[0] + 5, r.value().unwrap());
+    }
+
+    #[test]
+    fn test_neg() {
+        let r = roll("-(2d6) + 20", &mut StdRng::seed_from_u64(4));
+        let faces = r.evaluate_all();
+        assert_eq!(format!("-[{}, {}] + 20", marked(faces[0], 6), marked(faces[1], 6)), r.to_string());
+        assert_eq!(20 - faces[0] - faces[1], r.value().unwrap());
+
+        let r = roll("3 - -d1", &mut StdRng::seed_from_u64(4));
+        assert_eq!("3 - -[1]", r.to_string());
+        assert_eq!(4, r.value().unwrap());
+
+        let r = roll("2 * (-d1)", &mut StdRng::seed_from_u64(4));
+        assert_eq!("2 \\* (-[1])", r.to_string());
+        assert_eq!(-2, r.value().unwrap());
+
+        let r = roll("-(3d1 - 5) * 2", &mut StdRng::seed_from_u64(4));
+        assert_eq!("-([1, 1, 1] - 5) \\* 2", r.to_string());
+        assert_eq!(4, r.value().unwrap());
+
+        let r = roll("-(-9223372036854775807 - d1)", &mut StdRng::seed_from_u64(4));
+        assert_eq!(Err(EvalError::Overflow), r.value());
+    }
+
+    #[test]
+    fn test_group_display() {
+        let five = Roll::Grp(Box::new(Roll::Num(5)));
+        assert_eq!("5", five.to_string());
+        assert_eq!(5, five.value().unwrap());
+
+        let neg = Roll::Grp(Box::new(Roll::Num(-5)));
+        assert_eq!("(-5)", neg.to_string());
+
+        let mut rng = StdRng::seed_from_u64(2);
+        let r = roll("(2d6)", &mut rng);
+        let faces = r.evaluate_all();
+        assert_eq!(format!("[{}, {}]", marked(faces[0], 6), marked(faces[1], 6)), r.to_string());
+        assert_eq!(faces[0] + faces[1], r.value().unwrap());
+
+        let r = roll("(2d6+1)*3", &mut rng);
+        let faces = r.evaluate_all();
+        assert_eq!(format!("([{}, {}] + 1) \\* 3", marked(faces[0], 6), marked(faces[1], 6)), r.to_string());
+        assert_eq!((faces[0] + faces[1] + 1) * 3, r.value().unwrap());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let tests = [
+            "9999999999 * 9999999999",
+            "9223372036854775807 + 1",
+            "-9223372036854775807 - 2",
+            "(-9223372036854775807 - 1) / -1",
+            "max(1, 9223372036854775807 + 1)",
+            "abs(-9223372036854775807 - 1)",
+            "(9999999999 * 9999999999)d6",
+            "2d1000000 * 9223372036854775807",
+            "1000d1000000 * 9999999999 * 9999999999",
+        ];
+
+        for input in tests {
+            let r = roll(input, &mut StdRng::seed_from_u64(1));
+            assert_eq!(Err(EvalError::Overflow), r.value(), "{}", input);
+        }
+
+        assert_eq!(Ok(i64::MAX), roll("9223372036854775806 + 1", &mut UnusedRng).value());
+    }
+
+    #[test]
+    fn test_seeded() {
+        let a = roll("3d6 + 2d20", &mut StdRng::seed_from_u64(1234));
+        let b = roll("3d6 + 2d20", &mut StdRng::seed_from_u64(1234));
+        assert_eq!(a, b);
+        assert_eq!(a, a.clone());
+        assert_ne!(a, roll("3d6 + 2d20", &mut StdRng::seed_from_u64(4321)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize() {
+        use serde_json::json;
+
+        let r = roll("(2d6 + 3) * 2", &mut StdRng::seed_from_u64(11));
+        let faces = r.evaluate_all();
+        let die = |value| json!({"value": value, "rerolled": null, "dropped": false, "exploded": false, "raised": null});
+        let dice = faces[0] + faces[1];
+        assert_eq!(json!({
+            "kind": "mul",
+            "total": (dice + 3) * 2,
+            "lhs": {
+                "kind": "group",
+                "total": dice + 3,
+                "roll": {
+                    "kind": "add",
+                    "total": dice + 3,
+                    "lhs": {"kind": "dice", "total": dice, "dice": [die(faces[0]), die(faces[1])], "faces": [1, 6]},
+                    "rhs": {"kind": "num", "total": 3},
+                },
+            },
+            "rhs": {"kind": "num", "total": 2},
+        }), serde_json::to_value(&r).unwrap());
+
+        let r = roll("9999999999 * 9999999999", &mut UnusedRng);
+        assert_eq!(json!(null), serde_json::to_value(&r).unwrap()["total"]);
+    }
+}
@@ -0,0 +1,286 @@
+// without the redis feature only the file backend is ever used
+#![cfg_attr(not(feature = "redis"), allow(dead_code))]
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::io;
+use std::str::FromStr;
+
+use grammers_session::Session;
+
+/// Which kind of store keeps the session between runs.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) enum SessionBackend {
+    /// A file on local disk.
+    #[default]
+    File,
+    /// A key on a Redis server, for hosts without a persistent disk.
+    Redis,
+}
+
+impl FromStr for SessionBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "file" => Ok(SessionBackend::File),
+            "redis" => Ok(SessionBackend::Redis),
+            _ => Err(format!("unknown session backend {:?}, expected file or redis", s)),
+        }
+    }
+}
+
+/// Where the session is loaded from on startup and saved to while running,
+/// along with the users the bot has already seen.
+pub(crate) enum SessionStore {
+    /// The file at the given path.
+    File(String),
+    /// The given key on the Redis server at `addr`.
+    Redis {
+        addr: String,
+        password: Option<String>,
+        key: String,
+    },
+}
+
+impl SessionStore {
+    /// Loads the saved session, starting a new one if nothing's been saved yet.
+    pub(crate) async fn load(&self) -> Result<Session, Box<dyn Error>> {
+        match self {
+            SessionStore::File(path) => Ok(Session::load_file_or_create(path)?),
+            SessionStore::Redis { addr, password, key } => {
+                match redis_command(addr, password.as_deref(), &[b"GET", key.as_bytes()]).await? {
+                    Some(data) => Ok(Session::load(&data)?),
+                    None => Ok(Session::new()),
+                }
+            }
+        }
+    }
+
+    /// Saves `session`, replacing whatever was saved before.
+    pub(crate) async fn save(&self, session: &Session) -> io::Result<()> {
+        match self {
+            SessionStore::File(path) => session.save_to_file(path),
+            SessionStore::Redis { addr, password, key } => {
+                redis_command(addr, password.as_deref(), &[b"SET", key.as_bytes(), &session.save()]).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Loads the ids of the users seen by earlier runs, kept beside the
+    /// session in `<path>.seen` or `<key>:seen`. None have been seen if
+    /// nothing's been saved yet.
+    pub(crate) async fn load_seen(&self) -> io::Result<HashSet<i64>> {
+        let data = match self {
+            SessionStore::File(path) => match std::fs::read(format!("{}.seen", path)) {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+                Err(e) => return Err(e),
+            },
+            SessionStore::Redis { addr, password, key } => {
+                let key = format!("{}:seen", key);
+                redis_command(addr, password.as_deref(), &[b"GET", key.as_bytes()]).await?.unwrap_or_default()
+            }
+        };
+        decode_seen(&data)
+    }
+
+    /// Saves the ids of the users seen so far, replacing whatever was saved
+    /// before.
+    pub(crate) async fn save_seen(&self, seen: &HashSet<i64>) -> io::Result<()> {
+        let data = encode_seen(seen);
+        match self {
+            SessionStore::File(path) => std::fs::write(format!("{}.seen", path), data),
+            SessionStore::Redis { addr, password, key } => {
+                let key = format!("{}:seen", key);
+                redis_command(addr, password.as_deref(), &[b"SET", key.as_bytes(), &data]).await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Encodes user ids one per line, in order so saves of the same users match.
+fn encode_seen(seen: &HashSet<i64>) -> Vec<u8> {
+    let mut ids: Vec<_> = seen.iter().collect();
+    ids.sort();
+    ids.iter().map(|id| format!("{}\n", id)).collect::<String>().into_bytes()
+}
+
+fn decode_seen(data: &[u8]) -> io::Result<HashSet<i64>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    std::str::from_utf8(data)
+        .map_err(|e| invalid(format!("seen users aren't text: {}", e)))?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.trim().parse().map_err(|_| invalid(format!("bad seen user id {:?}", line))))
+        .collect()
+}
+
+/// How long a single Redis command may take, connecting included, before it's
+/// given up on.
+#[cfg(feature = "redis")]
+const REDIS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// The largest reply read from Redis. Sessions are a few kilobytes and seen
+/// users a dozen bytes each, so anything bigger is a wrong key rather than
+/// either.
+#[cfg(feature = "redis")]
+const MAX_REPLY_LEN: usize = 16 * 1024 * 1024;
+
+/// Sends a single command to the Redis server at `addr`, authenticating first
+/// if there's a password, and returns its reply. Sessions are only loaded and
+/// saved every few minutes, so each command gets its own connection.
+#[cfg(feature = "redis")]
+async fn redis_command(addr: &str, password: Option<&str>, args: &[&[u8]]) -> io::Result<Option<Vec<u8>>> {
+    within(REDIS_TIMEOUT, exchange(addr, password, args)).await
+}
+
+/// Fails `exchange` if it takes longer than `limit`, so a server that stops
+/// answering can't hold up whoever's waiting on it.
+#[cfg(feature = "redis")]
+async fn within<T>(limit: std::time::Duration, exchange: impl std::future::Future<Output=io::Result<T>>) -> io::Result<T> {
+    tokio::time::timeout(limit, exchange).await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "redis didn't answer in time")))
+}
+
+#[cfg(feature = "redis")]
+async fn exchange(addr: &str, password: Option<&str>, args: &[&[u8]]) -> io::Result<Option<Vec<u8>>> {
+    use tokio::io::{AsyncWriteExt, BufReader};
+
+    let mut stream = BufReader::new(tokio::net::TcpStream::connect(addr).await?);
+    if let Some(password) = password {
+        stream.write_all(&encode(&[b"AUTH", password.as_bytes()])).await?;
+        read_reply(&mut stream).await?;
+    }
+    stream.write_all(&encode(args)).await?;
+    read_reply(&mut stream).await
+}
+
+#[cfg(not(feature = "redis"))]
+async fn redis_command(_: &str, _: Option<&str>, _: &[&[u8]]) -> io::Result<Option<Vec<u8>>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "this build doesn't include the redis feature"))
+}
+
+/// Encodes a command as a RESP array of bulk strings.
+#[cfg(feature = "redis")]
+fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+/// Reads one RESP reply, returning `None` for a missing value and an error
+/// for an error reply. Only the reply kinds GET, SET and AUTH answer with are
+/// understood.
+#[cfg(feature = "redis")]
+async fn read_reply<R: tokio::io::AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let line = line.trim_end_matches("\r\n");
+    match line.split_at(line.len().min(1)) {
+        ("+", value) | (":", value) => Ok(Some(value.as_bytes().to_vec())),
+        ("-", msg) => Err(io::Error::other(format!("redis error: {}", msg))),
+        ("$", "-1") => Ok(None),
+        ("$", len) => {
+            let len: usize = len.parse().map_err(|_| invalid(format!("bad redis bulk length {:?}", len)))?;
+            if len > MAX_REPLY_LEN {
+                return Err(invalid(format!("redis reply of {} bytes is too long", len)));
+            }
+            let mut data = vec![0; len + 2];
+            reader.read_exact(&mut data).await?;
+            if !data.ends_with(b"\r\n") {
+                return Err(invalid("redis bulk reply isn't terminated".to_string()));
+            }
+            data.truncate(len);
+            Ok(Some(data))
+        }
+        _ => Err(invalid(format!("unexpected redis reply {:?}", line))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend() {
+        assert_eq!("file".parse(), Ok(SessionBackend::File));
+        assert_eq!(" Redis ".parse(), Ok(SessionBackend::Redis));
+        assert!("disk".parse::<SessionBackend>().is_err());
+    }
+
+    #[test]
+    fn test_seen() {
+        let seen = HashSet::from([42, -1001234, 7]);
+        assert_eq!(b"-1001234\n7\n42\n".to_vec(), encode_seen(&seen));
+        assert_eq!(seen, decode_seen(&encode_seen(&seen)).unwrap());
+        assert_eq!(HashSet::new(), decode_seen(b"").unwrap());
+        assert_eq!(HashSet::from([1, 2]), decode_seen(b"1\r\n2").unwrap());
+        assert!(decode_seen(b"1\nabc\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_seen_file() {
+        let path = std::env::temp_dir().join(format!("dice-goblin-{}.session", std::process::id()));
+        let store = SessionStore::File(path.to_string_lossy().into_owned());
+        assert_eq!(HashSet::new(), store.load_seen().await.unwrap());
+
+        let seen = HashSet::from([1, 2, 3]);
+        store.save_seen(&seen).await.unwrap();
+        assert_eq!(seen, store.load_seen().await.unwrap());
+        std::fs::remove_file(format!("{}.seen", path.display())).unwrap();
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(&[b"GET", b"goblin"]), b"*2\r\n$3\r\nGET\r\n$6\r\ngoblin\r\n");
+        assert_eq!(encode(&[b"SET", b"k", b"a\r\nb"]), b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\na\r\nb\r\n");
+    }
+
+    #[cfg(feature = "redis")]
+    #[tokio::test]
+    async fn test_read_reply() {
+        async fn read(mut reply: &[u8]) -> io::Result<Option<Vec<u8>>> {
+            read_reply(&mut reply).await
+        }
+
+        assert_eq!(read(b"+OK\r\n").await.unwrap(), Some(b"OK".to_vec()));
+        assert_eq!(read(b"$4\r\na\r\nb\r\n").await.unwrap(), Some(b"a\r\nb".to_vec()));
+        assert_eq!(read(b"$0\r\n\r\n").await.unwrap(), Some(vec![]));
+        assert_eq!(read(b"$-1\r\n").await.unwrap(), None);
+        assert!(read(b"-WRONGPASS invalid password\r\n").await.unwrap_err().to_string().contains("WRONGPASS"));
+        assert!(read(b"$5\r\nab\r\n").await.is_err());
+        assert!(read(b"$x\r\n").await.is_err());
+        assert!(read(b"*1\r\n").await.is_err());
+        assert!(read(b"").await.is_err());
+    }
+
+    #[cfg(feature = "redis")]
+    #[tokio::test]
+    async fn test_timeout() {
+        use std::time::Duration;
+
+        // the server accepts the connection but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let _server = tokio::spawn(async move {
+            let _conn = listener.accept().await;
+            std::future::pending::<()>().await
+        });
+
+        let err = within(Duration::from_millis(50), exchange(&addr, None, &[b"GET", b"goblin"])).await.unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use chrono::{DateTime, FixedOffset, Utc};
+
+/// Per-chat preferences toggled with bot commands. Settings are held in memory
+/// and reset to their defaults when the bot restarts.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct ChatSettings {
+    /// Append the time of the roll to every roll reply.
+    pub(crate) timestamps: bool,
+    /// The chat's timezone, in seconds east of UTC.
+    pub(crate) utc_offset: i32,
+    /// Show each roll's terms with their subtotals, and its total on a line
+    /// of its own.
+    pub(crate) breakdown: bool,
+}
+
+impl ChatSettings {
+    /// Formats `at` in the chat's timezone if timestamps are enabled.
+    pub(crate) fn timestamp(&self, at: DateTime<Utc>) -> Option<String> {
+        if !self.timestamps {
+            return None;
+        }
+
+        let offset = FixedOffset::east_opt(self.utc_offset)?;
+        Some(format!("rolled at {} {}", at.with_timezone(&offset).format("%H:%M"), format_utc_offset(self.utc_offset)))
+    }
+}
+
+/// Per-user preferences that follow a user across every chat.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct UserSettings {
+    /// Reroll every die that lands on a 1 one time.
+    pub(crate) reroll_ones: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct Settings {
+    chats: Mutex<HashMap<i64, ChatSettings>>,
+    users: Mutex<HashMap<i64, UserSettings>>,
+    seen_users: Mutex<HashSet<i64>>,
+}
+
+impl Settings {
+    /// Settings where the `seen` users have already interacted with the bot,
+    /// as saved by an earlier run.
+    pub(crate) fn with_seen_users(seen: HashSet<i64>) -> Self {
+        Self {
+            seen_users: Mutex::new(seen),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn get(&self, chat: i64) -> ChatSettings {
+        self.chats.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&chat)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn update(&self, chat: i64, f: impl FnOnce(&mut ChatSettings)) -> ChatSettings {
+        let mut chats = self.chats.lock().unwrap_or_else(|e| e.into_inner());
+        let settings = chats.entry(chat).or_default();
+        f(settings);
+        *settings
+    }
+
+    pub(crate) fn user(&self, user: i64) -> UserSettings {
+        self.users.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&user)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn update_user(&self, user: i64, f: impl FnOnce(&mut UserSettings)) -> UserSettings {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        let settings = users.entry(user).or_default();
+        f(settings);
+        *settings
+    }
+
+    /// Records that `user` has interacted with the bot, returning true only the
+    /// first time the user is seen.
+    pub(crate) fn first_visit(&self, user: i64) -> bool {
+        self.seen_users.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(user)
+    }
+
+    /// Every user seen so far, for saving between runs.
+    pub(crate) fn seen_users(&self) -> HashSet<i64> {
+        self.seen_users.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// Parses offsets like `UTC`, `+2`, `-05:30`, or `UTC+9` into seconds east of UTC.
+pub(crate) fn parse_utc_offset(input: &str) -> Option<i32> {
+    let input = input.trim();
+    let input = input.strip_prefix("UTC")
+        .or_else(|| input.strip_prefix("utc"))
+        .unwrap_or(input);
+    if input.is_empty() {
+        return Some(0);
+    }
+
+    let (sign, input) = match (input.strip_prefix('+'), input.strip_prefix('-')) {
+        (Some(rest), _) => (1, rest),
+        (_, Some(rest)) => (-1, rest),
+        _ => return None,
+    };
+    let (hours, minutes) = match input.split_once(':') {
+        Some((h, m)) => (h.parse::<i32>().ok()?, m.parse::<i32>().ok()?),
+        None => (input.parse::<i32>().ok()?, 0),
+    };
+    if !(0..=14).contains(&hours) || !(0..60).contains(&minutes) {
+        return None;
+    }
+
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+pub(crate) fn format_utc_offset(offset: i32) -> String {
+    if offset == 0 {
+        return "UTC".to_string();
+    }
+
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.abs();
+    format!("UTC{}{:02}:{:02}", sign, offset / 3600, offset % 3600 / 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_timestamp() {
+        let at = Utc.with_ymd_and_hms(2024, 1, 2, 14, 32, 5).unwrap();
+
+        let mut settings = ChatSettings::default();
+        assert_eq!(None, settings.timestamp(at));
+
+        settings.timestamps = true;
+        assert_eq!(Some("rolled at 14:32 UTC".to_string()), settings.timestamp(at));
+
+        settings.utc_offset = -(5 * 3600 + 30 * 60);
+        assert_eq!(Some("rolled at 09:02 UTC-05:30".to_string()), settings.timestamp(at));
+    }
+
+    #[test]
+    fn test_settings() {
+        let settings = Settings::default();
+        assert_eq!(ChatSettings::default(), settings.get(1));
+
+        settings.update(1, |s| s.timestamps = true);
+        assert!(settings.get(1).timestamps);
+        assert!(!settings.get(2).timestamps);
+    }
+
+    #[test]
+    fn test_user_settings() {
+        let settings = Settings::default();
+        assert_eq!(UserSettings::default(), settings.user(1));
+
+        let updated = settings.update_user(1, |s| s.reroll_ones = !s.reroll_ones);
+        assert!(updated.reroll_ones);
+        assert!(settings.user(1).reroll_ones);
+        assert!(!settings.user(2).reroll_ones);
+    }
+
+    #[test]
+    fn test_parse_utc_offset() {
+        let tests = [
+            ("UTC", Some(0)),
+            ("+2", Some(7200)),
+            ("UTC+9", Some(9 * 3600)),
+            ("-05:30", Some(-(5 * 3600 + 30 * 60))),
+            ("+15", None),
+            ("2", None),
+            ("+1:60", None),
+        ];
+
+        for (input, ex) in tests {
+            assert_eq!(ex, parse_utc_offset(input), "{}", input);
+        }
+    }
+}